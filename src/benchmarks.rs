@@ -1,298 +1,883 @@
-use crate::util::{construct_allocation_from_distribution, construct_random_allocations, create_random_data, get_random_safe_start, print_2x2, threadsafe_io_batch_complete_64, IoLog, QueuePairError, ONE_GIB};
-use rand_distr::Zipf;
-use vroom::{memory::{Dma, DmaSlice}, queues, NvmeDevice, NvmeQueuePair, HUGE_PAGE_SIZE};  
+use crate::cliff::{analyze_logs, CacheAnalysis};
+use crate::device::{IoBuffer, IoQueue, LbaWindow, Namespace, NvmeBackend};
+use crate::latency::IoTimer;
+use crate::steady::{SteadyStateAnalysis, SteadyStateCriteria};
+use crate::verify::{Verifier, VerifyMode, VerifyReport};
+use crate::error::BenchmarkError;
+use crate::health::{HealthDelta, HealthSnapshot};
+use crate::thermal::{ThermalPolicy, THERMAL_POLL};
+use crate::util::{construct_random_allocations, construct_zipf_allocations, create_random_data, get_random_safe_start, threadsafe_io_batch_complete_64, AccessPattern, IoLog, Limit, Phase, QueuePairError, Rate, RateLimit, SlotGenerator, Throughput, Workload, ONE_GIB};
+use rand_distr::{Distribution, Exp};
+use crate::cli::{Arrivals, ErrorPolicy};
+use vroom::HUGE_PAGE_SIZE;  
 use rand::{rngs::SmallRng, Rng, RngCore, SeedableRng};
-use std::{cmp::{max, min}, io, sync::{Arc, Mutex}, time::Instant};
-
+use std::{cmp::{max, min}, collections::BTreeMap, io, sync::{atomic::{AtomicBool, Ordering}, Arc, Barrier, OnceLock}, time::{Duration, Instant}};
+
+
+/**
+ * Every thread writes (or reads) sequentially through its own share of the region the limit covers,
+ * starting at the workload's lba_start.
+ * The workload's pattern is ignored, io_size is clamped to what fits a single transfer buffer.
+ * @returns the per-thread logs and the throughput tiers detected in the measured phase
+ */
+pub fn determine_cache_size<D: NvmeBackend>(nvme: D, workload: &Workload) -> Result<(D, Vec<Vec<IoLog>>, CacheAnalysis), BenchmarkError> {
+    let ns = namespace(&nvme, workload.ns_id)?;
+    let workload = cache_workload(&ns, workload);
+    let (nvme, results) = run_workload(nvme, &workload)?;
+    let analysis = analyze_logs(&results);
+
+    Ok((nvme, results, analysis))
+}
 
-pub fn determine_cache_size(mut nvme: NvmeDevice, max_io: u64, single_io_size: u64, write: bool, queue_depth: usize, num_threads: usize) -> (NvmeDevice, Vec<Vec<IoLog>>) {
-    let mut max_write = if max_io == 0 {
-        ONE_GIB * 8
-    } else {
-        max_io
-    };
+fn namespace<D: NvmeBackend>(nvme: &D, ns_id: u32) -> Result<Namespace, BenchmarkError> {
+    nvme.namespace(ns_id).ok_or_else(|| BenchmarkError::InvalidParameter(format!("namespace {} does not exist", ns_id)))
+}
 
-    let ns = nvme.namespaces.get(&1).unwrap();
-    let max_blocks = ns.blocks;
-    let ns_id = ns.id;
-    let block_size = ns.block_size;
+/// Stopping at the first failed command needs the status of every completion
+fn check_error_policy<D: NvmeBackend>(nvme: &D, on_error: ErrorPolicy) -> Result<(), BenchmarkError> {
+    if on_error == ErrorPolicy::Stop && !nvme.reports_completion_status() {
+        return Err(BenchmarkError::Unsupported("stopping on failed commands on this backend, it does not report the status of completions".into()));
+    }
+    Ok(())
+}
 
-    let batch_size = queue_depth;
+/// The workload determine_cache_size actually runs, with io size and LBA range resolved
+pub fn cache_workload(ns: &Namespace, workload: &Workload) -> Workload {
+    let window_blocks = workload.lba_count.unwrap_or(ns.blocks - workload.lba_start);
+    let capacity = window_blocks * ns.block_size;
 
     //needs to be a multiple of block_size, maximum size: HUGE_PAGE_SIZE-1
-    let io_size = min(single_io_size, HUGE_PAGE_SIZE as u64 - block_size);
-    let io_size  = io_size - (io_size % block_size);
-    //approximate amount of loops per results save / atleast so many loops need to be completed once before it is allowed to stop
-    let step_size = max(io_size / 8192, 1) * 32;
+    let io_size = min(workload.io_size, HUGE_PAGE_SIZE as u64 - ns.block_size);
+    let io_size = max(io_size - (io_size % ns.block_size), ns.block_size);
+
+    let mut workload = workload.clone();
+    workload.io_size = io_size;
+    workload.pattern = AccessPattern::Sequential;
+    workload.lba_count = match workload.limit {
+        Limit::Bytes(0) => {
+            workload.limit = Limit::Bytes(min(ONE_GIB * 8, capacity / 2));
+            Some(window_blocks)
+        }
+        Limit::Bytes(bytes) => Some(max(min(bytes, capacity) / ns.block_size, io_size / ns.block_size)),
+        Limit::Duration(_) => Some(window_blocks),
+    };
+    workload
+}
 
-    if max_write >= max_blocks * block_size {
-        max_write = max_blocks * block_size / 2;
+/**
+ * Runs a workload described by a job file or sweep point. Every thread gets its own queue pair and
+ * keeps up to queue_depth requests in flight, choosing between read and write per request according
+ * to the write ratio. All threads start at a barrier and share one clock, so the warmup, measure and
+ * ramp-down windows of all threads overlap exactly. A byte limit only counts IOs submitted while measuring.
+ * @returns per thread one log per operation type and interval
+ */
+pub fn run_workload<D: NvmeBackend>(nvme: D, workload: &Workload) -> Result<(D, Vec<Vec<IoLog>>), BenchmarkError> {
+    let (nvme, mut results, _, _) = run_workloads(nvme, std::slice::from_ref(workload))?;
+    Ok((nvme, results.remove(0)))
+}
+
+/**
+ * Runs several workloads at the same time, e.g. against different namespaces of the controller.
+ * The threads of all workloads start at the same barrier and share one clock. Verified workloads
+ * drain their queues whenever a request would reuse the buffer or LBA of one in flight.
+ * All queue pairs are deleted before returning, also on errors. If a thread fails to set up, the
+ * other threads stop right after the start barrier. The SMART / Health log is read right before the
+ * threads start and after all of them finished. With a max_temperature, the composite temperature is
+ * polled while the threads run and all of them stop once it exceeds the lowest limit. Writes of a
+ * workload with a range_op are submitted as that command, they are neither clamped to the MDTS nor
+ * need a data buffer. Writes of a workload with fua are submitted with Force Unit Access.
+ * @returns the per-thread logs and the verification report of every workload, in the order of the workloads,
+ * and the health delta of the device if the backend can read the log
+ */
+pub fn run_workloads<D: NvmeBackend>(mut nvme: D, workloads: &[Workload]) -> Result<(D, Vec<Vec<Vec<IoLog>>>, Vec<Option<VerifyReport>>, Option<HealthDelta>), BenchmarkError> {
+    let namespaces = workloads.iter().map(|w| namespace(&nvme, w.ns_id)).collect::<Result<Vec<_>, _>>()?;
+    let caps = nvme.capabilities();
+    for workload in workloads {
+        check_error_policy(&nvme, workload.on_error)?;
+    }
+    for workload in workloads.iter().filter(|w| w.ops().contains(&true)) {
+        match workload.range_op {
+            Some(_) if workload.verify.is_some() => return Err(BenchmarkError::InvalidParameter("verified workloads cannot issue range operations".into())),
+            Some(op) if !nvme.supports_range_op(op) => return Err(BenchmarkError::Unsupported(format!("{} on this backend", op.name()))),
+            None if workload.fua && !nvme.supports_durable_writes() => return Err(BenchmarkError::Unsupported("FUA writes on this backend".into())),
+            _ => {}
+        }
+    }
+    let mut queues: Vec<Vec<D::Queue>> = Vec::new();
+    for workload in workloads {
+        let len = max(workload.queue_depth * 2, 512);
+        let mut workload_queues = Vec::new();
+        for _ in 0..workload.num_threads {
+            match nvme.create_io_queue_pair(len) {
+                Ok(queue_pair) => workload_queues.push(queue_pair),
+                Err(e) => {
+                    queues.push(workload_queues);
+                    for queue_pair in queues.into_iter().flatten() {
+                        let _ = nvme.delete_io_queue_pair(queue_pair);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        queues.push(workload_queues);
     }
 
-    let mut handles = Vec::with_capacity(num_threads);
+    let health_before = HealthSnapshot::read(&mut nvme);
+    let seed = rand::rng().next_u64();
+    let barrier = Arc::new(Barrier::new(workloads.iter().map(|w| w.num_threads).sum()));
+    let epoch = Arc::new(OnceLock::new());
+    let setup_failed = Arc::new(AtomicBool::new(false));
+    let overheated = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::with_capacity(workloads.len());
+
+    for (w, ((workload, ns), queues)) in workloads.iter().zip(namespaces).zip(queues).enumerate() {
+        let seed = seed ^ ((w as u64) << 48);
+        let ns_id = ns.id;
+        let block_size = ns.block_size;
+        let lba_start = workload.lba_start;
+        let lba_count = workload.lba_count.unwrap_or(ns.blocks - lba_start);
+
+        let ops = workload.ops();
+        let range_op = workload.range_op;
+        let fua = workload.fua;
+        let io_sizes = [false, true].map(|write| {
+            let io_size = workload.io_size(write);
+            if write && range_op.is_some() {
+                return io_size;
+            }
+            let clamped = caps.clamp_io_size(io_size, block_size);
+            if clamped != io_size && ops.contains(&write) {
+                eprintln!("io size {} exceeds the MDTS of the controller, using {} bytes", io_size, clamped);
+            }
+            clamped
+        });
+        if ops.contains(&true) && range_op.is_none() {
+            if let Ok(identity) = nvme.identify_namespace(ns_id) {
+                for violation in identity.write_violations(lba_start, io_sizes[1] / block_size) {
+                    eprintln!("namespace {}: {}", ns_id, violation);
+                }
+            }
+        }
+        let patterns = [workload.pattern(false), workload.pattern(true)];
+        let write_ratio = workload.write_ratio;
+        let num_threads = workload.num_threads;
+        let batch_size = workload.queue_depth;
+        let log_interval = workload.log_interval;
+        let (warmup, ramp_down) = (workload.warmup, workload.ramp_down);
+        let rate = workload.offered_iops().zip(workload.rate.map(|r| r.arrivals));
+        let verify = workload.verify;
+        let stop_on_error = workload.on_error == ErrorPolicy::Stop;
+        let stopped = Arc::new(AtomicBool::new(false));
+        // verified threads never write the same LBAs, random writes are restricted to every num_threads-th slot
+        let write_slots = lba_count / (io_sizes[1] / block_size);
+        let own_write_slots = verify.is_some() && patterns[1] != AccessPattern::Sequential && write_slots >= num_threads as u64;
+
+        let (bytes_per_thread, run_time) = match workload.limit {
+            Limit::Bytes(bytes) => (bytes / num_threads as u64, None),
+            Limit::Duration(d) => (u64::MAX, Some(d)),
+        };
+        let mut workload_handles = Vec::with_capacity(num_threads);
+
+        for (i, mut queue_pair) in queues.into_iter().enumerate() {
+            let ops = ops.clone();
+            let arrivals = rate.map(|(iops, arrivals)| InterArrival::new(arrivals, iops / num_threads as f64));
+            let barrier = barrier.clone();
+            let epoch = epoch.clone();
+            let setup_failed = setup_failed.clone();
+            let stopped = stopped.clone();
+            let overheated = overheated.clone();
+
+            let handle = std::thread::spawn(move || {
+                let mut results = Vec::new();
+                let mut verifier = verify.map(|mode| Verifier::new(mode, seed ^ i as u64, block_size, io_sizes[1], batch_size));
+                let data_size = match range_op {
+                    Some(_) if !ops.contains(&false) => block_size,
+                    Some(_) => io_sizes[0],
+                    None => io_sizes[0].max(io_sizes[1]),
+                };
+                let dma = create_random_data(&queue_pair, verifier.as_ref().map_or(data_size as usize, |v| v.buffer_size()));
+                if dma.is_err() {
+                    setup_failed.store(true, Ordering::SeqCst);
+                }
+                let mut slot_generators: [Option<SlotGenerator>; 2] = [false, true].map(|write| {
+                    let slots = lba_count / (io_sizes[write as usize] / block_size);
+                    ops.contains(&write).then(|| SlotGenerator::new(patterns[write as usize], slots, i, num_threads, seed ^ ((write as u64) << 32)))
+                });
+                let mut op_rng = SmallRng::seed_from_u64(seed ^ ((i as u64) << 16));
+                let mut timer = IoTimer::new();
+
+                if barrier.wait().is_leader() {
+                    epoch.set(Instant::now()).unwrap();
+                }
+                barrier.wait();
+                let mut dma = match dma {
+                    Ok(_) if setup_failed.load(Ordering::SeqCst) => return (queue_pair, Ok((Vec::new(), None))),
+                    Ok(dma) => dma,
+                    Err(e) => return (queue_pair, Err(e)),
+                };
+                let epoch = *epoch.get().unwrap();
+                let measure_start = epoch + warmup;
+                let measure_end = run_time.map(|d| measure_start + d);
+                let mut ramp_down_end = measure_end.map(|end| end + ramp_down);
+
+                let mut phase = if warmup.is_zero() { Phase::Measure } else { Phase::Warmup };
+                let mut total = 0;
+                let mut submitted_bytes = 0;
+                let mut measured_bytes = 0;
+                let mut start = epoch;
+                let mut next_issue = arrivals.as_ref().map(|_| epoch);
+
+                loop {
+                    let now = Instant::now();
+                    let next_phase = match phase {
+                        Phase::Warmup if now >= measure_start => Some(Phase::Measure),
+                        Phase::Measure if measured_bytes >= bytes_per_thread || measure_end.is_some_and(|end| now >= end) => {
+                            if ramp_down.is_zero() {
+                                break;
+                            }
+                            ramp_down_end.get_or_insert(now + ramp_down);
+                            Some(Phase::RampDown)
+                        }
+                        Phase::RampDown if ramp_down_end.is_some_and(|end| now >= end) => break,
+                        _ => None,
+                    };
+                    if let Some(next_phase) = next_phase {
+                        push_op_logs(&mut results, &mut timer, start, now, phase);
+                        start = now;
+                        phase = next_phase;
+                    }
 
-    let mut queues = Vec::new();
+                    // open-loop runs only submit once the intended issue time of the next request has come
+                    if next_issue.map_or(true, |issue| now >= issue) {
+                        let write = op_rng.random_bool(write_ratio);
+                        let io_size = io_sizes[write as usize];
+                        let mut slot = slot_generators[write as usize].as_mut().unwrap().next_slot();
+                        if write && own_write_slots {
+                            slot = slot - slot % num_threads as u64 + i as u64;
+                            if slot >= write_slots {
+                                slot -= num_threads as u64;
+                            }
+                        }
+                        let lba = lba_start + slot * (io_size / block_size);
+                        let range = match verifier.as_mut() {
+                            Some(verifier) => {
+                                if verifier.must_drain(lba) {
+                                    timer.complete(&mut queue_pair, total);
+                                    total = 0;
+                                    verifier.drained(dma.as_slice());
+                                }
+                                verifier.submit(dma.as_mut_slice(), lba, write)
+                            }
+                            None => 0..io_size as usize,
+                        };
+                        let bytes = range.len() as u64;
+                        let submit_time = Instant::now();
+                        let res = match range_op.filter(|_| write) {
+                            Some(op) => queue_pair.submit_range(ns_id, block_size, lba, bytes / block_size, op),
+                            None if write && fua => queue_pair.submit_fua(ns_id, block_size, &dma, range, lba),
+                            None => queue_pair.submit_io(ns_id, block_size, &dma, range, lba, write),
+                        };
+                        if res == 0 {
+                            timer.rejected(write);
+                        } else {
+                            timer.submitted_request(res, bytes, lba, next_issue.unwrap_or(submit_time), write);
+                            submitted_bytes += bytes;
+                            if phase == Phase::Measure {
+                                measured_bytes += bytes;
+                            }
+                        }
+                        total += res;
+                        if let (Some(issue), Some(arrivals)) = (next_issue.as_mut(), &arrivals) {
+                            *issue += Duration::from_secs_f64(arrivals.sample(&mut op_rng));
+                        }
+                    }
 
-    for _ in 0..num_threads {
-        //minimum queue size to not run into issues with a single submit command with a size of 2 MiB
-        queues.push(nvme.create_io_queue_pair(max(queue_depth*2,512)).unwrap());
-    }
+                    total -= timer.poll(&mut queue_pair);
+                    if total >= batch_size {
+                        timer.complete(&mut queue_pair, total + 1 - batch_size);
+                        total -= total + 1 - batch_size;
+                    }
 
-    let queues = Arc::new(Mutex::new(queues));
-    
+                    let end = Instant::now();
+                    if end.duration_since(start) >= log_interval {
+                        push_op_logs(&mut results, &mut timer, start, end, phase);
+                        start = end;
+                    }
 
-    for i in 0..num_threads {
-        let shared_queues = queues.clone();
-
-        let handle = std::thread::spawn(move || {
-            let mut results = Vec::new();
-            let mut guard = shared_queues.lock().unwrap();
-            let mut queue_pair = guard.pop().unwrap();
-            drop(guard);
-
-            let dma = create_random_data(io_size as usize);
-
-            //let mut rng = SmallRng::seed_from_u64(Instant::now().elapsed().as_millis() as u64);
-
-            let mut it_check = 0;
-            let mut total = 0;
-            let mut cumulative_actions: usize = 0;
-            let mut t = 0;
-            /*let mut last_t = 0;
-            let mut dropoff_flag = false;
-            let mut end_counter = 0;
-            let mut it_start_dropoff = 1;*/
-            //let start_block = rng.next_u64() % (max_blocks - max_write / block_size);
-            let start_block = (max_io / block_size) * i as u64;
-            
-            let mut start = Instant::now();
-
-            while it_check < max_write / io_size {
-
-                let res = queue_pair.submit_io(ns_id, block_size, &dma.slice(0..io_size as usize), start_block+it_check*(io_size/block_size), write);
-                if res == 0 {
-                    eprintln!("Request was not queued, results will be inaccurate");
+                    // the first failed command of any thread ends the whole workload
+                    if stop_on_error && (timer.failures() > 0 || stopped.load(Ordering::Relaxed)) {
+                        stopped.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    if overheated.load(Ordering::Relaxed) {
+                        break;
+                    }
                 }
 
-                total += res;
-
-                while let Some(_) = queue_pair.quick_poll() {
-                    total -= 1;
-                    cumulative_actions += 1;
+                // without a ramp-down the IOs still in flight belong to the measured phase
+                if total > 0 {
+                    timer.complete(&mut queue_pair, total);
+                }
+                push_op_logs(&mut results, &mut timer, start, Instant::now(), phase);
+                let logged_bytes: u64 = results.iter().map(|log| log.bytes).sum();
+                if logged_bytes != submitted_bytes {
+                    let message = format!("thread {} logged {} bytes but submitted {}", i, logged_bytes, submitted_bytes);
+                    return (queue_pair, Err(BenchmarkError::QueuePair(message)));
                 }
 
-                if total >= batch_size {
-                    queue_pair.complete_io(total+1-batch_size);
-                    cumulative_actions += total+1-batch_size;
-                    total -= total+1-batch_size;
+                if let Some(verifier) = verifier.as_mut() {
+                    verifier.drained(dma.as_slice());
+                    if verifier.mode == VerifyMode::After {
+                        verifier.read_back(&mut queue_pair, &dma, ns_id);
+                    }
                 }
-                
+                (queue_pair, Ok((results, verifier.map(Verifier::finish))))
+            });
+            workload_handles.push(handle);
+        }
+        handles.push(workload_handles);
+    }
 
-                if cumulative_actions > step_size as usize {
-                    let end = Instant::now();
-                    //last_t = t;
-                    t = end.duration_since(start).as_micros();
-
-                    /*
-                    if !dropoff_flag && (it_check >= step_size*8) && (t * 3 >= last_t * 4) {
-                        dropoff_flag = true;
-                        it_start_dropoff = it_check / 2;
-                    }*/
-
-                    results.push(IoLog {
-                        start,
-                        end,
-                        actions: cumulative_actions,
-                        cumulative_size: (cumulative_actions * 8192) as usize,
-                    });
-
-                    cumulative_actions = 0;
-                    start = Instant::now();
+    let mut temperature_abort = None;
+    if let Some(limit) = workloads.iter().filter_map(|w| w.max_temperature).min() {
+        let mut next_poll = Instant::now();
+        while handles.iter().flatten().any(|handle| !handle.is_finished()) {
+            if Instant::now() >= next_poll {
+                next_poll += THERMAL_POLL;
+                let temperature = nvme.smart_log().map_or(i32::MIN, |log| log.composite_temperature_c);
+                if temperature > limit {
+                    temperature_abort = Some(BenchmarkError::Overheated { temperature, limit });
+                    overheated.store(true, Ordering::Relaxed);
+                    break;
                 }
-            
-                /* 
-                if dropoff_flag {
-                    end_counter += 1;
-                }*/
-                
-                it_check += 1;
             }
-            if total > 0 {
-                queue_pair.complete_io(total);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut reports = Vec::new();
+    let mut failure = None;
+    for workload_handles in handles {
+        let mut workload_results = Vec::new();
+        let mut workload_report: Option<VerifyReport> = None;
+        for handle in workload_handles {
+            let (res, report) = match handle.join() {
+                Ok((queue_pair, result)) => {
+                    let deleted = nvme.delete_io_queue_pair(queue_pair);
+                    match result.and_then(|result| deleted.map(|()| result)) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            failure.get_or_insert(e);
+                            continue;
+                        }
+                    }
+                }
+                Err(panic) => {
+                    let message = panic.downcast_ref::<String>().cloned()
+                        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_default();
+                    failure.get_or_insert(BenchmarkError::Thread(message));
+                    continue;
+                }
+            };
+            workload_results.push(res);
+            if let Some(report) = report {
+                workload_report.get_or_insert_with(VerifyReport::default).merge(report);
             }
-            return (results, queue_pair);
-        });
-        handles.push(handle);
+        }
+        results.push(workload_results);
+        reports.push(workload_report);
     }
 
-    eprintln!("{:?}", &handles);
+    let health = HealthSnapshot::delta(health_before, HealthSnapshot::read(&mut nvme));
 
-    let mut results = Vec::new();
-    for handle in handles {
-        let (res, queue_pair) = handle.join().unwrap();
-        nvme.delete_io_queue_pair(queue_pair);
-        results.push(res);
+    match failure.or(temperature_abort) {
+        Some(e) => Err(e),
+        None => Ok((nvme, results, reports, health)),
     }
+}
 
-    /* 
-    if it_check >= step_size*8 {
-        println!("Could not detect cache size, tested: {}MiB", it_check * block_size / (1024 * 1024));
-    } else {
-        println!("Cache size: {} MiB", it_check * block_size / (1024 * 1024));
+/**
+ * Measures the closed-loop saturation rate of the workload and then runs it open-loop at the given
+ * percentages of that rate in ascending order. The sweep stops after the first step whose achieved
+ * rate falls more than 10% short of the offered one, the device is saturated from there on. Between
+ * two steps it pauses for cooldown and until the thermal policy lets the next one start.
+ * @returns per step the offered IOPS (None for the saturation run) and the logs
+ */
+pub fn load_curve<D: NvmeBackend>(nvme: D, workload: &Workload, arrivals: Arrivals, percents: &[f64], cooldown: Duration, thermal: ThermalPolicy) -> Result<(D, Vec<(Option<f64>, Vec<Vec<IoLog>>)>), BenchmarkError> {
+    let mut workload = workload.clone();
+    workload.rate = None;
+    let (mut nvme, saturation) = run_workload(nvme, &workload)?;
+    let saturation_iops = Throughput::from_logs(&saturation).iops();
+    let stop = stops_on_error(&workload, &saturation);
+    let mut points = vec![(None, saturation)];
+    if stop {
+        return Ok((nvme, points));
     }
 
-    let data_points = min(50, results.len());
-    let mut condensed = Vec::with_capacity(data_points);
+    let mut percents = percents.to_vec();
+    percents.sort_by(f64::total_cmp);
+    for percent in percents.into_iter().filter(|&p| p > 0.0) {
+        thermal.cool_down(&mut nvme, cooldown)?;
+        let offered = saturation_iops * percent / 100.0;
+        workload.rate = Some(RateLimit { target: Rate::Iops(offered), arrivals });
+        let results;
+        (nvme, results) = run_workload(nvme, &workload)?;
+        let achieved = Throughput::from_logs(&results).iops();
+        let stop = stops_on_error(&workload, &results);
+        points.push((Some(offered), results));
+        if achieved < offered * 0.9 || stop {
+            break;
+        }
+    }
 
-    for i in 0..data_points {
-        let start = i * results.len() / data_points;
-        let end = (i + 1) * results.len() / data_points;
+    Ok((nvme, points))
+}
 
-        let chunk = &results[start..end];
-        let chunk_len = chunk.len();
+/**
+ * Brings the LBA window into a defined state before measuring: seq_fills sequential passes of 128 KiB
+ * writes over the whole window (workload independent), followed by random 4 KiB writes of
+ * random_fills times its capacity (workload dependent, as a fresh drive has no fragmentation).
+ * @returns the logs of every pass, labelled with its stage
+ */
+pub fn precondition<D: NvmeBackend>(mut nvme: D, ns_id: u32, window: LbaWindow, seq_fills: u32, random_fills: f64, queue_depth: usize, num_threads: usize, log_interval: Duration, on_error: ErrorPolicy, max_temperature: Option<i32>) -> Result<(D, Vec<(&'static str, Vec<Vec<IoLog>>)>), BenchmarkError> {
+    let ns = namespace(&nvme, ns_id)?;
+    let capacity = window.blocks * ns.block_size;
+    let fill = |io_size: u64, pattern, bytes: u64| Workload {
+        queue_depth,
+        num_threads,
+        write_ratio: 1.0,
+        pattern,
+        log_interval,
+        on_error,
+        max_temperature,
+        ..Workload::new(ns_id, window, max(io_size - io_size % ns.block_size, ns.block_size), Limit::Bytes(bytes))
+    };
 
-        if chunk_len > 0 {
-            let n: usize = chunk.iter().map(|(actions, _)| actions).sum();
-            let t: u128 = chunk.iter().map(|(_, time)| time).sum();
-            condensed.push((n as u128 * block_size as u128 * 1_000_000) / (t * 1024 * 1024));
+    let mut stages = Vec::new();
+    for _ in 0..seq_fills {
+        let workload = fill(128 * 1024, AccessPattern::Sequential, capacity);
+        let results;
+        (nvme, results) = run_workload(nvme, &workload)?;
+        let stop = stops_on_error(&workload, &results);
+        stages.push(("sequential", results));
+        if stop {
+            return Ok((nvme, stages));
         }
     }
+    if random_fills > 0.0 {
+        let results;
+        (nvme, results) = run_workload(nvme, &fill(4096, AccessPattern::Random, (capacity as f64 * random_fills) as u64))?;
+        stages.push(("random", results));
+    }
+
+    Ok((nvme, stages))
+}
 
-    condensed.iter().for_each(|x| println!("{} MiB/s", x));*/
+/**
+ * Repeats the workload in rounds of its limit until the IOPS of the last rounds satisfy the
+ * steady-state criteria, or max_rounds were run.
+ * @returns the logs of every round and the analysis, only the rounds of a converged window are steady-state numbers
+ */
+pub fn steady_state<D: NvmeBackend>(mut nvme: D, workload: &Workload, criteria: &SteadyStateCriteria, max_rounds: usize) -> Result<(D, Vec<Vec<Vec<IoLog>>>, SteadyStateAnalysis), BenchmarkError> {
+    let mut rounds = Vec::new();
+    let mut iops = Vec::new();
+
+    while rounds.len() < max_rounds {
+        let results;
+        (nvme, results) = run_workload(nvme, workload)?;
+        iops.push(Throughput::from_logs(&results).iops());
+        let stop = stops_on_error(workload, &results);
+        rounds.push(results);
+        if stop || criteria.evaluate(&iops).is_some_and(|w| criteria.is_steady(&w)) {
+            break;
+        }
+    }
 
-    (nvme, results)
+    let analysis = criteria.analyze(&iops);
+    Ok((nvme, rounds, analysis))
 }
 
-pub fn single_lba(mut nvme: NvmeDevice, write: bool) -> NvmeDevice {
-    let n_loops = 32;
+/**
+ * Runs the workload, which buffers writes in the volatile write cache, and times a Flush right after
+ * it, repetitions times. A workload with a byte limit of 0 is skipped, so only the flushes run.
+ * @returns the logs of every round of writes and the latencies of the flushes
+ */
+pub fn flush_after_writes<D: NvmeBackend>(mut nvme: D, workload: &Workload, repetitions: usize) -> Result<(D, Vec<Vec<Vec<IoLog>>>, Throughput), BenchmarkError> {
+    if !nvme.supports_durable_writes() {
+        return Err(BenchmarkError::Unsupported("Flush on this backend".into()));
+    }
+    let mut rounds = Vec::new();
+    let mut timer = IoTimer::new();
+    let mut actions = 0;
+    let mut duration = Duration::ZERO;
+
+    for _ in 0..repetitions {
+        if !matches!(workload.limit, Limit::Bytes(0)) {
+            let results;
+            (nvme, results) = run_workload(nvme, workload)?;
+            let stop = stops_on_error(workload, &results);
+            rounds.push(results);
+            if stop {
+                break;
+            }
+        }
+        nvme.with_queue_pair(8, |queue_pair| {
+            let submit_time = Instant::now();
+            let res = queue_pair.submit_flush(workload.ns_id);
+            if res == 0 {
+                timer.rejected(true);
+            } else {
+                actions += 1;
+            }
+            timer.submitted_request(res, 0, 0, submit_time, true);
+            timer.complete(queue_pair, res);
+            duration += submit_time.elapsed();
+            Ok(())
+        })?;
+        if workload.on_error == ErrorPolicy::Stop && timer.failures() > 0 {
+            break;
+        }
+    }
 
-    let ns = nvme.namespaces.get(&1).unwrap();
-    let max_blocks = ns.blocks;
-    let ns_id = ns.id;
-    let block_size = ns.block_size;
-    let dma = create_random_data(HUGE_PAGE_SIZE);
+    let (failed, rejected, errors) = timer.take_errors();
+    Ok((nvme, rounds, Throughput { actions, bytes: 0, duration, latency: timer.take(), sizes: BTreeMap::new(), failed, rejected, errors }))
+}
 
-    let mut queue_pair = nvme.create_io_queue_pair(128).unwrap();
+/// A run of a workload with the stop policy had failed commands, so the runs after it are skipped
+fn stops_on_error(workload: &Workload, results: &[Vec<IoLog>]) -> bool {
+    workload.on_error == ErrorPolicy::Stop && results.iter().flatten().any(|log| log.failed > 0)
+}
 
-    
-    let lba = get_random_safe_start(block_size, max_blocks, block_size).unwrap();
+/// Seconds between two requests of one thread
+enum InterArrival {
+    Constant(f64),
+    Poisson(Exp<f64>),
+}
 
-    if !write {
-        let res = queue_pair.submit_io(ns_id, block_size, &dma.slice(0..block_size as usize), lba, true);
-        queue_pair.complete_io(res);
+impl InterArrival {
+    fn new(arrivals: Arrivals, iops: f64) -> InterArrival {
+        match arrivals {
+            Arrivals::Constant => InterArrival::Constant(1.0 / iops),
+            Arrivals::Poisson => InterArrival::Poisson(Exp::new(iops).unwrap()),
+        }
     }
 
-    let mut submitted = 0;
-    let start = Instant::now();
-    for _ in 0..n_loops * HUGE_PAGE_SIZE as u64 / block_size {
-        submitted += queue_pair.submit_io(ns_id, block_size, &dma.slice(0..block_size as usize), lba, write);
-        
-        if submitted >= 64 {
-            queue_pair.complete_io(submitted/2);
-            submitted -= submitted / 2;
+    fn sample(&self, rng: &mut SmallRng) -> f64 {
+        match self {
+            InterArrival::Constant(interval) => *interval,
+            InterArrival::Poisson(distr) => distr.sample(rng),
         }
-        
     }
-    if submitted > 0 {
-        queue_pair.complete_io(submitted);
+}
+
+/// Writes one log per operation type that completed commands since the last call
+fn push_op_logs(results: &mut Vec<IoLog>, timer: &mut IoTimer, start: Instant, end: Instant, phase: Phase) {
+    for write in [false, true] {
+        let latency = timer.take_op(write);
+        let completed = timer.take_completed(write);
+        if completed.commands == 0 && completed.rejected == 0 {
+            continue;
+        }
+        results.push(IoLog {
+            start,
+            end,
+            actions: completed.requests,
+            bytes: completed.bytes,
+            sizes: completed.sizes,
+            failed: completed.failed,
+            rejected: completed.rejected,
+            errors: completed.errors,
+            latency,
+            phase,
+            write,
+        });
     }
-    let duration = start.elapsed();
-    println!("Continous {} same LBA completed with {}MiB/s", if write { "write to" } else { "read from" }, n_loops as u128 * HUGE_PAGE_SIZE as u128 * 1_000_000 / (duration.as_micros() * 1024 * 1024));
+}
+
+/**
+ * @param run_time if set, transfers until it elapsed instead of n_loops * HUGE_PAGE_SIZE bytes
+ */
+pub fn single_lba<D: NvmeBackend>(mut nvme: D, ns_id: u32, window: LbaWindow, write: bool, n_loops: u64, run_time: Option<Duration>, on_error: ErrorPolicy) -> Result<(D, Throughput), BenchmarkError> {
+    check_error_policy(&nvme, on_error)?;
+    let ns = namespace(&nvme, ns_id)?;
+    let max_blocks = window.blocks;
+    let block_size = ns.block_size;
+    let throughput = nvme.with_queue_pair(128, |queue_pair| {
+        let dma = create_random_data(queue_pair, HUGE_PAGE_SIZE)?;
 
-    nvme.delete_io_queue_pair(queue_pair);
     
-    nvme
+        let lba = window.first + get_random_safe_start(block_size, max_blocks, block_size).unwrap();
+
+        if !write {
+            let res = queue_pair.submit_io(ns_id, block_size, &dma, 0..block_size as usize, lba, true);
+            queue_pair.complete_io(res);
+        }
+
+        let mut submitted = 0;
+        let mut timer = IoTimer::new();
+        let max_actions = if run_time.is_some() { u64::MAX } else { n_loops * HUGE_PAGE_SIZE as u64 / block_size };
+        let mut issued = 0;
+        let mut actions = 0;
+        let start = Instant::now();
+        let deadline = run_time.map(|d| start + d);
+        while issued < max_actions && deadline.map_or(true, |d| Instant::now() < d) && !(on_error == ErrorPolicy::Stop && timer.failures() > 0) {
+            issued += 1;
+            let submit_time = Instant::now();
+            let res = queue_pair.submit_io(ns_id, block_size, &dma, 0..block_size as usize, lba, write);
+            if res == 0 {
+                timer.rejected(write);
+            } else {
+                actions += 1;
+            }
+            timer.submitted_request(res, block_size, lba, submit_time, write);
+            submitted += res;
+        
+            if submitted >= 64 {
+                timer.complete(queue_pair, submitted/2);
+                submitted -= submitted / 2;
+            }
+        
+        }
+        if submitted > 0 {
+            timer.complete(queue_pair, submitted);
+        }
+        let duration = start.elapsed();
+        let (failed, rejected, errors) = timer.take_errors();
+
+        Ok(Throughput { actions, bytes: actions * block_size, duration, latency: timer.take(), sizes: BTreeMap::from([(block_size, actions)]), failed, rejected, errors })
+    })?;
+
+    Ok((nvme, throughput))
 }
 
-pub fn full_random_combinations(mut nvme: NvmeDevice) -> NvmeDevice {
-    let ns = nvme.namespaces.get(&1).unwrap();
-    let max_blocks = ns.blocks;
-    let ns_id = ns.id;
+/**
+ * @param run_time if set, repeats every cell until it elapsed instead of num_it times
+ * @returns (random_from, random_to, throughput) for each cell of the 2x2 matrix
+ */
+pub fn full_random_combinations<D: NvmeBackend>(mut nvme: D, ns_id: u32, window: LbaWindow, write: bool, num_it: usize, run_time: Option<Duration>, on_error: ErrorPolicy) -> Result<(D, Vec<(bool, bool, Throughput)>), BenchmarkError> {
+    check_error_policy(&nvme, on_error)?;
+    let ns = namespace(&nvme, ns_id)?;
+    let max_blocks = window.blocks;
     let block_size = ns.block_size;
-    let mut dma = create_random_data(HUGE_PAGE_SIZE);
-
-    const NUM_IT: usize = 10;
-    let mut total= 0;
     let mut results = Vec::new();
 
-    let mut successfull_it = NUM_IT;
-
-    let mut queue_pair = nvme.create_io_queue_pair(128).unwrap();
+    let mut queue_pair = nvme.create_io_queue_pair(128)?;
+    let dma = match create_random_data(&queue_pair, HUGE_PAGE_SIZE) {
+        Ok(dma) => dma,
+        Err(e) => {
+            let _ = nvme.delete_io_queue_pair(queue_pair);
+            return Err(e);
+        }
+    };
 
-    for random_from in [false, true] {
+    'cells: for random_from in [false, true] {
         for random_to in [false, true] {
-            let allocations = construct_random_allocations(dma.size, max_blocks, block_size, random_from, random_to);
-            total = 0;
-            for _ in 0..NUM_IT {
-                
+            let mut allocations = construct_random_allocations(dma.len(), max_blocks, block_size, random_from, random_to);
+            allocations.iter_mut().for_each(|a| a.lba += window.first);
+            let allocation_bytes: usize = allocations.iter().map(|a| a.stop - a.start).sum();
+            let mut total = Duration::ZERO;
+            let mut it = 0;
+            let mut successfull_it = 0;
+            let mut timer = IoTimer::new();
+            while run_time.map_or(it < num_it, |d| total < d) {
+                it += 1;
                 let t = std::time::Instant::now();
-                queue_pair = match threadsafe_io_batch_complete_64(queue_pair, ns_id, block_size, (&dma, &allocations), true) {
-                    Ok(qp) => qp,
-                    Err(e) => {
-                        successfull_it -= 1;
-                        e.queue_pair
+                queue_pair = match threadsafe_io_batch_complete_64(queue_pair, ns_id, block_size, (&dma, &allocations), write, &mut timer) {
+                    Ok(qp) => {
+                        successfull_it += 1;
+                        qp
                     }
+                    Err(e) => e.queue_pair,
                 };
-                let duration = t.elapsed();
-                
-                total += duration.as_micros();
+                total += t.elapsed();
+                if on_error == ErrorPolicy::Stop && timer.failures() > 0 {
+                    break;
+                }
+            }
+            let (failed, rejected, errors) = timer.take_errors();
+            results.push((random_from, random_to, Throughput {
+                actions: (allocations.len() * successfull_it) as u64,
+                bytes: (allocation_bytes * successfull_it) as u64,
+                duration: total,
+                latency: timer.take(),
+                sizes: BTreeMap::new(),
+                failed,
+                rejected,
+                errors,
+            }));
+            if on_error == ErrorPolicy::Stop && failed > 0 {
+                break 'cells;
             }
-            results.push((1_000_000 * HUGE_PAGE_SIZE as u128 * successfull_it as u128) / (1024*1024 * total as u128));
         }
     }
-    nvme.delete_io_queue_pair(queue_pair);
+    nvme.delete_io_queue_pair(queue_pair)?;
 
-    print_2x2(&results);
-
-    nvme
+    Ok((nvme, results))
 }
 
-pub fn zipf_single_action(mut nvme: NvmeDevice, write: bool) -> NvmeDevice {
-    let ns = nvme.namespaces.get(&1).unwrap();
-    let max_blocks = ns.blocks;
-    let ns_id = ns.id;
+/**
+ * @param run_time if set, the n accesses of every point are repeated until it elapsed
+ * @returns (n, s, throughput) for every combination of LBA range size n and exponent s that fits the namespace
+ */
+pub fn zipf_single_action<D: NvmeBackend>(mut nvme: D, ns_id: u32, window: LbaWindow, write: bool, sizes: &[u64], exponents: &[f64], run_time: Option<Duration>, on_error: ErrorPolicy) -> Result<(D, Vec<(u64, f64, Throughput)>), BenchmarkError> {
+    check_error_policy(&nvme, on_error)?;
+    let ns = namespace(&nvme, ns_id)?;
+    let max_blocks = window.blocks;
     let block_size = ns.block_size;
-    let dma = create_random_data(HUGE_PAGE_SIZE);
-
-    let mut queue_pair = nvme.create_io_queue_pair(128).unwrap();
+    let mut queue_pair = nvme.create_io_queue_pair(128)?;
+    let dma = match create_random_data(&queue_pair, HUGE_PAGE_SIZE) {
+        Ok(dma) => dma,
+        Err(e) => {
+            let _ = nvme.delete_io_queue_pair(queue_pair);
+            return Err(e);
+        }
+    };
+    let mut results = Vec::new();
 
-    for s in 1..3 {
-        for n in [4096, 32768, 262144, 2097152] {
+    'points: for &s in exponents {
+        for &n in sizes {
             let start_lba = match get_random_safe_start(n * block_size, max_blocks, block_size) {
-                Some(x) => x,
+                Some(x) => window.first + x,
                 None => continue,
             };
 
-            let distr = Zipf::new(n as f64, s as f64).unwrap();
-
-            let mut allocations = construct_allocation_from_distribution(n as usize * block_size as usize, dma.size, block_size, distr);
-            allocations = allocations.iter_mut().map(|x| {x.lba += start_lba; *x}).collect();
-
-            if s==1 && n==4096 {
-                println!("{:?}", allocations);
-            }
-
-            let t = std::time::Instant::now();
-            queue_pair = match threadsafe_io_batch_complete_64(queue_pair, ns_id, block_size, (&dma, &allocations), write) {
-                Ok(qp) => qp,
+            let allocations = match construct_zipf_allocations(n, s, start_lba, dma.len(), block_size) {
+                Ok(allocations) => allocations,
                 Err(e) => {
-                    eprintln!("Failed to complete some transactions, report may be innacurate");
-                    e.queue_pair
+                    let _ = nvme.delete_io_queue_pair(queue_pair);
+                    return Err(e);
                 }
             };
-            let d = t.elapsed();
 
-            
+            let mut timer = IoTimer::new();
+            let mut passes = 0;
+            let t = std::time::Instant::now();
+            loop {
+                queue_pair = match threadsafe_io_batch_complete_64(queue_pair, ns_id, block_size, (&dma, &allocations), write, &mut timer) {
+                    Ok(qp) => qp,
+                    Err(e) => e.queue_pair,
+                };
+                passes += 1;
+                if run_time.map_or(true, |rt| t.elapsed() >= rt) || (on_error == ErrorPolicy::Stop && timer.failures() > 0) {
+                    break;
+                }
+            }
+            let d = t.elapsed();
 
             if d.as_micros() == 0 {
-                println!("Unexpected error where elapsed time is 0");
+                eprintln!("Unexpected error where elapsed time is 0");
                 continue
             }
 
-            println!("Zipf({},{}): {}MiB/s",n,s,n as u128 * block_size as u128 * 1_000_000/(d.as_micros() * 1024* 1024));
+            let (failed, rejected, errors) = timer.take_errors();
+            results.push((n, s, Throughput { actions: n * passes, bytes: n * passes * block_size, duration: d, latency: timer.take(), sizes: BTreeMap::from([(block_size, n * passes)]), failed, rejected, errors }));
+            if on_error == ErrorPolicy::Stop && failed > 0 {
+                break 'points;
+            }
         }
     }
-    nvme.delete_io_queue_pair(queue_pair);
+    nvme.delete_io_queue_pair(queue_pair)?;
     
-    nvme
-}
\ No newline at end of file
+    Ok((nvme, results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimConfig, SimDevice};
+
+    fn sim(spec: &str) -> SimDevice {
+        SimDevice::new(SimConfig::parse(spec).unwrap())
+    }
+
+    fn writes(nvme: &SimDevice, limit: Limit) -> Workload {
+        let ns = nvme.namespaces()[0];
+        Workload { queue_depth: 8, num_threads: 2, write_ratio: 1.0, ..Workload::new(ns.id, LbaWindow::whole(&ns), 4096, limit) }
+    }
+
+    #[test]
+    fn byte_limit_is_reached_and_logged() {
+        let nvme = sim("sim:capacity=1G,dist=const");
+        let workload = writes(&nvme, Limit::Bytes(16 << 20));
+        let (_, results) = run_workload(nvme, &workload).unwrap();
+        let total = Throughput::from_logs(&results);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(total.bytes, 16 << 20);
+        assert_eq!(total.actions * 4096, total.bytes);
+        assert_eq!(total.latency.summary().unwrap().count, total.actions);
+        assert_eq!(total.failed, 0);
+    }
+
+    #[test]
+    fn mixed_workload_issues_reads_and_writes() {
+        let nvme = sim("sim:capacity=1G");
+        let workload = Workload { write_ratio: 0.3, pattern: AccessPattern::Random, ..writes(&nvme, Limit::Bytes(8 << 20)) };
+        let (_, results) = run_workload(nvme, &workload).unwrap();
+        let logs = || results.iter().flatten();
+
+        let reads: usize = logs().filter(|l| !l.write).map(|l| l.actions).sum();
+        let writes: usize = logs().filter(|l| l.write).map(|l| l.actions).sum();
+        assert!(reads > 0 && writes > 0);
+        let ratio = writes as f64 / (reads + writes) as f64;
+        assert!((0.2..0.4).contains(&ratio), "write ratio {}", ratio);
+    }
+
+    #[test]
+    fn stop_policy_ends_the_run_at_the_first_failure() {
+        let nvme = sim("sim:capacity=1G,errors=1.0");
+        let workload = Workload { on_error: ErrorPolicy::Stop, ..writes(&nvme, Limit::Duration(Duration::from_secs(10))) };
+        let start = Instant::now();
+        let (_, results) = run_workload(nvme, &workload).unwrap();
+        let total = Throughput::from_logs(&results);
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(total.failed > 0);
+        assert_eq!(total.errors.total(), total.failed);
+    }
+
+    #[test]
+    fn verified_writes_read_back_clean() {
+        let nvme = sim("sim:capacity=256M,store=true");
+        let workload = Workload { verify: Some(VerifyMode::After), ..writes(&nvme, Limit::Bytes(4 << 20)) };
+        let (_, _, reports, _) = run_workloads(nvme, &[workload]).unwrap();
+        let report = reports[0].as_ref().unwrap();
+
+        assert_eq!(report.blocks_written, (4 << 20) / 512);
+        assert_eq!(report.blocks_checked, report.blocks_written);
+        assert_eq!(report.misdirected + report.stale + report.torn + report.corrupted, 0);
+    }
+
+    #[test]
+    fn verification_finds_damaged_blocks() {
+        let nvme = sim("sim:capacity=256M,store=true,faults=0.2");
+        let workload = Workload { verify: Some(VerifyMode::After), ..writes(&nvme, Limit::Bytes(4 << 20)) };
+        let (_, _, reports, _) = run_workloads(nvme, &[workload]).unwrap();
+        let report = reports[0].as_ref().unwrap();
+
+        assert!(report.misdirected + report.stale + report.torn + report.corrupted > 0);
+    }
+
+    #[test]
+    fn failed_read_backs_are_not_checked() {
+        let nvme = sim("sim:capacity=256M,store=true,errors=1.0");
+        let workload = Workload { verify: Some(VerifyMode::After), ..writes(&nvme, Limit::Bytes(1 << 20)) };
+        let (_, _, reports, _) = run_workloads(nvme, &[workload]).unwrap();
+        let report = reports[0].as_ref().unwrap();
+
+        assert_eq!(report.unreadable, report.blocks_written);
+        assert_eq!(report.blocks_checked, 0);
+        assert_eq!(report.failed_blocks(), 0);
+    }
+
+    #[test]
+    fn queue_pairs_are_released_after_a_run() {
+        let mut nvme = sim("sim:capacity=1G,max_queues=2");
+        for _ in 0..3 {
+            let workload = writes(&nvme, Limit::Bytes(1 << 20));
+            (nvme, _) = run_workload(nvme, &workload).unwrap();
+        }
+    }
+}
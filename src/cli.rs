@@ -0,0 +1,826 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::features::NvmCommand;
+use crate::report::Format;
+use crate::safety::LbaWindowSpec;
+
+#[derive(Parser, Debug)]
+#[command(name = "nvmebench", about = "NVMe benchmarks on top of the vroom userspace driver")]
+pub struct Cli {
+    /// PCI bus id of the controller, e.g. 0000:01:00.0, a block device or file path (containing a '/')
+    /// to run through the kernel with O_DIRECT, or "sim[:key=value,...]" for the simulated device
+    pub target: String,
+
+    /// Output format of the results
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    pub format: Format,
+
+    /// Write results to this file instead of stdout
+    #[arg(long, global = true)]
+    pub output: Option<PathBuf>,
+
+    /// Also report the series of every thread, aligned with the combined series
+    #[arg(long, global = true)]
+    pub thread_series: bool,
+
+    #[command(flatten)]
+    pub safety: SafetyArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Args, Debug)]
+pub struct SafetyArgs {
+    /// Only access this LBA window of the namespace, "offset+length" or "offset" as sizes or
+    /// percentages of the capacity, e.g. 1G+16G or 50%+25%
+    #[arg(long, global = true, value_parser = LbaWindowSpec::parse)]
+    pub lba_window: Option<LbaWindowSpec>,
+
+    /// Reject every command and job that writes
+    #[arg(long, global = true)]
+    pub read_only: bool,
+
+    /// Write even though a partition table or file system signature was found on the namespace
+    #[arg(long, global = true)]
+    pub allow_overwrite: bool,
+
+    /// Print the LBA ranges that would be accessed without issuing any benchmark IO
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Whether a command that completes with an error status ends the benchmark, job files can
+    /// override it per job with on_error. vroom does not report the status of completions, so
+    /// stop is only supported on the simulator and kernel devices
+    #[arg(long, value_enum, global = true, default_value = "continue")]
+    pub on_error: ErrorPolicy,
+
+    /// Between runs, wait until the composite temperature in degrees Celsius is below this
+    /// (simulator and kernel devices only, vroom cannot read the SMART / Health log)
+    #[arg(long, global = true)]
+    pub cool_below: Option<i32>,
+
+    /// Longest wait for --cool-below, the next run starts anyway afterwards
+    #[arg(long, global = true, value_parser = parse_duration, default_value = "10m")]
+    pub cool_timeout: Duration,
+
+    /// Abort a run once the composite temperature in degrees Celsius exceeds this
+    /// (simulator and kernel devices only)
+    #[arg(long, global = true)]
+    pub abort_above: Option<i32>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Sequential transfers per thread, sweeping over io size, queue depth and thread count
+    Cache(CacheArgs),
+    /// Repeated transfers to a single random LBA
+    SingleLba(SingleLbaArgs),
+    /// 2x2 matrix of sequential/random memory source and sequential/random LBA target
+    RandomMatrix(RandomMatrixArgs),
+    /// Zipf distributed accesses over LBA ranges of different sizes
+    Zipf(ZipfArgs),
+    /// Open-loop runs stepping the offered load towards saturation, giving a load/latency curve
+    LoadCurve(LoadCurveArgs),
+    /// Sequential and random fills of a namespace to get it into a defined state
+    Precondition(PreconditionArgs),
+    /// Repeats a workload in rounds until its IOPS reach a steady state (SNIA PTS criteria)
+    SteadyState(SteadyStateArgs),
+    /// Write amplification of sequential, random and zipf writes, from the host and media write counters of the device (simulator and kernel devices only)
+    Waf(WafArgs),
+    /// Throughput and latency of deallocate and write zeroes commands over range sizes and queue depths (simulator and kernel devices only)
+    RangeOps(RangeOpsArgs),
+    /// Reads or writes with and without deallocates running next to them on another part of the namespace (simulator and kernel devices only)
+    DeallocateImpact(DeallocateImpactArgs),
+    /// Random reads of written LBAs compared to reads of deallocated ones (simulator and kernel devices only)
+    DeallocatedRead(DeallocatedReadArgs),
+    /// Latency of a Flush command after buffering different amounts of writes in the volatile write cache (simulator and kernel devices only)
+    Flush(FlushArgs),
+    /// Writes with Force Unit Access compared to normal writes at different queue depths (simulator and kernel devices only)
+    Fua(FuaArgs),
+    /// The same workload with the volatile write cache enabled and disabled, restoring its setting afterwards (simulator and kernel devices only)
+    WriteCache(WriteCacheArgs),
+    /// Print the decoded Identify Controller data structure, as JSON with --format json
+    Identify,
+    /// Print the Identify Namespace data structure of one or all active namespaces (simulator and kernel devices only)
+    IdentifyNamespace(IdentifyNamespaceArgs),
+    /// List the active namespaces with their size and block size
+    Namespaces,
+    /// Run the jobs described in a TOML job file
+    Run(RunArgs),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Read,
+    Write,
+}
+
+impl Op {
+    pub fn is_write(self) -> bool {
+        self == Op::Write
+    }
+}
+
+/// Inter-arrival times of an open-loop run
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arrivals {
+    Constant,
+    Poisson,
+}
+
+/// Write patterns of the WAF measurement
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WritePattern {
+    Sequential,
+    Random,
+    Zipf,
+}
+
+/// Commands on an LBA range that do not transfer data
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RangeOp {
+    /// Dataset Management with the deallocate attribute (TRIM)
+    Deallocate,
+    WriteZeroes,
+}
+
+impl RangeOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            RangeOp::Deallocate => "deallocate",
+            RangeOp::WriteZeroes => "write-zeroes",
+        }
+    }
+
+    /// The optional NVM command a controller has to support for the operation
+    pub fn command(self) -> NvmCommand {
+        match self {
+            RangeOp::Deallocate => NvmCommand::DatasetManagement,
+            RangeOp::WriteZeroes => NvmCommand::WriteZeroes,
+        }
+    }
+}
+
+/// Setting of the Volatile Write Cache feature
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CacheState {
+    On,
+    Off,
+}
+
+impl CacheState {
+    pub fn enabled(self) -> bool {
+        self == CacheState::On
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheState::On => "on",
+            CacheState::Off => "off",
+        }
+    }
+}
+
+/// What a benchmark does once a command completes with an error status
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// keep going and report the failed commands with the results
+    #[default]
+    Continue,
+    /// end the run at the first failed command and skip the remaining runs
+    Stop,
+}
+
+/// Namespace option of the benchmark commands
+#[derive(Args, Debug)]
+pub struct NamespaceArgs {
+    /// Namespace to run against, defaults to the first active namespace
+    #[arg(long)]
+    pub ns: Option<u32>,
+}
+
+/// Options of the benchmark commands that report their per-thread logs as time series
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    /// Width of the time buckets the per-thread logs are combined into, down to 1ms
+    #[arg(long, value_parser = parse_bucket, default_value = "1s")]
+    pub bucket: Duration,
+
+    /// Time after which every thread starts a new log, limits how fine the buckets can resolve
+    #[arg(long, value_parser = parse_duration, default_value = "10ms")]
+    pub log_interval: Duration,
+}
+
+/// Options of the benchmark commands that run several workloads one after another
+#[derive(Args, Debug)]
+pub struct CommonArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    /// Pause between two runs or jobs, with --cool-below also until the device cooled down
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub cooldown: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct CacheArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Operations to sweep over
+    #[arg(long, value_delimiter = ',', default_value = "write")]
+    pub op: Vec<Op>,
+
+    /// IO sizes per request, as list and/or ranges (e.g. 8K,1M or 4K..1M or 4K..64K:+4K)
+    #[arg(long, value_parser = parse_size_list, default_value = "8K,1M")]
+    pub io_size: ValueList,
+
+    /// Queue depths per thread
+    #[arg(long, value_parser = parse_count_list, default_value = "1,32,128")]
+    pub queue_depth: ValueList,
+
+    /// Number of threads, each with its own queue pair
+    #[arg(long, value_parser = parse_count_list, default_value = "1,8,32")]
+    pub threads: ValueList,
+
+    /// Total bytes transferred per sweep point, split evenly between threads
+    #[arg(long, value_parser = parse_size, default_value = "64G")]
+    pub max_bytes: u64,
+
+    /// Measure for this long instead of up to max_bytes
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// IOs issued before measuring, excluded from the results
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    pub warmup: Duration,
+
+    /// IOs issued after measuring, excluded from the results
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    pub ramp_down: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct SingleLbaArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[arg(long, value_delimiter = ',', default_value = "write,read")]
+    pub op: Vec<Op>,
+
+    /// Amount of transferred data in multiples of HUGE_PAGE_SIZE
+    #[arg(long, default_value_t = 32)]
+    pub loops: u64,
+
+    /// Transfer for this long instead of a fixed amount of loops
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct RandomMatrixArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[arg(long, value_delimiter = ',', default_value = "write")]
+    pub op: Vec<Op>,
+
+    /// Repetitions per matrix cell
+    #[arg(long, default_value_t = 10)]
+    pub iterations: usize,
+
+    /// Repeat every cell for this long instead of a fixed amount of iterations
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct ZipfArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[arg(long, value_delimiter = ',', default_value = "write,read")]
+    pub op: Vec<Op>,
+
+    /// Number of distinct LBAs the distribution is drawn from
+    #[arg(long, value_parser = parse_size_list, default_value = "4096,32768,262144,2097152")]
+    pub n: ValueList,
+
+    /// Zipf exponents
+    #[arg(long, value_delimiter = ',', default_value = "1,2")]
+    pub s: Vec<f64>,
+
+    /// Repeat the accesses of every point for at least this long
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct LoadCurveArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    #[arg(long, value_enum, default_value = "read")]
+    pub op: Op,
+
+    /// Mixed workload with this share of reads, replaces --op
+    #[arg(long, conflicts_with = "op")]
+    pub read_percent: Option<f64>,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    /// Upper bound of requests in flight per thread, also used for the saturation run
+    #[arg(long, value_parser = parse_count, default_value_t = 128)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Access the LBAs sequentially instead of randomly
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// Measured time per load step
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub duration: Duration,
+
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    pub warmup: Duration,
+
+    /// Offered loads in percent of the measured saturation IOPS
+    #[arg(long, value_delimiter = ',', default_value = "10,20,30,40,50,60,70,80,90,95,100,110")]
+    pub percent: Vec<f64>,
+
+    #[arg(long, value_enum, default_value = "poisson")]
+    pub arrivals: Arrivals,
+}
+
+#[derive(Args, Debug)]
+pub struct FillArgs {
+    /// Sequential passes of 128K writes over the whole namespace
+    #[arg(long, default_value_t = 2)]
+    pub seq_fills: u32,
+
+    /// Random 4K writes of this many times the namespace capacity, after the sequential passes
+    #[arg(long, default_value_t = 0.0)]
+    pub random_fills: f64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub fill_queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 4)]
+    pub fill_threads: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct PreconditionArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub fill: FillArgs,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SteadyStateArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[arg(long, value_enum, default_value = "write")]
+    pub op: Op,
+
+    /// Mixed workload with this share of reads, replaces --op
+    #[arg(long, conflicts_with = "op")]
+    pub read_percent: Option<f64>,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Access the LBAs sequentially instead of randomly
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// Length of one round
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    pub round: Duration,
+
+    #[arg(long, default_value_t = 25)]
+    pub max_rounds: usize,
+
+    /// Number of rounds the criteria are evaluated over
+    #[arg(long, default_value_t = 5)]
+    pub window: usize,
+
+    /// Largest allowed (max - min) of the window in percent of its average
+    #[arg(long, default_value_t = 20.0)]
+    pub max_excursion: f64,
+
+    /// Largest allowed change of the best-fit line over the window in percent of its average
+    #[arg(long, default_value_t = 10.0)]
+    pub max_slope_excursion: f64,
+
+    /// Start measuring right away, e.g. on a namespace preconditioned with the precondition command
+    #[arg(long)]
+    pub skip_precondition: bool,
+
+    #[command(flatten)]
+    pub fill: FillArgs,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct WafArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Patterns to measure, one run each
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "sequential,random,zipf")]
+    pub pattern: Vec<WritePattern>,
+
+    /// Exponent of the zipf pattern
+    #[arg(long, default_value_t = 1.2)]
+    pub zipf_s: f64,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Write time per pattern, the counters of the device only change every 512000 bytes
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    pub duration: Duration,
+
+    /// Write this many bytes per pattern instead of for a fixed time
+    #[arg(long, value_parser = parse_size, conflicts_with = "duration")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+pub struct RangeOpsArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Commands to sweep over
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "deallocate,write-zeroes")]
+    pub op: Vec<RangeOp>,
+
+    /// Bytes covered per command, as list and/or ranges
+    #[arg(long, value_parser = parse_size_list, default_value = "4K,64K,1M,16M")]
+    pub range_size: ValueList,
+
+    /// Queue depths per thread
+    #[arg(long, value_parser = parse_count_list, default_value = "1,32")]
+    pub queue_depth: ValueList,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Walk the window sequentially instead of picking random ranges
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// Measured time per sweep point
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub duration: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct DeallocateImpactArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Operation of the foreground workload on the first half of the window
+    #[arg(long, value_enum, default_value = "read")]
+    pub op: Op,
+
+    /// Mixed foreground workload with this share of reads, replaces --op
+    #[arg(long, conflicts_with = "op")]
+    pub read_percent: Option<f64>,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Command of the background workload on the second half of the window
+    #[arg(long, value_enum, default_value = "deallocate")]
+    pub range_op: RangeOp,
+
+    /// Bytes covered per background command
+    #[arg(long, value_parser = parse_size, default_value = "1M")]
+    pub range_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 8)]
+    pub range_queue_depth: usize,
+
+    /// Measured time of both runs
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub duration: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct DeallocatedReadArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Size of the written and of the deallocated region at the start of the window
+    #[arg(long, value_parser = parse_size, default_value = "1G")]
+    pub region: u64,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Measured read time per region
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub duration: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct FlushArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Bytes written sequentially before every flush, as list and/or ranges, 0 flushes an idle cache
+    #[arg(long, value_parser = parse_size_list, default_value = "0,1M,16M,256M")]
+    pub buffered: ValueList,
+
+    #[arg(long, value_parser = parse_size, default_value = "128K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Flushes per buffered size
+    #[arg(long, value_parser = parse_count, default_value_t = 20)]
+    pub repeat: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct FuaArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    /// Queue depths per thread, each runs with normal and with FUA writes
+    #[arg(long, value_parser = parse_count_list, default_value = "1,4,32")]
+    pub queue_depth: ValueList,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Write sequentially instead of to random LBAs
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// Measured time per run
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    pub duration: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct WriteCacheArgs {
+    #[command(flatten)]
+    pub namespace: NamespaceArgs,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Settings of the volatile write cache to run the workload with, in this order
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "on,off")]
+    pub state: Vec<CacheState>,
+
+    #[arg(long, value_enum, default_value = "write")]
+    pub op: Op,
+
+    /// Mixed workload with this share of reads, replaces --op
+    #[arg(long, conflicts_with = "op")]
+    pub read_percent: Option<f64>,
+
+    /// Submit the writes with Force Unit Access
+    #[arg(long)]
+    pub fua: bool,
+
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    pub io_size: u64,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 32)]
+    pub queue_depth: usize,
+
+    #[arg(long, value_parser = parse_count, default_value_t = 1)]
+    pub threads: usize,
+
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// Measured time per setting
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    pub duration: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct IdentifyNamespaceArgs {
+    /// Namespace to identify, defaults to all active namespaces
+    #[arg(long)]
+    pub ns: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Job file to execute
+    pub file: PathBuf,
+
+    #[command(flatten)]
+    pub common: CommonArgs,
+}
+
+/// Expanded list of values given as comma separated items and ranges
+#[derive(Clone, Debug)]
+pub struct ValueList(pub Vec<u64>);
+
+/**
+ * Parses sizes with an optional binary suffix (K, M, G, T), e.g. "8K" or "1M"
+ */
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix in '{}'", s)),
+            };
+            (&s[..i], shift)
+        }
+        _ => (s, 0),
+    };
+    let value: u64 = digits.trim().parse().map_err(|_| format!("invalid size '{}'", s))?;
+    value.checked_shl(shift).filter(|v| v >> shift == value).ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/**
+ * Parses a comma separated list where every item is either a single size or a range
+ * "start..end[:step]". The step is "xN" (geometric, the default is x2) or "+N" (linear).
+ */
+pub fn parse_size_list(s: &str) -> Result<ValueList, String> {
+    let mut values = Vec::new();
+    for item in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let Some((start, rest)) = item.split_once("..") else {
+            values.push(parse_size(item)?);
+            continue;
+        };
+        let (end, step) = rest.split_once(':').unwrap_or((rest, "x2"));
+        let (start, end) = (parse_size(start)?, parse_size(end)?);
+        if start > end {
+            return Err(format!("range '{}' is empty", item));
+        }
+
+        let next: Box<dyn Fn(u64) -> u64> = if let Some(factor) = step.strip_prefix('x') {
+            let factor = parse_size(factor)?;
+            if factor < 2 || start == 0 {
+                return Err(format!("geometric range '{}' needs a start > 0 and a factor >= 2", item));
+            }
+            Box::new(move |v| v.saturating_mul(factor))
+        } else if let Some(increment) = step.strip_prefix('+') {
+            let increment = parse_size(increment)?;
+            if increment == 0 {
+                return Err(format!("linear range '{}' needs an increment > 0", item));
+            }
+            Box::new(move |v| v.saturating_add(increment))
+        } else {
+            return Err(format!("invalid step '{}', expected xN or +N", step));
+        };
+
+        let mut v = start;
+        while v <= end {
+            values.push(v);
+            if next(v) == v {
+                break;
+            }
+            v = next(v);
+        }
+    }
+
+    if values.is_empty() {
+        return Err("list is empty".into());
+    }
+    Ok(ValueList(values))
+}
+
+/**
+ * Parses a thread count or queue depth, which has to be at least 1
+ */
+pub fn parse_count(s: &str) -> Result<usize, String> {
+    let count: usize = s.trim().parse().map_err(|_| format!("invalid count '{}'", s))?;
+    if count == 0 {
+        return Err("count has to be at least 1".into());
+    }
+    Ok(count)
+}
+
+/**
+ * Like parse_size_list, but for thread counts and queue depths which have to be at least 1
+ */
+pub fn parse_count_list(s: &str) -> Result<ValueList, String> {
+    let list = parse_size_list(s)?;
+    if list.0.contains(&0) {
+        return Err(format!("'{}' contains 0, counts have to be at least 1", s));
+    }
+    Ok(list)
+}
+
+/**
+ * Parses a bucket width like parse_duration, rejecting widths below a millisecond which mostly
+ * show the completion batching of the threads
+ */
+pub fn parse_bucket(s: &str) -> Result<Duration, String> {
+    let bucket = parse_duration(s)?;
+    if bucket < Duration::from_millis(1) {
+        return Err(format!("bucket width '{}' is below 1ms", s));
+    }
+    Ok(bucket)
+}
+
+/**
+ * Parses durations like "1s", "250ms", "100us" or "2m"; plain numbers are seconds
+ */
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+    let secs = match unit.trim() {
+        "" | "s" => value,
+        "ms" => value / 1e3,
+        "us" => value / 1e6,
+        "m" | "min" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("unknown duration unit in '{}'", s)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid duration '{}'", s))
+}
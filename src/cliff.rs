@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::util::{combine_results, IoLog, Throughput};
+
+/// Finest resolution the throughput series is analysed at
+const MIN_RESOLUTION: Duration = Duration::from_millis(10);
+/// Number of buckets the series is split into if the run is long enough
+const TARGET_BUCKETS: u32 = 256;
+/// Neighbouring segments closer than this (relative) are treated as the same tier
+const MERGE_THRESHOLD: f64 = 0.10;
+/// Minimum relative drop between two tiers to be reported as a cliff
+const CLIFF_THRESHOLD: f64 = 0.20;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Tier {
+    pub start_s: f64,
+    pub end_s: f64,
+    /// bytes written before the tier starts / ends, over all threads
+    pub start_bytes: u64,
+    pub end_bytes: u64,
+    pub mib_per_s: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Cliff {
+    pub time_s: f64,
+    pub bytes_before: u64,
+    pub pre_mib_per_s: f64,
+    pub post_mib_per_s: f64,
+    /// in [0, 1], derived from a Welch t-test between the tiers before and after the cliff
+    pub confidence: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CacheAnalysis {
+    pub total_bytes: u64,
+    pub tiers: Vec<Tier>,
+    /// first drop of at least CLIFF_THRESHOLD between two tiers, None if the budget did not exhaust the cache
+    pub cliff: Option<Cliff>,
+    /// bandwidth of the last tier
+    pub sustained_mib_per_s: f64,
+}
+
+/**
+ * Detects throughput tiers (e.g. SLC cache -> TLC -> folding) in the logs of a sequential write.
+ * The series is analysed at a resolution independent of the reporting bucket width.
+ */
+pub fn analyze_logs(results: &Vec<Vec<IoLog>>) -> CacheAnalysis {
+    let total = Throughput::from_logs(results);
+    let resolution = (total.duration / TARGET_BUCKETS).max(MIN_RESOLUTION);
+    analyze_series(&combine_results(results, resolution), resolution)
+}
+
+/**
+ * @param series (actions, bytes) per bucket as returned by combine_results
+ */
+pub fn analyze_series(series: &[(f64, f64)], bucket_duration: Duration) -> CacheAnalysis {
+    let width = bucket_duration.as_secs_f64();
+    let total_bytes = series.iter().map(|b| b.1).sum::<f64>() as u64;
+
+    // the last bucket is only partially covered by the run
+    let series = if series.len() > 1 { &series[..series.len() - 1] } else { series };
+    let x: Vec<f64> = series.iter().map(|b| b.1 / width).collect();
+    let mut cumulative = vec![0.0; x.len() + 1];
+    for (i, b) in series.iter().enumerate() {
+        cumulative[i + 1] = cumulative[i] + b.1;
+    }
+
+    if x.is_empty() {
+        return CacheAnalysis { total_bytes, tiers: Vec::new(), cliff: None, sustained_mib_per_s: 0.0 };
+    }
+
+    let mut bounds = vec![0];
+    bounds.extend(change_points(&x));
+    bounds.push(x.len());
+    let bounds = merge_similar(&x, bounds);
+
+    let tiers: Vec<Tier> = bounds.windows(2).map(|w| Tier {
+        start_s: w[0] as f64 * width,
+        end_s: w[1] as f64 * width,
+        start_bytes: cumulative[w[0]] as u64,
+        end_bytes: cumulative[w[1]] as u64,
+        mib_per_s: mean(&x[w[0]..w[1]]) / (1024.0 * 1024.0),
+    }).collect();
+
+    let cliff = bounds.windows(3).find_map(|w| {
+        let (before, after) = (&x[w[0]..w[1]], &x[w[1]..w[2]]);
+        if mean(after) > mean(before) * (1.0 - CLIFF_THRESHOLD) {
+            return None;
+        }
+        Some(Cliff {
+            time_s: w[1] as f64 * width,
+            bytes_before: cumulative[w[1]] as u64,
+            pre_mib_per_s: mean(before) / (1024.0 * 1024.0),
+            post_mib_per_s: mean(after) / (1024.0 * 1024.0),
+            confidence: welch_confidence(before, after),
+        })
+    });
+
+    let sustained_mib_per_s = tiers.last().map_or(0.0, |t| t.mib_per_s);
+    CacheAnalysis { total_bytes, tiers, cliff, sustained_mib_per_s }
+}
+
+fn mean(x: &[f64]) -> f64 {
+    x.iter().sum::<f64>() / x.len() as f64
+}
+
+fn variance(x: &[f64]) -> f64 {
+    if x.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(x);
+    x.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (x.len() - 1) as f64
+}
+
+/**
+ * Binary segmentation for changes in mean. A split is accepted if it reduces the squared error by
+ * more than a BIC style penalty, the noise level is estimated robustly from the first differences.
+ * @returns sorted inner segment boundaries
+ */
+fn change_points(x: &[f64]) -> Vec<usize> {
+    let n = x.len();
+    let min_len = (n / 50).max(3);
+    if n < 2 * min_len {
+        return Vec::new();
+    }
+
+    let mut prefix = vec![0.0; n + 1];
+    let mut prefix_sq = vec![0.0; n + 1];
+    for i in 0..n {
+        prefix[i + 1] = prefix[i] + x[i];
+        prefix_sq[i + 1] = prefix_sq[i] + x[i] * x[i];
+    }
+
+    let mut diffs: Vec<f64> = x.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    diffs.sort_by(|a, b| a.total_cmp(b));
+    let mut sigma = diffs[diffs.len() / 2] / (0.6745 * 2f64.sqrt());
+    if sigma <= 0.0 {
+        sigma = 0.01 * mean(x).abs().max(1.0);
+    }
+    let penalty = 3.0 * sigma * sigma * (n as f64).ln();
+
+    let mut bounds = Vec::new();
+    split(&prefix, &prefix_sq, 0, n, min_len, penalty, &mut bounds);
+    bounds
+}
+
+fn split(prefix: &[f64], prefix_sq: &[f64], a: usize, b: usize, min_len: usize, penalty: f64, bounds: &mut Vec<usize>) {
+    if b - a < 2 * min_len {
+        return;
+    }
+    let cost = |a: usize, b: usize| {
+        let sum = prefix[b] - prefix[a];
+        prefix_sq[b] - prefix_sq[a] - sum * sum / (b - a) as f64
+    };
+
+    let total = cost(a, b);
+    let (best, gain) = (a + min_len..=b - min_len)
+        .map(|k| (k, total - cost(a, k) - cost(k, b)))
+        .max_by(|l, r| l.1.total_cmp(&r.1))
+        .unwrap();
+
+    if gain > penalty {
+        split(prefix, prefix_sq, a, best, min_len, penalty, bounds);
+        bounds.push(best);
+        split(prefix, prefix_sq, best, b, min_len, penalty, bounds);
+    }
+}
+
+/// Removes boundaries between segments whose means differ by less than MERGE_THRESHOLD
+fn merge_similar(x: &[f64], mut bounds: Vec<usize>) -> Vec<usize> {
+    loop {
+        let similar = bounds.windows(3).position(|w| {
+            let (l, r) = (mean(&x[w[0]..w[1]]), mean(&x[w[1]..w[2]]));
+            (l - r).abs() < MERGE_THRESHOLD * l.abs().max(r.abs())
+        });
+        match similar {
+            Some(i) => {
+                bounds.remove(i + 1);
+            }
+            None => return bounds,
+        }
+    }
+}
+
+fn welch_confidence(a: &[f64], b: &[f64]) -> f64 {
+    let se = (variance(a) / a.len() as f64 + variance(b) / b.len() as f64).sqrt();
+    if se == 0.0 {
+        return 1.0;
+    }
+    let t = (mean(a) - mean(b)).abs() / se;
+    erf(t / 2f64.sqrt())
+}
+
+/// Abramowitz & Stegun 7.1.26, absolute error below 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: f64 = 1024.0 * 1024.0;
+    const BUCKET: Duration = Duration::from_millis(100);
+
+    /// (actions, bytes) per bucket for the given MiB/s, with a little deterministic noise
+    fn series(levels: &[(usize, f64)]) -> Vec<(f64, f64)> {
+        let width = BUCKET.as_secs_f64();
+        levels.iter()
+            .flat_map(|&(n, mib_per_s)| std::iter::repeat_n(mib_per_s, n))
+            .enumerate()
+            .map(|(i, mib_per_s)| {
+                let noise = 1.0 + 0.02 * ((i * 7 % 5) as f64 - 2.0) / 2.0;
+                let bytes = mib_per_s * MIB * width * noise;
+                (bytes / 4096.0, bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_cliff_after_the_cache() {
+        let analysis = analyze_series(&series(&[(60, 3000.0), (60, 800.0), (1, 0.0)]), BUCKET);
+
+        let cliff = analysis.cliff.expect("no cliff found");
+        assert!((cliff.time_s - 6.0).abs() <= 0.2, "cliff at {} s", cliff.time_s);
+        assert!((cliff.pre_mib_per_s - 3000.0).abs() < 100.0);
+        assert!((cliff.post_mib_per_s - 800.0).abs() < 50.0);
+        assert!(cliff.confidence > 0.99);
+        assert!((analysis.sustained_mib_per_s - 800.0).abs() < 50.0);
+        assert_eq!(analysis.tiers.len(), 2);
+    }
+
+    #[test]
+    fn steady_throughput_has_no_cliff() {
+        let analysis = analyze_series(&series(&[(100, 2000.0), (1, 0.0)]), BUCKET);
+
+        assert!(analysis.cliff.is_none());
+        assert_eq!(analysis.tiers.len(), 1);
+        assert!((analysis.sustained_mib_per_s - 2000.0).abs() < 50.0);
+    }
+
+    #[test]
+    fn small_steps_are_merged_into_one_tier() {
+        let analysis = analyze_series(&series(&[(50, 2000.0), (50, 1900.0), (1, 0.0)]), BUCKET);
+
+        assert!(analysis.cliff.is_none());
+        assert_eq!(analysis.tiers.len(), 1);
+    }
+
+    #[test]
+    fn empty_series() {
+        let analysis = analyze_series(&[], BUCKET);
+        assert_eq!(analysis.total_bytes, 0);
+        assert!(analysis.tiers.is_empty() && analysis.cliff.is_none());
+    }
+}
@@ -0,0 +1,430 @@
+use std::alloc::{self, Layout};
+use std::ops::Range;
+use std::fmt;
+
+use vroom::memory::{Dma, DmaSlice};
+use vroom::{IdentifyControllerInfo, NvmeDevice, NvmeQueuePair};
+
+use crate::cli::RangeOp;
+use crate::error::BenchmarkError;
+use crate::features::{ControllerCapabilities, NamespaceIdentity, VOLATILE_WRITE_CACHE};
+use crate::health::{SmartLog, SMART_LOG_ID, SMART_LOG_SIZE};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Namespace {
+    pub id: u32,
+    pub blocks: u64,
+    pub block_size: u64,
+}
+
+/// The blocks first..first + blocks of a namespace the benchmarks are allowed to access
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LbaWindow {
+    pub first: u64,
+    pub blocks: u64,
+}
+
+impl LbaWindow {
+    pub fn whole(ns: &Namespace) -> LbaWindow {
+        LbaWindow { first: 0, blocks: ns.blocks }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.first + self.blocks
+    }
+
+    pub fn contains(&self, first: u64, blocks: u64) -> bool {
+        first >= self.first && first.checked_add(blocks).is_some_and(|end| end <= self.end())
+    }
+}
+
+impl std::fmt::Display for LbaWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LBA {}..{}", self.first, self.end())
+    }
+}
+
+/// NVMe ASCII fields are padded with spaces
+pub fn ascii_field<const N: usize>(s: &str) -> [u8; N] {
+    let mut field = [b' '; N];
+    let len = s.len().min(N);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+    field
+}
+
+/// Status field of a completion queue entry (bits 31:17 of DW3)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompletionStatus(pub u16);
+
+impl CompletionStatus {
+    pub const SUCCESS: CompletionStatus = CompletionStatus(0);
+    /// Generic Command Status, Data Transfer Error
+    pub const DATA_TRANSFER_ERROR: CompletionStatus = CompletionStatus(0x04);
+    /// Media and Data Integrity Errors, Write Fault
+    pub const WRITE_FAULT: CompletionStatus = CompletionStatus(0x2 << 8 | 0x80);
+    /// Media and Data Integrity Errors, Unrecovered Read Error
+    pub const UNRECOVERED_READ_ERROR: CompletionStatus = CompletionStatus(0x2 << 8 | 0x81);
+    /// Path Related Status, Command Aborted By Host, also for commands the host gave up waiting for
+    pub const ABORTED_BY_HOST: CompletionStatus = CompletionStatus(0x3 << 8 | 0x71);
+
+    /// Status code type and status code are both zero
+    pub fn is_success(self) -> bool {
+        self.0 & 0x7ff == 0
+    }
+
+    /// Status code type, e.g. 0 for generic command status or 2 for media errors
+    pub fn sct(self) -> u8 {
+        (self.0 >> 8 & 0x7) as u8
+    }
+
+    pub fn sc(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Without the retry delay, more and do not retry bits, which do not tell what went wrong
+    pub fn code(self) -> CompletionStatus {
+        CompletionStatus(self.0 & 0x7ff)
+    }
+
+    /// Name of the status codes of the NVM command set, the type for all others
+    pub fn description(self) -> &'static str {
+        match (self.sct(), self.sc()) {
+            (0, 0x00) => "successful completion",
+            (0, 0x01) => "invalid command opcode",
+            (0, 0x02) => "invalid field in command",
+            (0, 0x04) => "data transfer error",
+            (0, 0x05) => "aborted due to power loss notification",
+            (0, 0x06) => "internal error",
+            (0, 0x07) => "command abort requested",
+            (0, 0x08) => "command aborted due to SQ deletion",
+            (0, 0x0b) => "invalid namespace or format",
+            (0, 0x80) => "LBA out of range",
+            (0, 0x81) => "capacity exceeded",
+            (0, 0x82) => "namespace not ready",
+            (2, 0x80) => "write fault",
+            (2, 0x81) => "unrecovered read error",
+            (2, 0x82) => "end-to-end guard check error",
+            (2, 0x83) => "end-to-end application tag check error",
+            (2, 0x84) => "end-to-end reference tag check error",
+            (2, 0x85) => "compare failure",
+            (2, 0x86) => "access denied",
+            (2, 0x87) => "deallocated or unwritten logical block",
+            (3, 0x71) => "command aborted by host",
+            (0, _) => "generic command status",
+            (1, _) => "command specific status",
+            (2, _) => "media and data integrity error",
+            (3, _) => "path related status",
+            (7, _) => "vendor specific status",
+            _ => "reserved status code type",
+        }
+    }
+}
+
+impl fmt::Display for CompletionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SCT {:#x} SC {:#04x}", self.sct(), self.sc())
+    }
+}
+
+/**
+ * Memory the device transfers from and to. Like DMA memory, backends may fill it with read data
+ * while only holding a shared reference, so it must not be accessed while a read into it is in flight.
+ */
+pub trait IoBuffer {
+    fn len(&self) -> usize;
+    fn as_slice(&self) -> &[u8];
+    fn as_mut_slice(&mut self) -> &mut [u8];
+}
+
+/**
+ * The queue pair operations the benchmarks use
+ */
+pub trait IoQueue: Send + 'static {
+    type Buffer: IoBuffer;
+
+    fn allocate_buffer(&self, size: usize) -> Result<Self::Buffer, BenchmarkError>;
+
+    /**
+     * @returns the number of commands the request was split into, 0 if it could not be queued
+     */
+    fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &Self::Buffer, range: Range<usize>, lba: u64, write: bool) -> usize;
+
+    /**
+     * Submits a command on blocks LBAs that transfers no data, e.g. a deallocate
+     * @returns the number of commands the range was split into, 0 if it could not be queued
+     */
+    fn submit_range(&mut self, _ns_id: u32, _block_size: u64, _lba: u64, _blocks: u64, _op: RangeOp) -> usize {
+        0
+    }
+
+    /**
+     * Submits a write with Force Unit Access, which only completes once the data is on non-volatile media
+     * @returns the number of commands the request was split into, 0 if it could not be queued
+     */
+    fn submit_fua(&mut self, _ns_id: u32, _block_size: u64, _data: &Self::Buffer, _range: Range<usize>, _lba: u64) -> usize {
+        0
+    }
+
+    /**
+     * Submits a Flush, which completes once all writes to the namespace completed before are on non-volatile media
+     * @returns 1, 0 if it could not be queued
+     */
+    fn submit_flush(&mut self, _ns_id: u32) -> usize {
+        0
+    }
+
+    /**
+     * Blocks until n commands have completed
+     * @returns how many of them failed
+     */
+    fn complete_io(&mut self, n: usize) -> usize;
+
+    /// Reaps a single completion if one is available
+    fn quick_poll(&mut self) -> Option<CompletionStatus>;
+}
+
+/**
+ * A controller the benchmarks can run against
+ */
+pub trait NvmeBackend {
+    type Queue: IoQueue;
+
+    /// Active namespaces sorted by id
+    fn namespaces(&self) -> Vec<Namespace>;
+
+    fn namespace(&self, id: u32) -> Option<Namespace> {
+        self.namespaces().into_iter().find(|ns| ns.id == id)
+    }
+
+    fn controller_info(&self) -> &IdentifyControllerInfo;
+
+    /// CAP.MPSMIN in bytes, vroom and the other backends only use 4 KiB pages
+    fn min_page_size(&self) -> u64 {
+        4096
+    }
+
+    fn capabilities(&self) -> ControllerCapabilities {
+        ControllerCapabilities::new(self.controller_info(), self.min_page_size())
+    }
+
+    /// Identify Namespace, vroom has no passthrough for admin commands and does not support it
+    fn identify_namespace(&mut self, id: u32) -> Result<NamespaceIdentity, BenchmarkError> {
+        Err(BenchmarkError::Unsupported(format!("Identify Namespace {} on this backend", id)))
+    }
+
+    /// Reads the first bytes of a controller wide log page with Get Log Page, vroom does not support it
+    fn get_log_page(&mut self, log_id: u8, _data: &mut [u8]) -> Result<(), BenchmarkError> {
+        Err(BenchmarkError::Unsupported(format!("Get Log Page {:#04x} on this backend", log_id)))
+    }
+
+    fn smart_log(&mut self) -> Result<SmartLog, BenchmarkError> {
+        let mut data = [0u8; SMART_LOG_SIZE];
+        self.get_log_page(SMART_LOG_ID, &mut data)?;
+        Ok(SmartLog::parse(&data))
+    }
+
+    /// Whether the queue pairs can submit the range operation, vroom only submits reads and writes
+    fn supports_range_op(&self, _op: RangeOp) -> bool {
+        false
+    }
+
+    /// Whether the queue pairs can submit FUA writes and flushes, vroom cannot
+    fn supports_durable_writes(&self) -> bool {
+        false
+    }
+
+    /// Whether admin commands besides the queue pair management can be issued, vroom has no passthrough for them
+    fn supports_admin_commands(&self) -> bool {
+        false
+    }
+
+    /// Whether the queue pairs report the status of their completions, vroom only reports that they completed
+    fn reports_completion_status(&self) -> bool {
+        false
+    }
+
+    /// Get Features of the controller, vroom does not support it
+    fn get_features(&mut self, fid: u8) -> Result<u32, BenchmarkError> {
+        Err(BenchmarkError::Unsupported(format!("Get Features {:#04x} on this backend", fid)))
+    }
+
+    /// Set Features of the controller, vroom does not support it
+    fn set_features(&mut self, fid: u8, _value: u32) -> Result<(), BenchmarkError> {
+        Err(BenchmarkError::Unsupported(format!("Set Features {:#04x} on this backend", fid)))
+    }
+
+    fn write_cache_enabled(&mut self) -> Result<bool, BenchmarkError> {
+        Ok(self.get_features(VOLATILE_WRITE_CACHE)? & 1 != 0)
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) -> Result<(), BenchmarkError> {
+        self.set_features(VOLATILE_WRITE_CACHE, enabled as u32)
+    }
+
+    fn create_io_queue_pair(&mut self, len: usize) -> Result<Self::Queue, BenchmarkError>;
+
+    fn delete_io_queue_pair(&mut self, queue_pair: Self::Queue) -> Result<(), BenchmarkError>;
+
+    /**
+     * Runs f on a fresh queue pair and deletes it afterwards, also if f fails
+     */
+    fn with_queue_pair<T>(&mut self, len: usize, f: impl FnOnce(&mut Self::Queue) -> Result<T, BenchmarkError>) -> Result<T, BenchmarkError> where Self: Sized {
+        let mut queue_pair = self.create_io_queue_pair(len)?;
+        let result = f(&mut queue_pair);
+        let deleted = self.delete_io_queue_pair(queue_pair);
+        let result = result?;
+        deleted?;
+        Ok(result)
+    }
+}
+
+impl IoBuffer for Dma<u8> {
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self[0..self.size]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        let size = self.size;
+        &mut self[0..size]
+    }
+}
+
+impl IoQueue for NvmeQueuePair {
+    type Buffer = Dma<u8>;
+
+    fn allocate_buffer(&self, size: usize) -> Result<Dma<u8>, BenchmarkError> {
+        Dma::allocate(size).map_err(|e| BenchmarkError::Allocation { size, reason: e.to_string() })
+    }
+
+    fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &Dma<u8>, range: Range<usize>, lba: u64, write: bool) -> usize {
+        NvmeQueuePair::submit_io(self, ns_id, block_size, &data.slice(range), lba, write)
+    }
+
+    // vroom does not hand out the status of its completions, see NvmeBackend::reports_completion_status
+    fn complete_io(&mut self, n: usize) -> usize {
+        NvmeQueuePair::complete_io(self, n);
+        0
+    }
+
+    fn quick_poll(&mut self) -> Option<CompletionStatus> {
+        NvmeQueuePair::quick_poll(self).map(|()| CompletionStatus::SUCCESS)
+    }
+}
+
+impl NvmeBackend for NvmeDevice {
+    type Queue = NvmeQueuePair;
+
+    fn namespaces(&self) -> Vec<Namespace> {
+        let mut namespaces: Vec<_> = self.namespaces.values()
+            .map(|ns| Namespace { id: ns.id, blocks: ns.blocks, block_size: ns.block_size })
+            .collect();
+        namespaces.sort_by_key(|ns| ns.id);
+        namespaces
+    }
+
+    fn controller_info(&self) -> &IdentifyControllerInfo {
+        &self.identify_controller_info
+    }
+
+    fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair, BenchmarkError> {
+        NvmeDevice::create_io_queue_pair(self, len).map_err(BenchmarkError::device)
+    }
+
+    fn delete_io_queue_pair(&mut self, queue_pair: NvmeQueuePair) -> Result<(), BenchmarkError> {
+        NvmeDevice::delete_io_queue_pair(self, queue_pair).map_err(BenchmarkError::device)
+    }
+}
+
+/**
+ * Page aligned heap memory for backends that do not need DMA memory
+ */
+pub struct HostBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// the buffer exclusively owns its allocation
+unsafe impl Send for HostBuffer {}
+
+impl HostBuffer {
+    pub const ALIGNMENT: usize = 4096;
+
+    pub fn allocate(size: usize) -> Result<HostBuffer, BenchmarkError> {
+        let layout = Layout::from_size_align(size.max(1), Self::ALIGNMENT)
+            .map_err(|e| BenchmarkError::Allocation { size, reason: e.to_string() })?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(BenchmarkError::Allocation { size, reason: "out of memory".into() });
+        }
+        Ok(HostBuffer { ptr, layout })
+    }
+
+    /**
+     * Raw access for backends that complete reads while only holding a shared reference to the buffer
+     */
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+impl IoBuffer for HostBuffer {
+    fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for HostBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_fields() {
+        // SCT 2, SC 0x81 with CRD 1, More and Do Not Retry set
+        let status = CompletionStatus(0x4000 | 0x2000 | 0x0800 | 0x0281);
+        assert!(!status.is_success());
+        assert_eq!(status.sct(), 2);
+        assert_eq!(status.sc(), 0x81);
+        assert_eq!(status.code(), CompletionStatus::UNRECOVERED_READ_ERROR);
+        assert_eq!(status.description(), "unrecovered read error");
+        assert_eq!(status.to_string(), "SCT 0x2 SC 0x81");
+    }
+
+    #[test]
+    fn retry_bits_alone_are_success() {
+        assert!(CompletionStatus::SUCCESS.is_success());
+        assert!(CompletionStatus(0x4000 | 0x2000 | 0x1800).is_success());
+    }
+
+    #[test]
+    fn unknown_codes_fall_back_to_their_type() {
+        assert_eq!(CompletionStatus(0x0042).description(), "generic command status");
+        assert_eq!(CompletionStatus(0x01ff).description(), "command specific status");
+        assert_eq!(CompletionStatus(0x0700).description(), "vendor specific status");
+        assert_eq!(CompletionStatus(0x0500).description(), "reserved status code type");
+    }
+
+    #[test]
+    fn window_end() {
+        let ns = Namespace { id: 1, blocks: 1000, block_size: 512 };
+        assert_eq!(LbaWindow::whole(&ns).end(), 1000);
+        assert_eq!(LbaWindow { first: 100, blocks: 50 }.end(), 150);
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+
+use crate::util::QueuePairError;
+
+/**
+ * Errors of the benchmark engine and its backends. Errors of the driver are kept as text, so they
+ * can be passed on from the benchmark threads.
+ */
+#[derive(Debug, Clone)]
+pub enum BenchmarkError {
+    /// the driver, kernel or simulator refused an operation, e.g. creating a queue pair
+    Device(String),
+    /// a queue pair could not be used as intended, e.g. it rejected a request
+    QueuePair(String),
+    Allocation { size: usize, reason: String },
+    InvalidParameter(String),
+    /// the backend cannot issue a command, e.g. an admin command without passthrough
+    Unsupported(String),
+    /// a benchmark thread panicked, its queue pair is lost
+    Thread(String),
+    /// the composite temperature exceeded the limit of the run
+    Overheated { temperature: i32, limit: i32 },
+}
+
+impl BenchmarkError {
+    pub fn device(error: impl fmt::Display) -> BenchmarkError {
+        BenchmarkError::Device(error.to_string())
+    }
+}
+
+impl fmt::Display for BenchmarkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchmarkError::Device(message) => write!(f, "device error: {}", message),
+            BenchmarkError::QueuePair(message) => write!(f, "queue pair error: {}", message),
+            BenchmarkError::Allocation { size, reason } => write!(f, "failed to allocate {} bytes: {}", size, reason),
+            BenchmarkError::InvalidParameter(message) => write!(f, "invalid parameter: {}", message),
+            BenchmarkError::Unsupported(message) => write!(f, "not supported: {}", message),
+            BenchmarkError::Thread(message) => write!(f, "benchmark thread failed: {}", message),
+            BenchmarkError::Overheated { temperature, limit } => write!(f, "aborted at a composite temperature of {} C, above the limit of {} C", temperature, limit),
+        }
+    }
+}
+
+impl std::error::Error for BenchmarkError {}
+
+impl<Q> From<QueuePairError<Q>> for BenchmarkError {
+    fn from(error: QueuePairError<Q>) -> BenchmarkError {
+        BenchmarkError::QueuePair(error.message)
+    }
+}
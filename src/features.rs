@@ -1,119 +1,499 @@
+use serde::Serialize;
 use vroom::IdentifyControllerInfo;
+use crate::device::Namespace;
 
-fn ascii_to_string(bytes: &[u8]) -> String {
+pub fn ascii_to_string(bytes: &[u8]) -> String {
     String::from_utf8_lossy(bytes)
         .trim_end_matches('\0')
         .trim()
         .to_string()
 }
 
+/// Optional Admin Command Support (OACS), in the order of their bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminCommand {
+    SecuritySendReceive,
+    FormatNvm,
+    FirmwareDownload,
+    NamespaceManagement,
+    DeviceSelfTest,
+    Directives,
+    NvmeMi,
+    VirtualizationManagement,
+    DoorbellBufferConfig,
+    GetLbaStatus,
+    CommandFeatureLockdown,
+}
 
-pub fn print_identify_controller_info(info: &IdentifyControllerInfo) {
-    println!("NVMe Identify Controller Information:");
-    println!("===================================");
+impl AdminCommand {
+    const ALL: [AdminCommand; 11] = [
+        AdminCommand::SecuritySendReceive, AdminCommand::FormatNvm, AdminCommand::FirmwareDownload,
+        AdminCommand::NamespaceManagement, AdminCommand::DeviceSelfTest, AdminCommand::Directives,
+        AdminCommand::NvmeMi, AdminCommand::VirtualizationManagement, AdminCommand::DoorbellBufferConfig,
+        AdminCommand::GetLbaStatus, AdminCommand::CommandFeatureLockdown,
+    ];
 
-    // --- Controller Capabilities and Features ---
-    println!("\n--- Controller Capabilities and Features ---");
-    let vid = info.vid;
-    println!("PCI Vendor ID (VID)              : {:#06x}", vid);
-    let ssvid = info.ssvid;
-    println!("PCI Subsystem Vendor ID (SSVID)  : {:#06x}", ssvid);
-    println!("Serial Number (SN)               : {}", ascii_to_string(&info.serial_number));
-    println!("Model Number (MN)                : {}", ascii_to_string(&info.model_number));
-    println!("Firmware Revision (FR)           : {}", ascii_to_string(&info.firmware_revision));
-    if info.recommended_arbitration_burst > 0 {
-        println!("Recommended Arbitration Burst    : 2^{} commands", info.recommended_arbitration_burst);
-    }
-    println!("IEEE OUI Identifier              : {:02x}-{:02x}-{:02x}", info.ieee_oui_identifier[0], info.ieee_oui_identifier[1], info.ieee_oui_identifier[2]);
-    
-    // --- Controller Multi-Path I/O and Namespace Sharing ---
-    println!("\n--- Multi-Path I/O and Namespace Sharing ---");
-    if (info.controller_mpath_ns_sharing & (1 << 0)) != 0 {
-        println!("  - NVM subsystem has more than one port");
+    pub fn description(self) -> &'static str {
+        match self {
+            AdminCommand::SecuritySendReceive => "Security Send/Receive",
+            AdminCommand::FormatNvm => "Format NVM",
+            AdminCommand::FirmwareDownload => "Firmware Commit/Image Download",
+            AdminCommand::NamespaceManagement => "Namespace Management",
+            AdminCommand::DeviceSelfTest => "Device Self-test",
+            AdminCommand::Directives => "Directives",
+            AdminCommand::NvmeMi => "NVMe-MI Send/Receive",
+            AdminCommand::VirtualizationManagement => "Virtualization Management",
+            AdminCommand::DoorbellBufferConfig => "Doorbell Buffer Config",
+            AdminCommand::GetLbaStatus => "Get LBA Status",
+            AdminCommand::CommandFeatureLockdown => "Command and Feature Lockdown",
+        }
+    }
+}
+
+/// Optional NVM Command Support (ONCS), in the order of their bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NvmCommand {
+    Compare,
+    WriteUncorrectable,
+    DatasetManagement,
+    WriteZeroes,
+    SaveSelectFeatures,
+    Reservations,
+    Timestamp,
+    Verify,
+    Copy,
+}
+
+impl NvmCommand {
+    const ALL: [NvmCommand; 9] = [
+        NvmCommand::Compare, NvmCommand::WriteUncorrectable, NvmCommand::DatasetManagement,
+        NvmCommand::WriteZeroes, NvmCommand::SaveSelectFeatures, NvmCommand::Reservations,
+        NvmCommand::Timestamp, NvmCommand::Verify, NvmCommand::Copy,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            NvmCommand::Compare => "Compare",
+            NvmCommand::WriteUncorrectable => "Write Uncorrectable",
+            NvmCommand::DatasetManagement => "Dataset Management",
+            NvmCommand::WriteZeroes => "Write Zeroes",
+            NvmCommand::SaveSelectFeatures => "Save/Select fields in Get/Set Features",
+            NvmCommand::Reservations => "Reservations",
+            NvmCommand::Timestamp => "Timestamp feature",
+            NvmCommand::Verify => "Verify",
+            NvmCommand::Copy => "Copy",
+        }
+    }
+}
+
+/// Controller Multi-Path I/O and Namespace Sharing Capabilities (CMIC), in the order of their bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultiPath {
+    MultiplePorts,
+    MultipleControllers,
+    SrIovVirtualFunction,
+    AsymmetricNamespaceAccess,
+}
+
+impl MultiPath {
+    const ALL: [MultiPath; 4] = [MultiPath::MultiplePorts, MultiPath::MultipleControllers, MultiPath::SrIovVirtualFunction, MultiPath::AsymmetricNamespaceAccess];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            MultiPath::MultiplePorts => "NVM subsystem has more than one port",
+            MultiPath::MultipleControllers => "NVM subsystem has more than one controller",
+            MultiPath::SrIovVirtualFunction => "Associated with an SR-IOV Virtual Function",
+            MultiPath::AsymmetricNamespaceAccess => "Asymmetric Namespace Access Reporting supported",
+        }
+    }
+}
+
+/// The variants of all whose bit is set, bit i belongs to all[i]
+fn flags<T: Copy>(value: u16, all: &[T]) -> Vec<T> {
+    all.iter().enumerate().filter(|(bit, _)| value & (1 << bit) != 0).map(|(_, &flag)| flag).collect()
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u8,
+    pub tertiary: u8,
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.tertiary)
     }
-    if (info.controller_mpath_ns_sharing & (1 << 1)) != 0 {
-        println!("  - NVM subsystem has more than one controller");
+}
+
+/// Firmware Updates (FRMW)
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct FirmwareUpdates {
+    pub slots: u8,
+    pub slot1_read_only: bool,
+    pub activation_without_reset: bool,
+}
+
+/// Smallest and largest supported queue entry size in bytes
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct EntrySize {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl EntrySize {
+    fn from_field(field: u8) -> EntrySize {
+        EntrySize { min: 1 << (field & 0xf), max: 1 << (field >> 4) }
     }
-    if (info.controller_mpath_ns_sharing & (1 << 2)) != 0 {
-        println!("  - Associated with an SR-IOV Virtual Function");
+}
+
+/**
+ * Decoded Identify Controller data structure, so the benchmarks can check which commands the
+ * controller supports and how large a single command may get.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct ControllerCapabilities {
+    pub vendor_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub serial_number: String,
+    pub model_number: String,
+    pub firmware_revision: String,
+    pub ieee_oui: String,
+    pub controller_id: u16,
+    pub version: Version,
+    /// commands, None if the controller does not recommend a burst
+    pub arbitration_burst: Option<u32>,
+    /// MDTS in bytes, None if there is no limit
+    pub max_transfer_bytes: Option<u64>,
+    pub multi_path: Vec<MultiPath>,
+    pub admin_commands: Vec<AdminCommand>,
+    pub nvm_commands: Vec<NvmCommand>,
+    pub firmware: FirmwareUpdates,
+    pub abort_command_limit: u16,
+    pub async_event_request_limit: u16,
+    pub submission_queue_entry: EntrySize,
+    pub completion_queue_entry: EntrySize,
+    pub num_namespaces: u32,
+    pub volatile_write_cache: bool,
+    pub subnqn: String,
+}
+
+impl ControllerCapabilities {
+    /**
+     * @param min_page_size CAP.MPSMIN of the controller in bytes, the unit of MDTS
+     */
+    pub fn new(info: &IdentifyControllerInfo, min_page_size: u64) -> ControllerCapabilities {
+        let version = info.version;
+        let oui = info.ieee_oui_identifier;
+        ControllerCapabilities {
+            vendor_id: info.vid,
+            subsystem_vendor_id: info.ssvid,
+            serial_number: ascii_to_string(&info.serial_number),
+            model_number: ascii_to_string(&info.model_number),
+            firmware_revision: ascii_to_string(&info.firmware_revision),
+            ieee_oui: format!("{:02x}-{:02x}-{:02x}", oui[0], oui[1], oui[2]),
+            controller_id: info.controller_id,
+            version: Version { major: (version >> 16) as u16, minor: (version >> 8) as u8, tertiary: version as u8 },
+            arbitration_burst: (info.recommended_arbitration_burst > 0).then(|| 1 << info.recommended_arbitration_burst.min(31)),
+            max_transfer_bytes: (info.max_data_transfer_size > 0).then(|| min_page_size << info.max_data_transfer_size),
+            multi_path: flags(info.controller_mpath_ns_sharing as u16, &MultiPath::ALL),
+            admin_commands: flags(info.optional_admin_command_support, &AdminCommand::ALL),
+            nvm_commands: flags(info.optional_nvm_cmd_support, &NvmCommand::ALL),
+            firmware: FirmwareUpdates {
+                slots: (info.firmware_updates >> 1) & 0b111,
+                slot1_read_only: info.firmware_updates & 1 != 0,
+                activation_without_reset: info.firmware_updates & (1 << 4) != 0,
+            },
+            abort_command_limit: info.abort_command_limit as u16 + 1,
+            async_event_request_limit: info.asynchronous_event_request_limit as u16 + 1,
+            submission_queue_entry: EntrySize::from_field(info.submission_queue_entry_size),
+            completion_queue_entry: EntrySize::from_field(info.completion_queue_entry_size),
+            num_namespaces: info.num_namespaces,
+            volatile_write_cache: info.volatile_write_cache & 1 != 0,
+            subnqn: ascii_to_string(&info.subnqn),
+        }
     }
-     if (info.controller_mpath_ns_sharing & (1 << 3)) != 0 {
-        println!("  - Asymmetric Namespace Access Reporting supported");
+
+    /**
+     * Largest request of at most io_size bytes that fits into a single command, in whole blocks
+     * @returns io_size if the controller has no transfer limit or the request already fits
+     */
+    pub fn clamp_io_size(&self, io_size: u64, block_size: u64) -> u64 {
+        match self.max_transfer_bytes {
+            Some(max) if io_size > max => (max - max % block_size).max(block_size),
+            _ => io_size,
+        }
+    }
+
+    pub fn supports(&self, command: NvmCommand) -> bool {
+        self.nvm_commands.contains(&command)
     }
+}
 
+/// Relative performance of an LBA format compared to the other formats of the namespace
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelativePerformance {
+    Best,
+    Better,
+    Good,
+    Degraded,
+}
 
-    // --- Data Transfer and Versioning ---
-    println!("\n--- Data Transfer and Versioning ---");
-    if info.max_data_transfer_size == 0 {
-        println!("Max Data Transfer Size (MDTS)    : No limit");
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct LbaFormat {
+    pub index: u8,
+    pub data_size: u64,
+    /// metadata bytes per block
+    pub metadata_size: u16,
+    pub relative_performance: RelativePerformance,
+}
+
+/// End-to-end data protection the namespace supports (DPC) and has enabled (DPS)
+#[derive(Serialize, Clone, Debug)]
+pub struct ProtectionInfo {
+    /// supported protection information types out of 1, 2 and 3
+    pub supported_types: Vec<u8>,
+    pub first_bytes_of_metadata: bool,
+    pub last_bytes_of_metadata: bool,
+    /// enabled protection information type, None if disabled
+    pub enabled_type: Option<u8>,
+    /// enabled protection information is transferred in the first bytes of the metadata
+    pub enabled_first_bytes: bool,
+}
+
+/**
+ * Preferred sizes and alignments in logical blocks (NSFEAT.OPTPERF), writes and deallocations that
+ * violate them may be slower or wear the device more
+ */
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct PreferredGranularity {
+    /// preferred write granularity (NPWG)
+    pub write_granularity: u32,
+    /// preferred write alignment (NPWA)
+    pub write_alignment: u32,
+    /// preferred deallocate granularity (NPDG)
+    pub deallocate_granularity: u32,
+    /// preferred deallocate alignment (NPDA)
+    pub deallocate_alignment: u32,
+    /// optimal write size (NOWS)
+    pub optimal_write_size: u32,
+}
+
+/**
+ * Decoded Identify Namespace data structure (CNS 00h)
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct NamespaceIdentity {
+    pub id: u32,
+    /// namespace size, capacity and utilization in logical blocks (NSZE, NCAP, NUSE)
+    pub size: u64,
+    pub capacity: u64,
+    pub utilization: u64,
+    pub thin_provisioning: bool,
+    pub lba_formats: Vec<LbaFormat>,
+    pub active_format: u8,
+    /// metadata is transferred at the end of each block instead of in a separate buffer
+    pub extended_metadata: bool,
+    pub protection: ProtectionInfo,
+    /// None if the namespace does not report them
+    pub preferred: Option<PreferredGranularity>,
+}
+
+/// Size of the Identify data structures
+pub const IDENTIFY_SIZE: usize = 4096;
+/// Feature identifier of the Volatile Write Cache, bit 0 of its value enables the cache
+pub const VOLATILE_WRITE_CACHE: u8 = 0x06;
+
+impl NamespaceIdentity {
+    /// Decodes the 4096 bytes the controller returns for Identify Namespace
+    pub fn parse(id: u32, data: &[u8]) -> NamespaceIdentity {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        let nsfeat = data[24];
+        let flbas = data[26];
+        let (dpc, dps) = (data[28], data[29]);
+        let lba_formats = (0..=data[25].min(63)).map(|index| {
+            let lbaf = u32_at(128 + 4 * index as usize);
+            LbaFormat {
+                index,
+                data_size: 1 << ((lbaf >> 16) & 0xff).min(63),
+                metadata_size: lbaf as u16,
+                relative_performance: match (lbaf >> 24) & 0b11 {
+                    0 => RelativePerformance::Best,
+                    1 => RelativePerformance::Better,
+                    2 => RelativePerformance::Good,
+                    _ => RelativePerformance::Degraded,
+                },
+            }
+        }).collect();
+
+        // the granularities are 0's based
+        let preferred = (nsfeat & (1 << 4) != 0).then(|| PreferredGranularity {
+            write_granularity: u16_at(64) as u32 + 1,
+            write_alignment: u16_at(66) as u32 + 1,
+            deallocate_granularity: u16_at(68) as u32 + 1,
+            deallocate_alignment: u16_at(70) as u32 + 1,
+            optimal_write_size: u16_at(72) as u32 + 1,
+        });
+
+        NamespaceIdentity {
+            id,
+            size: u64_at(0),
+            capacity: u64_at(8),
+            utilization: u64_at(16),
+            thin_provisioning: nsfeat & 1 != 0,
+            lba_formats,
+            active_format: (flbas & 0xf) | ((flbas >> 5 & 0b11) << 4),
+            extended_metadata: flbas & (1 << 4) != 0,
+            protection: ProtectionInfo {
+                supported_types: (1..=3).filter(|t| dpc & (1 << (t - 1)) != 0).collect(),
+                first_bytes_of_metadata: dpc & (1 << 3) != 0,
+                last_bytes_of_metadata: dpc & (1 << 4) != 0,
+                enabled_type: (dps & 0b111 != 0).then_some(dps & 0b111),
+                enabled_first_bytes: dps & (1 << 3) != 0,
+            },
+            preferred,
+        }
+    }
+
+    /**
+     * Checks requests of io_blocks blocks at LBAs lba_start + k * io_blocks against the preferred
+     * write granularity, alignment and optimal write size
+     * @returns a message for every preference the writes violate
+     */
+    pub fn write_violations(&self, lba_start: u64, io_blocks: u64) -> Vec<String> {
+        let Some(preferred) = self.preferred else {
+            return Vec::new();
+        };
+        let mut violations = Vec::new();
+        let (granularity, alignment, optimal) = (preferred.write_granularity as u64, preferred.write_alignment as u64, preferred.optimal_write_size as u64);
+        if io_blocks % granularity != 0 {
+            violations.push(format!("write size of {} blocks is not a multiple of the preferred write granularity of {} blocks", io_blocks, granularity));
+        }
+        if lba_start % alignment != 0 || io_blocks % alignment != 0 {
+            violations.push(format!("writes are not aligned to the preferred write alignment of {} blocks", alignment));
+        }
+        if io_blocks % optimal != 0 {
+            violations.push(format!("write size of {} blocks is not a multiple of the optimal write size of {} blocks", io_blocks, optimal));
+        }
+        violations
+    }
+}
+
+pub fn print_namespace_identity(ns: &NamespaceIdentity) {
+    println!("NVMe Identify Namespace {}:", ns.id);
+    println!("===================================");
+    println!("Size (NSZE)                      : {} blocks", ns.size);
+    println!("Capacity (NCAP)                  : {} blocks", ns.capacity);
+    println!("Utilization (NUSE)               : {} blocks", ns.utilization);
+    if ns.thin_provisioning {
+        println!("  - Thin provisioning supported");
+    }
+
+    println!("\n--- LBA Formats ---");
+    for format in &ns.lba_formats {
+        let active = if format.index == ns.active_format { " (active)" } else { "" };
+        println!("  LBAF {:>2}: {:>6} bytes data, {:>3} bytes metadata, {:?} performance{}",
+            format.index, format.data_size, format.metadata_size, format.relative_performance, active);
+    }
+    if ns.extended_metadata {
+        println!("  - Metadata is transferred at the end of the data");
+    }
+
+    println!("\n--- End-to-end Data Protection ---");
+    let p = &ns.protection;
+    if p.supported_types.is_empty() {
+        println!("  - Not supported");
     } else {
-        // The value is 2^n * CAP.MPSMIN. We'll just show the 2^n part.
-        println!("Max Data Transfer Size (MDTS)    : 2^{} * (Min Page Size)", info.max_data_transfer_size);
+        let types: Vec<_> = p.supported_types.iter().map(|t| t.to_string()).collect();
+        println!("Supported Types                  : {}", types.join(", "));
+        if p.first_bytes_of_metadata { println!("  - Protection information in the first bytes of metadata"); }
+        if p.last_bytes_of_metadata { println!("  - Protection information in the last bytes of metadata"); }
+        match p.enabled_type {
+            Some(t) => println!("Enabled Type                     : {}{}", t, if p.enabled_first_bytes { ", first bytes of metadata" } else { "" }),
+            None => println!("Enabled Type                     : none"),
+        }
     }
-    let controller_id = info.controller_id;
-    println!("Controller ID (CNTLID)           : {:#06x}", controller_id);
-    let major = (info.version >> 16) & 0xFFFF;
-    let minor = (info.version >> 8) & 0xFF;
-    let tertiary = info.version & 0xFF;
-    println!("Version (VER)                    : {}.{}.{}", major, minor, tertiary);
 
+    println!("\n--- Preferred Granularities ---");
+    match &ns.preferred {
+        Some(g) => {
+            println!("Write Granularity (NPWG)         : {} blocks", g.write_granularity);
+            println!("Write Alignment (NPWA)           : {} blocks", g.write_alignment);
+            println!("Deallocate Granularity (NPDG)    : {} blocks", g.deallocate_granularity);
+            println!("Deallocate Alignment (NPDA)      : {} blocks", g.deallocate_alignment);
+            println!("Optimal Write Size (NOWS)        : {} blocks", g.optimal_write_size);
+        }
+        None => println!("  - Not reported"),
+    }
+}
+
+pub fn print_capabilities(caps: &ControllerCapabilities) {
+    println!("NVMe Identify Controller Information:");
+    println!("===================================");
+
+    println!("\n--- Controller Capabilities and Features ---");
+    println!("PCI Vendor ID (VID)              : {:#06x}", caps.vendor_id);
+    println!("PCI Subsystem Vendor ID (SSVID)  : {:#06x}", caps.subsystem_vendor_id);
+    println!("Serial Number (SN)               : {}", caps.serial_number);
+    println!("Model Number (MN)                : {}", caps.model_number);
+    println!("Firmware Revision (FR)           : {}", caps.firmware_revision);
+    if let Some(burst) = caps.arbitration_burst {
+        println!("Recommended Arbitration Burst    : {} commands", burst);
+    }
+    println!("IEEE OUI Identifier              : {}", caps.ieee_oui);
+
+    println!("\n--- Multi-Path I/O and Namespace Sharing ---");
+    caps.multi_path.iter().for_each(|m| println!("  - {}", m.description()));
+
+    println!("\n--- Data Transfer and Versioning ---");
+    match caps.max_transfer_bytes {
+        Some(bytes) => println!("Max Data Transfer Size (MDTS)    : {} bytes", bytes),
+        None => println!("Max Data Transfer Size (MDTS)    : No limit"),
+    }
+    println!("Controller ID (CNTLID)           : {:#06x}", caps.controller_id);
+    println!("Version (VER)                    : {}", caps.version);
 
-    // --- Optional Admin Command Support (OACS) ---
     println!("\n--- Optional Admin Command Support (OACS) ---");
-    let oacs = info.optional_admin_command_support;
-    if (oacs & (1 << 0)) != 0 { println!("  - Security Send/Receive supported"); }
-    if (oacs & (1 << 1)) != 0 { println!("  - Format NVM supported"); }
-    if (oacs & (1 << 2)) != 0 { println!("  - Firmware Commit/Image Download supported"); }
-    if (oacs & (1 << 3)) != 0 { println!("  - Namespace Management supported"); }
-    if (oacs & (1 << 4)) != 0 { println!("  - Device Self-test supported"); }
-    if (oacs & (1 << 5)) != 0 { println!("  - Directives supported"); }
-    if (oacs & (1 << 6)) != 0 { println!("  - NVMe-MI Send/Receive supported"); }
-    if (oacs & (1 << 7)) != 0 { println!("  - Virtualization Management supported"); }
-    if (oacs & (1 << 8)) != 0 { println!("  - Doorbell Buffer Config supported"); }
-    if (oacs & (1 << 9)) != 0 { println!("  - Get LBA Status supported"); }
-    if (oacs & (1 << 10)) != 0 { println!("  - Command and Feature Lockdown supported"); }
-
-
-    // --- Firmware ---
+    caps.admin_commands.iter().for_each(|c| println!("  - {} supported", c.description()));
+
     println!("\n--- Firmware ---");
-    let fw = info.firmware_updates;
-    println!("Firmware Slots                   : {}", (fw >> 1) & 0b111);
-    if (fw & (1 << 0)) != 0 { println!("  - Slot 1 is Read-Only"); }
-    if (fw & (1 << 4)) != 0 { println!("  - Activation without reset supported"); }
+    println!("Firmware Slots                   : {}", caps.firmware.slots);
+    if caps.firmware.slot1_read_only { println!("  - Slot 1 is Read-Only"); }
+    if caps.firmware.activation_without_reset { println!("  - Activation without reset supported"); }
 
-    // --- Queue Information ---
     println!("\n--- Queue Information ---");
-    println!("Abort Command Limit              : {}", info.abort_command_limit + 1);
-    println!("Async Event Request Limit        : {}", info.asynchronous_event_request_limit + 1);
-    println!("Submission Queue Entry Size (SQES) : Min: 2^{}, Max: 2^{} bytes", info.submission_queue_entry_size & 0xF, (info.submission_queue_entry_size >> 4) & 0xF);
-    println!("Completion Queue Entry Size (CQES) : Min: 2^{}, Max: 2^{} bytes", info.completion_queue_entry_size & 0xF, (info.completion_queue_entry_size >> 4) & 0xF);
-    let num_namespaces = info.num_namespaces;
-    println!("Number of Namespaces (NN)        : {}", num_namespaces);
+    println!("Abort Command Limit              : {}", caps.abort_command_limit);
+    println!("Async Event Request Limit        : {}", caps.async_event_request_limit);
+    let (sq, cq) = (caps.submission_queue_entry, caps.completion_queue_entry);
+    println!("Submission Queue Entry Size (SQES) : Min: {}, Max: {} bytes", sq.min, sq.max);
+    println!("Completion Queue Entry Size (CQES) : Min: {}, Max: {} bytes", cq.min, cq.max);
+    println!("Number of Namespaces (NN)        : {}", caps.num_namespaces);
 
-
-    // --- NVM Command Set Attributes ---
     println!("\n--- Optional NVM Command Support (ONCS) ---");
-    let oncs = info.optional_nvm_cmd_support;
-    if (oncs & (1 << 0)) != 0 { println!("  - Compare supported"); }
-    if (oncs & (1 << 1)) != 0 { println!("  - Write Uncorrectable supported"); }
-    if (oncs & (1 << 2)) != 0 { println!("  - Dataset Management supported"); }
-    if (oncs & (1 << 3)) != 0 { println!("  - Write Zeroes supported"); }
-    if (oncs & (1 << 4)) != 0 { println!("  - Save/Select fields in Get/Set Features supported"); }
-    if (oncs & (1 << 5)) != 0 { println!("  - Reservations supported"); }
-    if (oncs & (1 << 6)) != 0 { println!("  - Timestamp feature supported"); }
-    if (oncs & (1 << 7)) != 0 { println!("  - Verify command supported"); }
-    if (oncs & (1 << 8)) != 0 { println!("  - Copy command supported"); }
-
-
-    // --- Volatile Write Cache (VWC) ---
+    caps.nvm_commands.iter().for_each(|c| println!("  - {} supported", c.description()));
+
     println!("\n--- Volatile Write Cache (VWC) ---");
-    if (info.volatile_write_cache & 1) != 0 {
+    if caps.volatile_write_cache {
         println!("  - Volatile Write Cache is present");
     } else {
         println!("  - No Volatile Write Cache");
     }
-    
-    // --- NVM Subsystem ---
+
     println!("\n--- NVM Subsystem ---");
-    println!("NVM Subsystem Qualified Name (SUBNQN): {}", ascii_to_string(&info.subnqn));
-}
\ No newline at end of file
+    println!("NVM Subsystem Qualified Name (SUBNQN): {}", caps.subnqn);
+}
+
+pub fn print_namespaces(namespaces: &[Namespace]) {
+    println!("{:>6} {:>16} {:>10} {:>14}", "NSID", "Blocks", "Block size", "Capacity");
+    for ns in namespaces {
+        let capacity = ns.blocks * ns.block_size;
+        println!("{:>6} {:>16} {:>10} {:>10.2} GiB", ns.id, ns.blocks, ns.block_size, capacity as f64 / (1u64 << 30) as f64);
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+
+use crate::device::NvmeBackend;
+
+/// Log identifier of the SMART / Health Information log
+pub const SMART_LOG_ID: u8 = 0x02;
+pub const SMART_LOG_SIZE: usize = 512;
+/// SMART / Health Information Extended log of the OCP Datacenter NVMe SSD specification
+pub const OCP_SMART_LOG_ID: u8 = 0xc0;
+pub const OCP_SMART_LOG_SIZE: usize = 512;
+/// Log page GUID of the OCP log, other vendor specific logs use the same identifier
+pub const OCP_SMART_LOG_GUID: u128 = 0xafd5_14c9_7c6f_4f9c_a4f2_bfea_2810_afc5;
+/// The data unit counters count thousands of 512 byte units, rounded up
+const DATA_UNIT: u64 = 512 * 1000;
+const GIB: f64 = (1u64 << 30) as f64;
+
+/**
+ * The SMART / Health Information log of the controller. Temperatures are converted from Kelvin,
+ * sensors the controller does not implement are None.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct SmartLog {
+    pub critical_warning: u8,
+    pub composite_temperature_c: i32,
+    /// percent of the spare capacity that is left
+    pub available_spare: u8,
+    pub available_spare_threshold: u8,
+    /// estimate of the used life in percent, may exceed 100
+    pub percentage_used: u8,
+    pub data_units_read: u64,
+    pub data_units_written: u64,
+    pub host_read_commands: u64,
+    pub host_write_commands: u64,
+    pub controller_busy_minutes: u64,
+    pub power_cycles: u64,
+    pub power_on_hours: u64,
+    pub unsafe_shutdowns: u64,
+    pub media_errors: u64,
+    pub error_log_entries: u64,
+    pub warning_temperature_minutes: u32,
+    pub critical_temperature_minutes: u32,
+    pub temperature_sensors_c: [Option<i32>; 8],
+    /// how often the controller entered thermal management temperature 1 (light) and 2 (heavy throttling)
+    pub thermal_transitions: [u32; 2],
+    /// seconds spent in thermal management temperature 1 and 2
+    pub thermal_time_s: [u32; 2],
+}
+
+fn kelvin_to_celsius(kelvin: u16) -> i32 {
+    kelvin as i32 - 273
+}
+
+impl SmartLog {
+    pub fn parse(data: &[u8]) -> SmartLog {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        // 128 bit counters, saturated to what a report can hold
+        let counter_at = |offset: usize| u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap()).min(u64::MAX as u128) as u64;
+
+        SmartLog {
+            critical_warning: data[0],
+            composite_temperature_c: kelvin_to_celsius(u16_at(1)),
+            available_spare: data[3],
+            available_spare_threshold: data[4],
+            percentage_used: data[5],
+            data_units_read: counter_at(32),
+            data_units_written: counter_at(48),
+            host_read_commands: counter_at(64),
+            host_write_commands: counter_at(80),
+            controller_busy_minutes: counter_at(96),
+            power_cycles: counter_at(112),
+            power_on_hours: counter_at(128),
+            unsafe_shutdowns: counter_at(144),
+            media_errors: counter_at(160),
+            error_log_entries: counter_at(176),
+            warning_temperature_minutes: u32_at(192),
+            critical_temperature_minutes: u32_at(196),
+            temperature_sensors_c: std::array::from_fn(|i| Some(u16_at(200 + 2 * i)).filter(|&k| k != 0).map(kelvin_to_celsius)),
+            thermal_transitions: [u32_at(216), u32_at(220)],
+            thermal_time_s: [u32_at(224), u32_at(228)],
+        }
+    }
+
+    /// Highest reading of the composite temperature and all implemented sensors
+    pub fn max_temperature_c(&self) -> i32 {
+        self.temperature_sensors_c.iter().flatten().fold(self.composite_temperature_c, |max, &t| max.max(t))
+    }
+}
+
+/// Bytes the controller physically wrote to and read from the media, from the OCP extended log
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct MediaCounters {
+    pub media_bytes_written: u64,
+    pub media_bytes_read: u64,
+}
+
+impl MediaCounters {
+    /// None if the log is not the OCP one
+    pub fn parse(data: &[u8]) -> Option<MediaCounters> {
+        let u128_at = |offset: usize| u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap());
+        (u128_at(496) == OCP_SMART_LOG_GUID).then(|| MediaCounters {
+            media_bytes_written: u128_at(0).min(u64::MAX as u128) as u64,
+            media_bytes_read: u128_at(16).min(u64::MAX as u128) as u64,
+        })
+    }
+}
+
+/// The logs a health delta is computed from, media is None if the controller does not expose media counters
+#[derive(Clone, Debug)]
+pub struct HealthSnapshot {
+    pub smart: SmartLog,
+    pub media: Option<MediaCounters>,
+}
+
+impl HealthSnapshot {
+    /// None if the backend cannot read the SMART / Health log
+    pub fn read<D: NvmeBackend>(nvme: &mut D) -> Option<HealthSnapshot> {
+        let smart = nvme.smart_log().ok()?;
+        let mut data = [0u8; OCP_SMART_LOG_SIZE];
+        let media = nvme.get_log_page(OCP_SMART_LOG_ID, &mut data).ok().and_then(|()| MediaCounters::parse(&data));
+        Some(HealthSnapshot { smart, media })
+    }
+
+    pub fn delta(before: Option<HealthSnapshot>, after: Option<HealthSnapshot>) -> Option<HealthDelta> {
+        before.zip(after).map(|(before, after)| HealthDelta::between(&before, &after))
+    }
+}
+
+/**
+ * What changed on the device while a job ran, from the SMART / Health logs read before and after.
+ * Host bytes have the 512000 byte resolution of the data unit counters. The write amplification
+ * compares them with the media writes, if the controller reports those.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthDelta {
+    pub temperature_before_c: i32,
+    pub temperature_after_c: i32,
+    pub max_temperature_after_c: i32,
+    /// transitions into thermal management temperature 1 or 2
+    pub thermal_throttle_events: u64,
+    pub thermal_throttle_s: u64,
+    pub warning_temperature_minutes: u64,
+    pub critical_temperature_minutes: u64,
+    pub host_bytes_read: u64,
+    pub host_bytes_written: u64,
+    /// None if the controller does not expose media counters
+    pub media_bytes_written: Option<u64>,
+    /// media bytes written per host byte written
+    pub write_amplification: Option<f64>,
+    pub host_read_commands: u64,
+    pub host_write_commands: u64,
+    pub media_errors: u64,
+    pub error_log_entries: u64,
+    /// state after the job
+    pub critical_warning: u8,
+    pub percentage_used: u8,
+    pub available_spare: u8,
+}
+
+impl HealthDelta {
+    pub fn between(before: &HealthSnapshot, after: &HealthSnapshot) -> HealthDelta {
+        let sum = |values: [u32; 2]| values.iter().map(|&v| v as u64).sum::<u64>();
+        let media_bytes_written = before.media.zip(after.media).map(|(b, a)| a.media_bytes_written.saturating_sub(b.media_bytes_written));
+        let (before, after) = (&before.smart, &after.smart);
+        let host_bytes_written = after.data_units_written.saturating_sub(before.data_units_written) * DATA_UNIT;
+        HealthDelta {
+            temperature_before_c: before.composite_temperature_c,
+            temperature_after_c: after.composite_temperature_c,
+            max_temperature_after_c: after.max_temperature_c(),
+            thermal_throttle_events: sum(after.thermal_transitions).saturating_sub(sum(before.thermal_transitions)),
+            thermal_throttle_s: sum(after.thermal_time_s).saturating_sub(sum(before.thermal_time_s)),
+            warning_temperature_minutes: after.warning_temperature_minutes.saturating_sub(before.warning_temperature_minutes) as u64,
+            critical_temperature_minutes: after.critical_temperature_minutes.saturating_sub(before.critical_temperature_minutes) as u64,
+            host_bytes_read: after.data_units_read.saturating_sub(before.data_units_read) * DATA_UNIT,
+            host_bytes_written,
+            media_bytes_written,
+            write_amplification: media_bytes_written.filter(|_| host_bytes_written > 0).map(|media| media as f64 / host_bytes_written as f64),
+            host_read_commands: after.host_read_commands.saturating_sub(before.host_read_commands),
+            host_write_commands: after.host_write_commands.saturating_sub(before.host_write_commands),
+            media_errors: after.media_errors.saturating_sub(before.media_errors),
+            error_log_entries: after.error_log_entries.saturating_sub(before.error_log_entries),
+            critical_warning: after.critical_warning,
+            percentage_used: after.percentage_used,
+            available_spare: after.available_spare,
+        }
+    }
+
+    /// One line summary, e.g. "3 thermal throttle events (42 s), +64.00 GiB host writes, ..."
+    pub fn describe(&self) -> String {
+        let mut parts = vec![
+            format!("{} -> {} C", self.temperature_before_c, self.temperature_after_c),
+            format!("{} thermal throttle events ({} s)", self.thermal_throttle_events, self.thermal_throttle_s),
+            format!("+{:.2} GiB host writes", self.host_bytes_written as f64 / GIB),
+            format!("+{:.2} GiB host reads", self.host_bytes_read as f64 / GIB),
+        ];
+        if let Some(waf) = self.write_amplification {
+            parts.push(format!("WAF {:.2}", waf));
+        }
+        if self.media_errors > 0 {
+            parts.push(format!("{} media errors", self.media_errors));
+        }
+        if self.critical_warning != 0 {
+            parts.push(format!("critical warning {:#04x}", self.critical_warning));
+        }
+        parts.push(format!("{}% used", self.percentage_used));
+        parts.join(", ")
+    }
+}
@@ -0,0 +1,781 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use toml::Spanned;
+use vroom::HUGE_PAGE_SIZE;
+
+use crate::cli::{parse_duration, parse_size, Arrivals, ErrorPolicy, Op, RangeOp};
+use crate::device::{LbaWindow, Namespace};
+use crate::safety::LbaWindowSpec;
+use crate::verify::VerifyMode;
+use crate::util::{AccessPattern, Limit, Rate, RateLimit, Workload, LOG_INTERVAL};
+
+/*
+ * Job files describe workloads in TOML, e.g.
+ *
+ *   [[job]]
+ *   name = "fill"
+ *   ns = 1
+ *   pattern = "sequential"
+ *   op = "write"
+ *   bytes = "64G"
+ *
+ *   [sweep]
+ *   io_size = ["8K", "1M"]
+ *   queue_depth = [1, 32, 128]
+ *   threads = [1, 8, 32]
+ *
+ * Every job is expanded into the cartesian product of the sweep lists. A value set in the job
+ * itself takes precedence over the sweep for that dimension.
+ *
+ * warmup and ramp_down (e.g. "10s") keep IOs running before and after the measured window of a
+ * job, their IOs are not part of the results.
+ *
+ * rate_iops or rate_bandwidth (e.g. "500M" per second) make a job open-loop: requests are issued
+ * at that rate with constant or poisson (the default) arrivals, queue_depth only caps the requests
+ * in flight, and latencies are measured from the intended issue time.
+ *
+ * verify = "inline" or "after" writes self-describing blocks and checks them on reads of the job or
+ * in a read-back pass after it. It needs writes and one io size for reads and writes.
+ *
+ * range_op = "deallocate" or "write-zeroes" issues the writes of a job as Dataset Management
+ * (deallocate) or Write Zeroes commands on the io_size bytes they would have written. fua = true
+ * submits the writes with Force Unit Access instead. vroom can issue neither.
+ *
+ * on_error = "stop" ends a job at the first command that completes with an error status and skips
+ * the jobs after it, "continue" only reports the failed commands. It defaults to --on-error, stop
+ * is not supported on vroom.
+ *
+ * ns defaults to the first active namespace of the device and the LBA range to the allowed
+ * window (--lba-window), an explicit lba_start / lba_count has to lie inside it. Jobs sharing a group (e.g.
+ * group = "mixed") start together and run concurrently, which needs each of them to expand to a
+ * single sweep point with the same repeat count.
+ */
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    #[serde(default)]
+    job: Vec<Spanned<JobSpec>>,
+    #[serde(default)]
+    sweep: SweepSpec,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobSpec {
+    name: Option<String>,
+    ns: Option<Spanned<u32>>,
+    lba_start: Option<Spanned<u64>>,
+    lba_count: Option<Spanned<u64>>,
+    io_size: Option<Spanned<Size>>,
+    queue_depth: Option<Spanned<u64>>,
+    threads: Option<Spanned<u64>>,
+    op: Option<Spanned<Op>>,
+    /// mixed workload, e.g. 70 for 70% reads and 30% writes
+    read_percent: Option<Spanned<f64>>,
+    pattern: Option<Spanned<Pattern>>,
+    zipf_s: Option<Spanned<f64>>,
+    /// override io_size and pattern for the writes of a mixed workload
+    write_io_size: Option<Spanned<Size>>,
+    write_pattern: Option<Spanned<Pattern>>,
+    write_zipf_s: Option<Spanned<f64>>,
+    bytes: Option<Spanned<Size>>,
+    duration: Option<Spanned<RunTime>>,
+    warmup: Option<Spanned<RunTime>>,
+    ramp_down: Option<Spanned<RunTime>>,
+    /// open-loop runs issue requests at this total rate instead of keeping the queues full
+    rate_iops: Option<Spanned<f64>>,
+    /// bytes per second, e.g. "500M"
+    rate_bandwidth: Option<Spanned<Size>>,
+    arrivals: Option<Spanned<Arrivals>>,
+    /// "inline" checks reads against earlier writes, "after" reads every written block back
+    verify: Option<Spanned<VerifyMode>>,
+    /// "deallocate" or "write-zeroes" issues the writes of the job as that command
+    range_op: Option<Spanned<RangeOp>>,
+    /// true submits the writes with Force Unit Access
+    fua: Option<Spanned<bool>>,
+    /// "stop" ends the job at its first failed command and skips the remaining jobs
+    on_error: Option<Spanned<ErrorPolicy>>,
+    /// jobs with the same group run at the same time, e.g. against different namespaces
+    group: Option<Spanned<String>>,
+    repeat: Option<Spanned<u64>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SweepSpec {
+    #[serde(default)]
+    io_size: Vec<Spanned<Size>>,
+    #[serde(default)]
+    queue_depth: Vec<Spanned<u64>>,
+    #[serde(default)]
+    threads: Vec<Spanned<u64>>,
+    #[serde(default)]
+    op: Vec<Spanned<Op>>,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Pattern {
+    Sequential,
+    Random,
+    Zipf,
+}
+
+/// Integer or string with binary suffix, e.g. 4096 or "4K"
+#[derive(Copy, Clone, Debug)]
+struct Size(u64);
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SizeVisitor;
+
+        impl<'de> Visitor<'de> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a size like 4096 or \"4K\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Size, E> {
+                u64::try_from(v).map(Size).map_err(|_| E::custom("size must not be negative"))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Size, E> {
+                Ok(Size(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Size, E> {
+                parse_size(v).map(Size).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+/// Seconds as integer or a string like "30s" / "500ms"
+#[derive(Copy, Clone, Debug)]
+struct RunTime(Duration);
+
+impl<'de> Deserialize<'de> for RunTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RunTimeVisitor;
+
+        impl<'de> Visitor<'de> for RunTimeVisitor {
+            type Value = RunTime;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a duration like 30 or \"30s\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<RunTime, E> {
+                u64::try_from(v).map(|s| RunTime(Duration::from_secs(s))).map_err(|_| E::custom("duration must not be negative"))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<RunTime, E> {
+                Ok(RunTime(Duration::from_secs(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<RunTime, E> {
+                parse_duration(v).map(RunTime).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(RunTimeVisitor)
+    }
+}
+
+/// Source locations of the values a planned job was built from
+#[derive(Clone, Debug)]
+struct JobSpans {
+    ns: Range<usize>,
+    lba: Range<usize>,
+    io_size: Range<usize>,
+    write_io_size: Range<usize>,
+    queue_depth: Range<usize>,
+    threads: Range<usize>,
+    limit: Range<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedJob {
+    pub name: String,
+    pub workload: Workload,
+    pub repeat: u64,
+    pub group: Option<String>,
+    /// error policy set in the job file, otherwise the one of --on-error
+    pub on_error: Option<ErrorPolicy>,
+    /// namespace set in the job file, otherwise the workload gets the first active one
+    ns: Option<u32>,
+    /// start of the LBA range set in the job file, otherwise the start of the allowed window
+    lba_start: Option<u64>,
+    spans: JobSpans,
+}
+
+pub struct JobPlan {
+    pub jobs: Vec<PlannedJob>,
+    source: SourceMap,
+}
+
+#[derive(Debug)]
+pub struct JobFileError {
+    messages: Vec<String>,
+}
+
+impl JobFileError {
+    /// Sweep points built from the same source value report the same error only once
+    fn new(mut messages: Vec<String>) -> JobFileError {
+        let mut seen = HashSet::new();
+        messages.retain(|m| seen.insert(m.clone()));
+        JobFileError { messages }
+    }
+}
+
+impl Error for JobFileError {}
+impl fmt::Display for JobFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.messages.join("\n"))
+    }
+}
+
+struct SourceMap {
+    path: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceMap {
+    fn new(path: &Path, text: &str) -> SourceMap {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        SourceMap { path: path.display().to_string(), line_starts }
+    }
+
+    fn error(&self, span: &Range<usize>, message: impl fmt::Display) -> String {
+        let line = self.line_starts.partition_point(|&start| start <= span.start);
+        let column = span.start - self.line_starts[line.max(1) - 1] + 1;
+        format!("{}:{}:{}: {}", self.path, line.max(1), column, message)
+    }
+}
+
+/**
+ * @param prefix of the key names in error messages, "" or "write_"
+ * @returns None if no pattern is given
+ */
+fn resolve_pattern(pattern: &Option<Spanned<Pattern>>, zipf_s: &Option<Spanned<f64>>, prefix: &str) -> Result<Option<AccessPattern>, (Range<usize>, String)> {
+    match (pattern.as_ref().map(|p| *p.get_ref()), zipf_s) {
+        (Some(Pattern::Zipf), Some(s)) if *s.get_ref() >= 0.0 => Ok(Some(AccessPattern::Zipf(*s.get_ref()))),
+        (Some(Pattern::Zipf), Some(s)) => Err((s.span(), format!("{}zipf_s must not be negative", prefix))),
+        (Some(Pattern::Zipf), None) => Err((pattern.as_ref().unwrap().span(), format!("{}pattern zipf requires {}zipf_s", prefix, prefix))),
+        (_, Some(s)) => Err((s.span(), format!("{}zipf_s is only valid with {}pattern = \"zipf\"", prefix, prefix))),
+        (Some(Pattern::Random), None) => Ok(Some(AccessPattern::Random)),
+        (Some(Pattern::Sequential), None) => Ok(Some(AccessPattern::Sequential)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Picks the job's own value, the sweep list or the default, in that order
+fn candidates<T: Clone>(own: &Option<Spanned<T>>, sweep: &[Spanned<T>], default: Option<T>, job: &Range<usize>) -> Option<Vec<(T, Range<usize>)>> {
+    if let Some(v) = own {
+        return Some(vec![(v.get_ref().clone(), v.span())]);
+    }
+    if !sweep.is_empty() {
+        return Some(sweep.iter().map(|v| (v.get_ref().clone(), v.span())).collect());
+    }
+    default.map(|v| vec![(v, job.clone())])
+}
+
+impl JobPlan {
+    /**
+     * Parses and expands a job file. Syntax errors, unknown keys and invalid values that do not
+     * depend on the device are reported with file:line:column.
+     */
+    pub fn load(path: &Path) -> Result<JobPlan, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        JobPlan::parse(path, &text)
+    }
+
+    /// Like load, for the text of a job file, path only names it in error messages
+    fn parse(path: &Path, text: &str) -> Result<JobPlan, Box<dyn Error>> {
+        let source = SourceMap::new(path, text);
+
+        let file: JobFile = toml::from_str(text).map_err(|e| {
+            let span = e.span().unwrap_or(0..0);
+            JobFileError { messages: vec![source.error(&span, e.message())] }
+        })?;
+
+        let mut messages = Vec::new();
+        let mut jobs = Vec::new();
+        let mut group_repeats: HashMap<String, (u64, String)> = HashMap::new();
+
+        if file.job.is_empty() {
+            messages.push(source.error(&(0..0), "no [[job]] defined"));
+        }
+
+        for (index, spanned) in file.job.iter().enumerate() {
+            let job_span = spanned.span();
+            let job = spanned.get_ref();
+            let name = job.name.clone().unwrap_or_else(|| format!("job{}", index));
+            let sweep = &file.sweep;
+
+            let Some(io_sizes) = candidates(&job.io_size, &sweep.io_size, None, &job_span) else {
+                messages.push(source.error(&job_span, format!("{}: io_size is neither set in the job nor in [sweep]", name)));
+                continue;
+            };
+            let queue_depths = candidates(&job.queue_depth, &sweep.queue_depth, Some(1), &job_span).unwrap();
+            let thread_counts = candidates(&job.threads, &sweep.threads, Some(1), &job_span).unwrap();
+            let ops = candidates(&job.op, &sweep.op, Some(Op::Read), &job_span).unwrap();
+
+            let (limit, limit_span) = match (&job.bytes, &job.duration) {
+                (Some(bytes), None) => (Limit::Bytes(bytes.get_ref().0), bytes.span()),
+                (None, Some(duration)) => (Limit::Duration(duration.get_ref().0), duration.span()),
+                (Some(_), Some(duration)) => {
+                    messages.push(source.error(&duration.span(), format!("{}: bytes and duration are mutually exclusive", name)));
+                    continue;
+                }
+                (None, None) => {
+                    messages.push(source.error(&job_span, format!("{}: either bytes or duration is required", name)));
+                    continue;
+                }
+            };
+
+            let pattern = match resolve_pattern(&job.pattern, &job.zipf_s, "") {
+                Ok(pattern) => pattern.unwrap_or(AccessPattern::Sequential),
+                Err((span, message)) => {
+                    messages.push(source.error(&span, format!("{}: {}", name, message)));
+                    continue;
+                }
+            };
+            let write_pattern = match resolve_pattern(&job.write_pattern, &job.write_zipf_s, "write_") {
+                Ok(pattern) => pattern,
+                Err((span, message)) => {
+                    messages.push(source.error(&span, format!("{}: {}", name, message)));
+                    continue;
+                }
+            };
+
+            // (write ratio, span) per op sweep point, a read_percent replaces the op dimension
+            let mixes: Vec<(f64, Range<usize>)> = match &job.read_percent {
+                Some(_) if job.op.is_some() => {
+                    messages.push(source.error(&job.op.as_ref().unwrap().span(), format!("{}: op and read_percent are mutually exclusive", name)));
+                    continue;
+                }
+                Some(p) if (0.0..=100.0).contains(p.get_ref()) => vec![(1.0 - p.get_ref() / 100.0, p.span())],
+                Some(p) => {
+                    messages.push(source.error(&p.span(), format!("{}: read_percent must be between 0 and 100", name)));
+                    continue;
+                }
+                None => ops.iter().map(|(op, span)| (if op.is_write() { 1.0 } else { 0.0 }, span.clone())).collect(),
+            };
+
+            let target = match (&job.rate_iops, &job.rate_bandwidth) {
+                (Some(_), Some(bandwidth)) => {
+                    messages.push(source.error(&bandwidth.span(), format!("{}: rate_iops and rate_bandwidth are mutually exclusive", name)));
+                    continue;
+                }
+                (Some(iops), None) if *iops.get_ref() > 0.0 => Some(Rate::Iops(*iops.get_ref())),
+                (None, Some(bandwidth)) if bandwidth.get_ref().0 > 0 => Some(Rate::Bandwidth(bandwidth.get_ref().0)),
+                (Some(iops), None) => {
+                    messages.push(source.error(&iops.span(), format!("{}: rate_iops must be greater than 0", name)));
+                    continue;
+                }
+                (None, Some(bandwidth)) => {
+                    messages.push(source.error(&bandwidth.span(), format!("{}: rate_bandwidth must be greater than 0", name)));
+                    continue;
+                }
+                (None, None) => None,
+            };
+            if let (None, Some(arrivals)) = (target, &job.arrivals) {
+                messages.push(source.error(&arrivals.span(), format!("{}: arrivals requires rate_iops or rate_bandwidth", name)));
+                continue;
+            }
+            let arrivals = job.arrivals.as_ref().map_or(Arrivals::Poisson, |a| *a.get_ref());
+            let rate = target.map(|target| RateLimit { target, arrivals });
+
+            if let Some(verify) = &job.verify {
+                let write_io_size = job.write_io_size.as_ref().map(|s| s.get_ref().0);
+                if write_io_size.is_some_and(|size| job.io_size.as_ref().is_none_or(|io| io.get_ref().0 != size)) {
+                    messages.push(source.error(&verify.span(), format!("{}: verify needs the same io_size for reads and writes", name)));
+                    continue;
+                }
+                if mixes.iter().all(|(write_ratio, _)| *write_ratio == 0.0) {
+                    messages.push(source.error(&verify.span(), format!("{}: verify needs a job that writes", name)));
+                    continue;
+                }
+                if job.range_op.is_some() {
+                    messages.push(source.error(&verify.span(), format!("{}: verify and range_op are mutually exclusive", name)));
+                    continue;
+                }
+            }
+
+            if let (Some(_), Some(fua)) = (&job.range_op, &job.fua) {
+                messages.push(source.error(&fua.span(), format!("{}: fua and range_op are mutually exclusive", name)));
+                continue;
+            }
+
+            let first_point = jobs.len();
+            let repeat = job.repeat.as_ref().map_or(1, |r| *r.get_ref());
+            if repeat == 0 {
+                messages.push(source.error(&job.repeat.as_ref().unwrap().span(), format!("{}: repeat must be at least 1", name)));
+                continue;
+            }
+
+            let ns_span = job.ns.as_ref().map_or(job_span.clone(), |n| n.span());
+            let lba_span = job.lba_start.as_ref().or(job.lba_count.as_ref()).map_or(job_span.clone(), |l| l.span());
+
+            for (write_ratio, _) in &mixes {
+                for (io_size, io_size_span) in &io_sizes {
+                    for (queue_depth, queue_depth_span) in &queue_depths {
+                        for (threads, threads_span) in &thread_counts {
+                            let point = PlannedJob {
+                                name: name.clone(),
+                                workload: Workload {
+                                    ns_id: job.ns.as_ref().map_or(0, |n| *n.get_ref()),
+                                    lba_start: job.lba_start.as_ref().map_or(0, |l| *l.get_ref()),
+                                    lba_count: job.lba_count.as_ref().map(|l| *l.get_ref()),
+                                    io_size: io_size.0,
+                                    queue_depth: *queue_depth as usize,
+                                    num_threads: *threads as usize,
+                                    write_ratio: *write_ratio,
+                                    pattern,
+                                    write_io_size: job.write_io_size.as_ref().map(|s| s.get_ref().0),
+                                    write_pattern,
+                                    limit,
+                                    warmup: job.warmup.as_ref().map_or(Duration::ZERO, |w| w.get_ref().0),
+                                    ramp_down: job.ramp_down.as_ref().map_or(Duration::ZERO, |r| r.get_ref().0),
+                                    rate,
+                                    verify: job.verify.as_ref().map(|v| *v.get_ref()),
+                                    range_op: job.range_op.as_ref().map(|r| *r.get_ref()),
+                                    fua: job.fua.as_ref().is_some_and(|f| *f.get_ref()),
+                                    log_interval: LOG_INTERVAL,
+                                    on_error: ErrorPolicy::Continue,
+                                    max_temperature: None,
+                                },
+                                repeat,
+                                group: job.group.as_ref().map(|g| g.get_ref().clone()),
+                                on_error: job.on_error.as_ref().map(|e| *e.get_ref()),
+                                ns: job.ns.as_ref().map(|n| *n.get_ref()),
+                                lba_start: job.lba_start.as_ref().map(|l| *l.get_ref()),
+                                spans: JobSpans {
+                                    ns: ns_span.clone(),
+                                    lba: lba_span.clone(),
+                                    io_size: io_size_span.clone(),
+                                    write_io_size: job.write_io_size.as_ref().map_or(job_span.clone(), |s| s.span()),
+                                    queue_depth: queue_depth_span.clone(),
+                                    threads: threads_span.clone(),
+                                    limit: limit_span.clone(),
+                                },
+                            };
+                            messages.extend(point.check_static().into_iter().map(|(span, msg)| source.error(&span, msg)));
+                            jobs.push(point);
+                        }
+                    }
+                }
+            }
+
+            if let Some(group) = &job.group {
+                let points = jobs.len() - first_point;
+                if points > 1 {
+                    messages.push(source.error(&group.span(), format!("{}: jobs of group {} run concurrently and must not expand to {} sweep points, set io_size, queue_depth, threads and op in the job", name, group.get_ref(), points)));
+                }
+                match group_repeats.get(group.get_ref()) {
+                    Some(&(other, ref other_name)) if other != repeat => {
+                        messages.push(source.error(&group.span(), format!("{}: repeat {} differs from repeat {} of {} in group {}", name, repeat, other, other_name, group.get_ref())));
+                    }
+                    Some(_) => {}
+                    None => {
+                        group_repeats.insert(group.get_ref().clone(), (repeat, name.clone()));
+                    }
+                }
+            }
+        }
+
+        if !messages.is_empty() {
+            return Err(Box::new(JobFileError::new(messages)));
+        }
+        Ok(JobPlan { jobs, source })
+    }
+
+    /**
+     * Checks every planned job against the namespaces of the opened device before any IO is issued.
+     * Jobs without ns get the first active namespace, jobs without an LBA range the allowed window.
+     */
+    pub fn validate(&mut self, namespaces: &[Namespace], window: Option<LbaWindowSpec>) -> Result<(), JobFileError> {
+        let mut messages = Vec::new();
+        for job in &mut self.jobs {
+            messages.extend(job.apply_device(namespaces, window).into_iter().map(|(span, msg)| self.source.error(&span, msg)));
+        }
+
+        if messages.is_empty() {
+            Ok(())
+        } else {
+            Err(JobFileError::new(messages))
+        }
+    }
+}
+
+impl JobPlan {
+    /// Jobs in file order, a group runs at the position of its first job
+    pub fn batches(&self) -> Vec<Vec<&PlannedJob>> {
+        let mut batches: Vec<Vec<&PlannedJob>> = Vec::new();
+        let mut groups: HashMap<&String, usize> = HashMap::new();
+        for job in &self.jobs {
+            match job.group.as_ref().and_then(|g| groups.get(g)) {
+                Some(&index) => batches[index].push(job),
+                None => {
+                    if let Some(group) = &job.group {
+                        groups.insert(group, batches.len());
+                    }
+                    batches.push(vec![job]);
+                }
+            }
+        }
+        batches
+    }
+}
+
+impl PlannedJob {
+    fn check_static(&self) -> Vec<(Range<usize>, String)> {
+        let w = &self.workload;
+        let mut errors = Vec::new();
+
+        for (key, io_size, span) in self.io_sizes() {
+            if io_size == 0 {
+                errors.push((span, format!("{}: {} must be at least 1", self.name, key)));
+            }
+        }
+        if w.queue_depth == 0 {
+            errors.push((self.spans.queue_depth.clone(), format!("{}: queue_depth must be at least 1", self.name)));
+        }
+        if w.num_threads == 0 {
+            errors.push((self.spans.threads.clone(), format!("{}: threads must be at least 1", self.name)));
+        }
+        if let Limit::Bytes(bytes) = w.limit {
+            if w.num_threads > 0 && bytes < w.io_size * w.num_threads as u64 {
+                errors.push((self.spans.limit.clone(), format!("{}: bytes {} is less than one io of {} bytes per thread ({} threads)", self.name, bytes, w.io_size, w.num_threads)));
+            }
+        }
+        errors
+    }
+
+    /// Fills in the defaults that depend on the device and checks the job against its namespace
+    fn apply_device(&mut self, namespaces: &[Namespace], window: Option<LbaWindowSpec>) -> Vec<(Range<usize>, String)> {
+        if let (None, Some(first)) = (self.ns, namespaces.first()) {
+            self.workload.ns_id = first.id;
+        }
+        let w = &self.workload;
+        let Some(ns) = namespaces.iter().find(|ns| ns.id == w.ns_id) else {
+            let available: Vec<_> = namespaces.iter().map(|ns| ns.id).collect();
+            return vec![(self.spans.ns.clone(), format!("{}: namespace {} does not exist, available: {:?}", self.name, w.ns_id, available))];
+        };
+
+        let mut errors = Vec::new();
+        for (key, io_size, span) in self.io_sizes() {
+            // a transfer buffer is one huge page, of which the engine uses at most all but one block
+            let max_io_size = HUGE_PAGE_SIZE as u64 - ns.block_size;
+            if io_size % ns.block_size != 0 {
+                errors.push((span, format!("{}: {} {} is not a multiple of the block size {} of namespace {}", self.name, key, io_size, ns.block_size, ns.id)));
+            } else if io_size > max_io_size {
+                errors.push((span, format!("{}: {} {} exceeds the largest transfer of {} bytes (HUGE_PAGE_SIZE minus one block of namespace {})", self.name, key, io_size, max_io_size, ns.id)));
+            }
+        }
+        let window = match window.map(|spec| spec.resolve(ns)) {
+            Some(Ok(window)) => window,
+            Some(Err(message)) => return vec![(self.spans.ns.clone(), format!("{}: {}", self.name, message))],
+            None => LbaWindow::whole(ns),
+        };
+        if !errors.is_empty() {
+            return errors;
+        }
+
+        let lba_start = self.lba_start.unwrap_or(window.first);
+        let lba_count = self.workload.lba_count.unwrap_or(window.end().saturating_sub(lba_start));
+        self.workload.lba_start = lba_start;
+        self.workload.lba_count = Some(lba_count);
+
+        let largest_io = self.io_sizes().iter().map(|s| s.1).max().unwrap();
+        if !window.contains(lba_start, lba_count) {
+            let limit = if window == LbaWindow::whole(ns) { format!("namespace {} with {} blocks", ns.id, ns.blocks) } else { format!("the allowed {} of namespace {}", window, ns.id) };
+            errors.push((self.spans.lba.clone(), format!("{}: LBA range {}+{} exceeds {}", self.name, lba_start, lba_count, limit)));
+        } else if lba_count < largest_io / ns.block_size {
+            errors.push((self.spans.lba.clone(), format!("{}: LBA range of {} blocks is smaller than one io", self.name, lba_count)));
+        }
+        errors
+    }
+
+    /// (key, size, span) of the io sizes the workload actually issues
+    fn io_sizes(&self) -> Vec<(&'static str, u64, Range<usize>)> {
+        let w = &self.workload;
+        let mut sizes = Vec::new();
+        if w.write_ratio < 1.0 || w.write_io_size.is_none() {
+            sizes.push(("io_size", w.io_size, self.spans.io_size.clone()));
+        }
+        if let (Some(size), true) = (w.write_io_size, w.write_ratio > 0.0) {
+            sizes.push(("write_io_size", size, self.spans.write_io_size.clone()));
+        }
+        sizes
+    }
+
+    pub fn params(&self) -> Value {
+        let w = &self.workload;
+        let pattern_name = |pattern| match pattern {
+            AccessPattern::Sequential => ("sequential", None),
+            AccessPattern::Random => ("random", None),
+            AccessPattern::Zipf(s) => ("zipf", Some(s)),
+        };
+        let (pattern, zipf_s) = pattern_name(w.pattern);
+        let (write_pattern, write_zipf_s) = w.write_pattern.map_or((None, None), |p| {
+            let (name, s) = pattern_name(p);
+            (Some(name), s)
+        });
+        let write = match w.write_ratio {
+            r if r == 0.0 => Some(false),
+            r if r == 1.0 => Some(true),
+            _ => None,
+        };
+        let (bytes, duration_s) = match w.limit {
+            Limit::Bytes(b) => (Some(b), None),
+            Limit::Duration(d) => (None, Some(d.as_secs_f64())),
+        };
+        json!({
+            "job": self.name,
+            "ns": w.ns_id,
+            "lba_start": w.lba_start,
+            "lba_count": w.lba_count,
+            "io_size": w.io_size,
+            "queue_depth": w.queue_depth,
+            "num_threads": w.num_threads,
+            "write": write,
+            "read_percent": (1.0 - w.write_ratio) * 100.0,
+            "pattern": pattern,
+            "zipf_s": zipf_s,
+            "write_io_size": w.write_io_size,
+            "write_pattern": write_pattern,
+            "write_zipf_s": write_zipf_s,
+            "bytes": bytes,
+            "duration_s": duration_s,
+            "warmup_s": w.warmup.as_secs_f64(),
+            "ramp_down_s": w.ramp_down.as_secs_f64(),
+            "offered_iops": w.offered_iops(),
+            "arrivals": w.rate.map(|r| r.arrivals),
+            "group": self.group,
+            "verify": w.verify,
+            "range_op": w.range_op,
+            "fua": w.fua,
+            "on_error": self.on_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<JobPlan, String> {
+        JobPlan::parse(Path::new("test.toml"), text).map_err(|e| e.to_string())
+    }
+
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Ok(plan) => panic!("expected an error, got {} jobs", plan.jobs.len()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn sweep_expands_to_the_cartesian_product() {
+        let plan = parse(r#"
+            [[job]]
+            name = "fill"
+            op = "write"
+            bytes = "64M"
+
+            [[job]]
+            name = "read"
+            queue_depth = 4
+            duration = "2s"
+
+            [sweep]
+            io_size = ["8K", "1M"]
+            queue_depth = [1, 32, 128]
+            threads = [1, 8]
+        "#).unwrap();
+
+        let fill: Vec<_> = plan.jobs.iter().filter(|j| j.name == "fill").collect();
+        let read: Vec<_> = plan.jobs.iter().filter(|j| j.name == "read").collect();
+        assert_eq!(fill.len(), 2 * 3 * 2);
+        assert_eq!(read.len(), 2 * 2);
+        assert!(fill.iter().all(|j| j.workload.write_ratio == 1.0 && matches!(j.workload.limit, Limit::Bytes(b) if b == 64 << 20)));
+        assert!(read.iter().all(|j| j.workload.queue_depth == 4 && matches!(j.workload.limit, Limit::Duration(d) if d == Duration::from_secs(2))));
+        assert_eq!(plan.batches().len(), plan.jobs.len());
+    }
+
+    #[test]
+    fn mixed_zipf_job() {
+        let plan = parse(r#"
+            [[job]]
+            io_size = 4096
+            read_percent = 70
+            pattern = "zipf"
+            zipf_s = 1.2
+            write_io_size = "16K"
+            duration = "1s"
+        "#).unwrap();
+
+        let w = &plan.jobs[0].workload;
+        assert!((w.write_ratio - 0.3).abs() < 1e-9);
+        assert_eq!(w.pattern, AccessPattern::Zipf(1.2));
+        assert_eq!(w.io_size(true), 16384);
+        assert_eq!(w.io_size(false), 4096);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_value() {
+        let error = parse_error("[[job]]\nio_size = \"4K\"\nqueue_depth = 0\nduration = 1\n");
+        assert!(error.contains("test.toml:3:15: job0: queue_depth must be at least 1"), "{}", error);
+
+        let error = parse_error("[[job]]\nio_size = \"4K\"\npattern = \"zipf\"\nduration = 1\n");
+        assert!(error.contains("test.toml:3:11:") && error.contains("pattern zipf requires zipf_s"), "{}", error);
+
+        let error = parse_error("[[job]]\nio_size = \"4K\"\nqueue_dept = 1\n");
+        assert!(error.contains("test.toml:3:1:") && error.contains("unknown field"), "{}", error);
+    }
+
+    #[test]
+    fn conflicting_keys_are_rejected() {
+        let error = parse_error("[[job]]\nio_size = \"4K\"\nop = \"write\"\nverify = \"after\"\nrange_op = \"deallocate\"\nduration = 1\n");
+        assert!(error.contains("range_op"), "{}", error);
+
+        let error = parse_error("[[job]]\nname = \"a\"\nrepeat = 2\ngroup = \"g\"\nio_size = \"4K\"\nduration = 1\n[[job]]\nname = \"b\"\nrepeat = 3\ngroup = \"g\"\nio_size = \"4K\"\nduration = 1\n");
+        assert!(error.contains("repeat 3 differs from repeat 2"), "{}", error);
+    }
+
+    #[test]
+    fn device_checks() {
+        let namespaces = [Namespace { id: 1, blocks: 1 << 20, block_size: 4096 }];
+        let mut plan = parse("[[job]]\nio_size = 512\nduration = 1\n").unwrap();
+        let error = plan.validate(&namespaces, None).unwrap_err().to_string();
+        assert!(error.contains("not a multiple of the block size 4096"), "{}", error);
+
+        let mut plan = parse("[[job]]\nio_size = \"2M\"\nduration = 1\n").unwrap();
+        let error = plan.validate(&namespaces, None).unwrap_err().to_string();
+        assert!(error.contains("exceeds the largest transfer of 2093056 bytes"), "{}", error);
+
+        let mut plan = parse("[[job]]\nio_size = \"4K\"\nlba_start = 1048570\nlba_count = 16\nduration = 1\n").unwrap();
+        let error = plan.validate(&namespaces, None).unwrap_err().to_string();
+        assert!(error.contains("exceeds namespace 1"), "{}", error);
+
+        let mut plan = parse("[[job]]\nio_size = \"4K\"\nduration = 1\n").unwrap();
+        plan.validate(&namespaces, None).unwrap();
+        let w = &plan.jobs[0].workload;
+        assert_eq!((w.ns_id, w.lba_start, w.lba_count), (1, 0, Some(1 << 20)));
+    }
+}
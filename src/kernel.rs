@@ -0,0 +1,589 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use vroom::IdentifyControllerInfo;
+
+use crate::cli::RangeOp;
+use crate::device::{ascii_field, CompletionStatus, HostBuffer, IoBuffer, IoQueue, Namespace, NvmeBackend};
+use crate::error::BenchmarkError;
+use crate::features::{NamespaceIdentity, IDENTIFY_SIZE};
+
+/// BLKSSZGET from linux/fs.h, logical sector size of a block device
+const BLKSSZGET: libc::c_ulong = 0x1268;
+/// Largest single pread / pwrite, larger requests are split like vroom splits them into commands
+const MAX_TRANSFER: usize = 2 * 1024 * 1024;
+/// BLKDISCARD and BLKZEROOUT from linux/fs.h, take a byte offset and length
+const BLKDISCARD: libc::c_ulong = 0x1277;
+const BLKZEROOUT: libc::c_ulong = 0x127f;
+/// NVME_IOCTL_ID from linux/nvme_ioctl.h, namespace id of an NVMe namespace block device
+const NVME_IOCTL_ID: libc::c_ulong = 0x4e40;
+/// NVME_IOCTL_ADMIN_CMD, _IOWR('N', 0x41, struct nvme_passthru_cmd)
+const NVME_IOCTL_ADMIN_CMD: libc::c_ulong = 0xc048_4e41;
+
+/// struct nvme_passthru_cmd from linux/nvme_ioctl.h
+#[repr(C)]
+#[derive(Default)]
+struct PassthruCommand {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/**
+ * Runs the benchmarks through the kernel block layer against a block device or a preallocated
+ * regular file opened with O_DIRECT, so the userspace driver can be compared with the kernel stack.
+ * The whole file is exposed as namespace 1. Admin commands go through the NVMe driver if the
+ * target is an NVMe namespace block device. Deallocates are discards respectively punched holes
+ * in the file, Write Zeroes are BLKZEROOUT respectively zeroed file ranges. FUA writes are O_DSYNC
+ * writes, which the kernel turns into FUA commands, and flushes are fdatasync.
+ */
+pub struct KernelDevice {
+    file: Arc<File>,
+    namespace: Namespace,
+    info: IdentifyControllerInfo,
+    block_device: bool,
+    /// id of the NVMe namespace behind the block device
+    nsid: Option<u32>,
+}
+
+impl KernelDevice {
+    /// With read_only the target is opened without write access, writes then fail with EBADF
+    pub fn open(path: &Path, read_only: bool) -> Result<KernelDevice, Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .map_err(|e| format!("failed to open {} with O_DIRECT: {}", path.display(), e))?;
+
+        let metadata = file.metadata()?;
+        let block_device = metadata.file_type().is_block_device();
+        let (size, block_size) = if block_device {
+            let mut sector_size: libc::c_int = 0;
+            if unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut sector_size) } < 0 {
+                return Err(format!("BLKSSZGET on {} failed: {}", path.display(), io::Error::last_os_error()).into());
+            }
+            (file.seek(SeekFrom::End(0))?, sector_size as u64)
+        } else {
+            // O_DIRECT on a file system has to be aligned to its block size
+            (metadata.len(), metadata.blksize())
+        };
+
+        if size < block_size {
+            return Err(format!("{} is smaller than one block, preallocate files e.g. with fallocate -l 16G {}", path.display(), path.display()).into());
+        }
+
+        let mut info: IdentifyControllerInfo = unsafe { std::mem::zeroed() };
+        let model = if block_device { "kernel block device" } else { "kernel file" };
+        info.model_number = ascii_field(model);
+        info.serial_number = ascii_field(&path.display().to_string());
+        info.firmware_revision = ascii_field(env!("CARGO_PKG_VERSION"));
+        info.num_namespaces = 1;
+
+        let nsid = match block_device {
+            true => u32::try_from(unsafe { libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ID) }).ok(),
+            false => None,
+        };
+
+        Ok(KernelDevice {
+            file: Arc::new(file),
+            namespace: Namespace { id: 1, blocks: size / block_size, block_size },
+            info,
+            block_device,
+            nsid,
+        })
+    }
+
+    /**
+     * Submits an admin command for the NVMe namespace behind the block device, the caller needs
+     * CAP_SYS_ADMIN
+     * @param data transferred from the controller into the buffer (or the other way round for write)
+     * @returns dword 0 of the completion
+     */
+    fn admin_command(&self, opcode: u8, nsid: u32, cdw10: u32, cdw11: u32, data: &mut [u8]) -> Result<u32, BenchmarkError> {
+        if self.nsid.is_none() {
+            return Err(BenchmarkError::Unsupported(format!("admin command {:#04x} needs an NVMe namespace block device", opcode)));
+        }
+        let mut command = PassthruCommand {
+            opcode,
+            nsid,
+            addr: data.as_mut_ptr() as u64,
+            data_len: data.len() as u32,
+            cdw10,
+            cdw11,
+            ..PassthruCommand::default()
+        };
+        match unsafe { libc::ioctl(self.file.as_raw_fd(), NVME_IOCTL_ADMIN_CMD, &mut command) } {
+            0 => Ok(command.result),
+            status if status > 0 => Err(BenchmarkError::Device(format!("admin command {:#04x} failed with {}", opcode, CompletionStatus(status as u16)))),
+            _ => Err(BenchmarkError::device(format!("admin command {:#04x}: {}", opcode, io::Error::last_os_error()))),
+        }
+    }
+
+    /// Targets that name a path instead of a PCI address, e.g. /dev/nvme0n1 or ./test.img
+    pub fn is_path(target: &str) -> bool {
+        target.contains('/')
+    }
+}
+
+impl NvmeBackend for KernelDevice {
+    type Queue = KernelQueue;
+
+    fn namespaces(&self) -> Vec<Namespace> {
+        vec![self.namespace]
+    }
+
+    fn controller_info(&self) -> &IdentifyControllerInfo {
+        &self.info
+    }
+
+    fn identify_namespace(&mut self, id: u32) -> Result<NamespaceIdentity, BenchmarkError> {
+        if id != self.namespace.id {
+            return Err(BenchmarkError::InvalidParameter(format!("namespace {} does not exist", id)));
+        }
+        let mut data = vec![0u8; IDENTIFY_SIZE];
+        self.admin_command(0x06, self.nsid.unwrap_or(0), 0, 0, &mut data)?;
+        Ok(NamespaceIdentity::parse(id, &data))
+    }
+
+    fn get_log_page(&mut self, log_id: u8, data: &mut [u8]) -> Result<(), BenchmarkError> {
+        // number of dwords minus one, split into NUMDL (cdw10) and NUMDU (cdw11)
+        let Some(numd) = (data.len() / 4).checked_sub(1) else {
+            return Err(BenchmarkError::InvalidParameter(format!("Get Log Page {:#04x} needs a buffer of at least one dword, got {} bytes", log_id, data.len())));
+        };
+        let numd = numd as u32;
+        self.admin_command(0x02, 0xffff_ffff, log_id as u32 | (numd & 0xffff) << 16, numd >> 16, data)?;
+        Ok(())
+    }
+
+    fn get_features(&mut self, fid: u8) -> Result<u32, BenchmarkError> {
+        self.admin_command(0x0a, 0, fid as u32, 0, &mut [])
+    }
+
+    fn set_features(&mut self, fid: u8, value: u32) -> Result<(), BenchmarkError> {
+        self.admin_command(0x09, 0, fid as u32, value, &mut [])?;
+        Ok(())
+    }
+
+    /// The kernel emulates both where the device lacks them, discards then fail
+    fn supports_range_op(&self, _op: RangeOp) -> bool {
+        true
+    }
+
+    fn supports_durable_writes(&self) -> bool {
+        true
+    }
+
+    fn supports_admin_commands(&self) -> bool {
+        true
+    }
+
+    fn reports_completion_status(&self) -> bool {
+        true
+    }
+
+    fn create_io_queue_pair(&mut self, len: usize) -> Result<KernelQueue, BenchmarkError> {
+        let (submit, requests) = mpsc::channel();
+        let (done, completions) = mpsc::channel();
+        Ok(KernelQueue {
+            file: self.file.clone(),
+            block_device: self.block_device,
+            namespace: self.namespace,
+            len,
+            inflight: 0,
+            submit: Some(submit),
+            requests: Arc::new(Mutex::new(requests)),
+            done,
+            completions,
+            workers: Vec::new(),
+        })
+    }
+
+    fn delete_io_queue_pair(&mut self, mut queue_pair: KernelQueue) -> Result<(), BenchmarkError> {
+        let inflight = queue_pair.inflight;
+        queue_pair.shutdown();
+        if inflight > 0 {
+            return Err(BenchmarkError::QueuePair(format!("queue pair still had {} requests in flight", inflight)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone)]
+enum RequestKind {
+    Read,
+    Write,
+    Fua,
+    Flush,
+    Range(RangeOp),
+}
+
+struct Request {
+    /// address in a HostBuffer, which the benchmarks keep alive until the request completed
+    addr: usize,
+    len: usize,
+    offset: u64,
+    kind: RequestKind,
+}
+
+/// pwrite with RWF_DSYNC, the data is durable once it returns
+fn write_dsync(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    let iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    match unsafe { libc::pwritev2(file.as_raw_fd(), &iov, 1, offset as libc::off_t, libc::RWF_DSYNC) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n if n as usize != data.len() => Err(io::Error::new(io::ErrorKind::WriteZero, "short write")),
+        _ => Ok(()),
+    }
+}
+
+/// Issues a range operation on len bytes at offset
+fn range_op(file: &File, block_device: bool, op: RangeOp, offset: u64, len: u64) -> io::Result<()> {
+    let res = if block_device {
+        let range = [offset, len];
+        let request = match op {
+            RangeOp::Deallocate => BLKDISCARD,
+            RangeOp::WriteZeroes => BLKZEROOUT,
+        };
+        unsafe { libc::ioctl(file.as_raw_fd(), request, &range) }
+    } else {
+        let mode = match op {
+            RangeOp::Deallocate => libc::FALLOC_FL_PUNCH_HOLE,
+            RangeOp::WriteZeroes => libc::FALLOC_FL_ZERO_RANGE,
+        };
+        unsafe { libc::fallocate(file.as_raw_fd(), mode | libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, len as libc::off_t) }
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/**
+ * Emulates a queue pair with a pool of worker threads doing blocking pread / pwrite. A worker is
+ * added whenever more requests are in flight than there are workers, so the pool grows to the
+ * queue depth the benchmark actually uses. Requests complete out of order.
+ */
+pub struct KernelQueue {
+    file: Arc<File>,
+    block_device: bool,
+    namespace: Namespace,
+    len: usize,
+    inflight: usize,
+    submit: Option<Sender<Request>>,
+    requests: Arc<Mutex<Receiver<Request>>>,
+    done: Sender<io::Result<()>>,
+    completions: Receiver<io::Result<()>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl KernelQueue {
+    fn spawn_worker(&mut self) {
+        let file = self.file.clone();
+        let block_device = self.block_device;
+        let requests = self.requests.clone();
+        let done = self.done.clone();
+
+        self.workers.push(std::thread::spawn(move || loop {
+            let request = match requests.lock().unwrap().recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            let result = match request.kind {
+                RequestKind::Write => {
+                    let data = unsafe { std::slice::from_raw_parts(request.addr as *const u8, request.len) };
+                    file.write_all_at(data, request.offset)
+                }
+                RequestKind::Read => {
+                    let data = unsafe { std::slice::from_raw_parts_mut(request.addr as *mut u8, request.len) };
+                    file.read_exact_at(data, request.offset)
+                }
+                RequestKind::Fua => {
+                    let data = unsafe { std::slice::from_raw_parts(request.addr as *const u8, request.len) };
+                    write_dsync(&file, data, request.offset)
+                }
+                RequestKind::Flush => file.sync_data(),
+                RequestKind::Range(op) => range_op(&file, block_device, op, request.offset, request.len as u64),
+            };
+            if done.send(result).is_err() {
+                return;
+            }
+        }));
+    }
+
+    /// The kernel only reports an errno, every failed request counts as a data transfer error
+    fn reap(&mut self, result: io::Result<()>) -> CompletionStatus {
+        self.inflight -= 1;
+        match result {
+            Ok(()) => CompletionStatus::SUCCESS,
+            Err(_) => CompletionStatus::DATA_TRANSFER_ERROR,
+        }
+    }
+
+    /// Splits a read or write like vroom splits it into commands
+    fn submit(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64, kind: RequestKind) -> usize {
+        let ns = self.namespace;
+        let bytes = range.len();
+        if ns_id != ns.id || block_size != ns.block_size || bytes == 0 || range.end > data.len()
+            || lba + (bytes as u64).div_ceil(block_size) > ns.blocks {
+            return 0;
+        }
+
+        let chunks: Vec<_> = range.clone().step_by(MAX_TRANSFER).map(|start| start..(start + MAX_TRANSFER).min(range.end)).collect();
+        if self.inflight + chunks.len() >= self.len {
+            return 0;
+        }
+
+        for chunk in &chunks {
+            let request = Request {
+                addr: data.as_ptr() as usize + chunk.start,
+                len: chunk.len(),
+                offset: lba * block_size + (chunk.start - range.start) as u64,
+                kind,
+            };
+            self.send(request);
+        }
+        chunks.len()
+    }
+
+    fn send(&mut self, request: Request) {
+        self.inflight += 1;
+        if self.workers.len() < self.inflight {
+            self.spawn_worker();
+        }
+        self.submit.as_ref().unwrap().send(request).expect("workers run until the queue is deleted");
+    }
+
+    fn shutdown(&mut self) {
+        while self.inflight > 0 {
+            let result = self.completions.recv().expect("workers outlive their requests");
+            self.reap(result);
+        }
+        self.submit = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for KernelQueue {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl IoQueue for KernelQueue {
+    type Buffer = HostBuffer;
+
+    fn allocate_buffer(&self, size: usize) -> Result<HostBuffer, BenchmarkError> {
+        HostBuffer::allocate(size)
+    }
+
+    fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64, write: bool) -> usize {
+        self.submit(ns_id, block_size, data, range, lba, if write { RequestKind::Write } else { RequestKind::Read })
+    }
+
+    fn submit_fua(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64) -> usize {
+        self.submit(ns_id, block_size, data, range, lba, RequestKind::Fua)
+    }
+
+    fn submit_flush(&mut self, ns_id: u32) -> usize {
+        if ns_id != self.namespace.id || self.inflight + 1 >= self.len {
+            return 0;
+        }
+        self.send(Request { addr: 0, len: 0, offset: 0, kind: RequestKind::Flush });
+        1
+    }
+
+    /// The kernel splits the range itself, so it is a single request
+    fn submit_range(&mut self, ns_id: u32, block_size: u64, lba: u64, blocks: u64, op: RangeOp) -> usize {
+        let ns = self.namespace;
+        if ns_id != ns.id || block_size != ns.block_size || blocks == 0 || lba + blocks > ns.blocks || self.inflight + 1 >= self.len {
+            return 0;
+        }
+        self.send(Request { addr: 0, len: (blocks * block_size) as usize, offset: lba * block_size, kind: RequestKind::Range(op) });
+        1
+    }
+
+    fn complete_io(&mut self, n: usize) -> usize {
+        let mut failed = 0;
+        for _ in 0..n.min(self.inflight) {
+            let result = self.completions.recv().expect("workers outlive their requests");
+            failed += !self.reap(result).is_success() as usize;
+        }
+        failed
+    }
+
+    fn quick_poll(&mut self) -> Option<CompletionStatus> {
+        let result = self.completions.try_recv().ok()?;
+        Some(self.reap(result))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::NamedTempFile;
+
+    use crate::benchmarks::run_workloads;
+    use crate::device::LbaWindow;
+    use crate::util::{Limit, Workload};
+    use crate::verify::VerifyMode;
+
+    const SIZE: u64 = 16 << 20;
+
+    fn image() -> (NamedTempFile, KernelDevice) {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(SIZE).unwrap();
+        let device = KernelDevice::open(file.path(), false).unwrap();
+        (file, device)
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i / 7) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn exposes_the_file_as_one_namespace() {
+        let (_file, device) = image();
+        let ns = device.namespaces();
+        assert_eq!(ns.len(), 1);
+        assert_eq!(ns[0].id, 1);
+        assert_eq!(ns[0].blocks * ns[0].block_size, SIZE);
+    }
+
+    #[test]
+    fn writes_and_reads_match_the_file() {
+        let (file, mut device) = image();
+        let ns = device.namespaces()[0];
+        let len = 3 * MAX_TRANSFER + 4096;
+        let lba = 8192 / ns.block_size;
+        let written = pattern(len, 0x5a);
+
+        device.with_queue_pair(64, |queue_pair| {
+            let mut buffer = queue_pair.allocate_buffer(len)?;
+            buffer.as_mut_slice().copy_from_slice(&written);
+            let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..len, lba, true);
+            assert_eq!(commands, 4);
+            assert_eq!(queue_pair.complete_io(commands), 0);
+            Ok(())
+        }).unwrap();
+        let contents = fs::read(file.path()).unwrap();
+        assert_eq!(&contents[8192..8192 + len], &written[..]);
+
+        let expected = pattern(len, 0xa5);
+        file.as_file().write_all_at(&expected, 8192).unwrap();
+        let read = device.with_queue_pair(64, |queue_pair| {
+            let buffer = queue_pair.allocate_buffer(len)?;
+            let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..len, lba, false);
+            assert_eq!(queue_pair.complete_io(commands), 0);
+            Ok(buffer.as_slice().to_vec())
+        }).unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn range_ops_zero_the_file_range() {
+        let (file, mut device) = image();
+        let ns = device.namespaces()[0];
+        file.as_file().write_all_at(&pattern(SIZE as usize, 1), 0).unwrap();
+
+        let blocks = (1 << 20) / ns.block_size;
+        for (op, lba) in [(RangeOp::Deallocate, 0), (RangeOp::WriteZeroes, blocks * 4)] {
+            device.with_queue_pair(8, |queue_pair| {
+                assert_eq!(queue_pair.submit_range(ns.id, ns.block_size, lba, blocks, op), 1);
+                assert_eq!(queue_pair.complete_io(1), 0);
+                Ok(())
+            }).unwrap();
+        }
+
+        let contents = fs::read(file.path()).unwrap();
+        assert!(contents[..1 << 20].iter().all(|&b| b == 0));
+        assert!(contents[4 << 20..5 << 20].iter().all(|&b| b == 0));
+        assert_eq!(&contents[1 << 20..4 << 20], &pattern(SIZE as usize, 1)[1 << 20..4 << 20]);
+    }
+
+    #[test]
+    fn fua_writes_and_flushes_complete() {
+        let (file, mut device) = image();
+        let ns = device.namespaces()[0];
+        let written = pattern(65536, 3);
+        device.with_queue_pair(8, |queue_pair| {
+            let mut buffer = queue_pair.allocate_buffer(written.len())?;
+            buffer.as_mut_slice().copy_from_slice(&written);
+            let commands = queue_pair.submit_fua(ns.id, ns.block_size, &buffer, 0..written.len(), 0);
+            assert_eq!(commands, 1);
+            assert_eq!(queue_pair.submit_flush(ns.id), 1);
+            assert_eq!(queue_pair.complete_io(2), 0);
+            Ok(())
+        }).unwrap();
+        assert_eq!(&fs::read(file.path()).unwrap()[..written.len()], &written[..]);
+    }
+
+    #[test]
+    fn log_pages_need_one_dword() {
+        let (_file, mut device) = image();
+        for len in [0, 3] {
+            let error = device.get_log_page(0x02, &mut vec![0; len]).unwrap_err();
+            assert!(matches!(error, BenchmarkError::InvalidParameter(_)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn read_only_targets_reject_writes() {
+        let (file, _) = image();
+        let mut device = KernelDevice::open(file.path(), true).unwrap();
+        let ns = device.namespaces()[0];
+        device.with_queue_pair(8, |queue_pair| {
+            let buffer = queue_pair.allocate_buffer(4096)?;
+            let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..4096, 0, true);
+            assert_eq!(queue_pair.complete_io(commands), 1);
+            let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..4096, 0, false);
+            assert_eq!(queue_pair.complete_io(commands), 0);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn verified_workload_round_trips() {
+        let (_file, device) = image();
+        let ns = device.namespaces()[0];
+        let workload = Workload {
+            queue_depth: 8,
+            num_threads: 2,
+            write_ratio: 1.0,
+            verify: Some(VerifyMode::After),
+            ..Workload::new(ns.id, LbaWindow::whole(&ns), 16384, Limit::Bytes(4 << 20))
+        };
+        let (_, _, reports, _) = run_workloads(device, &[workload]).unwrap();
+        let report = reports[0].as_ref().unwrap();
+
+        assert!(report.blocks_checked > 0);
+        assert_eq!(report.blocks_checked, report.blocks_written);
+        assert_eq!(report.misdirected + report.stale + report.torn + report.corrupted, 0);
+    }
+}
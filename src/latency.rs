@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::device::{CompletionStatus, IoQueue};
+
+/// Longest wait for the next completion, like the default IO timeout of the Linux NVMe driver
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/*
+ * Log-linear bucketing in the style of HdrHistogram: values below 2*SUB_BUCKETS get their own bucket,
+ * every further power of two is split into SUB_BUCKETS linear buckets. The relative error of a
+ * reported value is below 1/SUB_BUCKETS (< 0.8%). Values are nanoseconds.
+ */
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = ((64 - SUB_BUCKET_BITS as usize) + 1) * SUB_BUCKETS as usize;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = (63 - value.leading_zeros()) - SUB_BUCKET_BITS;
+    (shift as u64 * SUB_BUCKETS + (value >> shift)) as usize
+}
+
+/// Highest value that maps to the given bucket
+fn bucket_upper(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let top = index - shift * SUB_BUCKETS;
+    (top << shift) + ((1 << shift) - 1)
+}
+
+/**
+ * Sparse latency histogram, cheap to keep per IoLog and mergeable across threads and time.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    /// (bucket index, count), sorted by index
+    buckets: Vec<(u16, u64)>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram::default()
+    }
+
+    fn add_stats(&mut self, count: u64, sum: u128, min: u64, max: u64) {
+        if count == 0 {
+            return;
+        }
+        self.min = if self.count == 0 { min } else { self.min.min(min) };
+        self.max = self.max.max(max);
+        self.count += count;
+        self.sum += sum;
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        if other.count == 0 {
+            return;
+        }
+        let mut merged = Vec::with_capacity(self.buckets.len().max(other.buckets.len()));
+        let (mut a, mut b) = (self.buckets.iter().peekable(), other.buckets.iter().peekable());
+        loop {
+            let next = match (a.peek(), b.peek()) {
+                (Some(&&x), Some(&&y)) if x.0 == y.0 => (x.0, x.1 + y.1),
+                (Some(&&x), Some(&&y)) => if x.0 < y.0 { x } else { y },
+                (Some(&&x), None) => x,
+                (None, Some(&&y)) => y,
+                (None, None) => break,
+            };
+            if a.peek().is_some_and(|x| x.0 == next.0) {
+                a.next();
+            }
+            if b.peek().is_some_and(|y| y.0 == next.0) {
+                b.next();
+            }
+            merged.push(next);
+        }
+        self.buckets = merged;
+        self.add_stats(other.count, other.sum, other.min, other.max);
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /**
+     * @param percentile in [0, 100]
+     * @returns the highest value equivalent to the bucket the percentile falls into, clamped to min/max
+     */
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for &(index, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return bucket_upper(index as usize).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        if self.count == 0 {
+            return None;
+        }
+        let us = |ns: u64| ns as f64 / 1000.0;
+        Some(LatencySummary {
+            count: self.count,
+            min_us: us(self.min),
+            mean_us: self.mean() / 1000.0,
+            p50_us: us(self.percentile(50.0)),
+            p90_us: us(self.percentile(90.0)),
+            p99_us: us(self.percentile(99.0)),
+            p99_9_us: us(self.percentile(99.9)),
+            p99_99_us: us(self.percentile(99.99)),
+            max_us: us(self.max),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min_us: f64,
+    pub mean_us: f64,
+    pub p50_us: f64,
+    pub p90_us: f64,
+    pub p99_us: f64,
+    pub p99_9_us: f64,
+    pub p99_99_us: f64,
+    pub max_us: f64,
+}
+
+/**
+ * Dense histogram for the hot path, drained into a sparse LatencyHistogram whenever an IoLog is written.
+ */
+pub struct LatencyRecorder {
+    counts: Vec<u64>,
+    lowest: usize,
+    highest: usize,
+    histogram: LatencyHistogram,
+}
+
+impl LatencyRecorder {
+    pub fn new() -> LatencyRecorder {
+        LatencyRecorder { counts: vec![0; NUM_BUCKETS], lowest: NUM_BUCKETS, highest: 0, histogram: LatencyHistogram::new() }
+    }
+
+    pub fn record(&mut self, value_ns: u64) {
+        let index = bucket_index(value_ns);
+        self.counts[index] += 1;
+        self.lowest = self.lowest.min(index);
+        self.highest = self.highest.max(index);
+        self.histogram.add_stats(1, value_ns as u128, value_ns, value_ns);
+    }
+
+    /// Returns everything recorded since the last call
+    pub fn take(&mut self) -> LatencyHistogram {
+        let mut histogram = std::mem::take(&mut self.histogram);
+        if self.lowest <= self.highest {
+            for index in self.lowest..=self.highest {
+                if self.counts[index] > 0 {
+                    histogram.buckets.push((index as u16, self.counts[index]));
+                    self.counts[index] = 0;
+                }
+            }
+        }
+        self.lowest = NUM_BUCKETS;
+        self.highest = 0;
+        histogram
+    }
+}
+
+/// Distinct entries an ErrorTable keeps the LBA of, further failures only count per status and operation
+const MAX_ERROR_ENTRIES: usize = 1024;
+
+/**
+ * Failed commands counted by status, operation and LBA of the request they belong to
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ErrorTable {
+    /// (status, write, lba), lba is None once the table is full
+    entries: BTreeMap<(CompletionStatus, bool, Option<u64>), u64>,
+}
+
+/// One line of an error table
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorRow {
+    pub op: &'static str,
+    /// None for the failures that no longer fitted into the table
+    pub lba: Option<u64>,
+    pub sct: u8,
+    pub sc: u8,
+    pub description: &'static str,
+    pub count: u64,
+}
+
+impl ErrorTable {
+    pub fn record(&mut self, status: CompletionStatus, write: bool, lba: u64) {
+        self.add((status.code(), write, Some(lba)), 1);
+    }
+
+    pub fn merge(&mut self, other: &ErrorTable) {
+        for (&key, &count) in &other.entries {
+            self.add(key, count);
+        }
+    }
+
+    fn add(&mut self, key: (CompletionStatus, bool, Option<u64>), count: u64) {
+        let key = if self.entries.len() < MAX_ERROR_ENTRIES || self.entries.contains_key(&key) { key } else { (key.0, key.1, None) };
+        *self.entries.entry(key).or_insert(0) += count;
+    }
+
+    /// Failed commands in the table
+    pub fn total(&self) -> u64 {
+        self.entries.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn rows(&self) -> Vec<ErrorRow> {
+        self.entries.iter().map(|(&(status, write, lba), &count)| ErrorRow {
+            op: if write { "write" } else { "read" },
+            lba,
+            sct: status.sct(),
+            sc: status.sc(),
+            description: status.description(),
+            count,
+        }).collect()
+    }
+}
+
+/// Requests and bytes of one operation type completed since the last take
+#[derive(Clone, Debug, Default)]
+pub struct Completed {
+    pub requests: usize,
+    pub commands: usize,
+    pub bytes: u64,
+    /// completed requests per request size
+    pub sizes: BTreeMap<u64, u64>,
+    /// commands that completed with an error status, they count as completed nonetheless
+    pub failed: usize,
+    /// requests the queue pair did not accept
+    pub rejected: usize,
+    pub errors: ErrorTable,
+}
+
+struct Inflight {
+    submitted: Instant,
+    write: bool,
+    bytes: u64,
+    /// first LBA of the request
+    lba: u64,
+    /// size of the request if this is its last command
+    request: Option<u64>,
+}
+
+/**
+ * Submit timestamps of the commands in flight on one queue pair. quick_poll does not hand out
+ * the command id of a completion, so completions are matched to submissions in FIFO order. If the
+ * controller completes out of order, samples can be swapped between commands.
+ * Reads and writes are recorded separately for mixed workloads. The bytes of a request are split
+ * evenly between its commands, a request counts as completed with its last command.
+ */
+pub struct IoTimer {
+    inflight: VecDeque<Inflight>,
+    recorders: [LatencyRecorder; 2],
+    completed: [Completed; 2],
+    /// failed commands since the timer was created
+    failures: u64,
+}
+
+impl IoTimer {
+    pub fn new() -> IoTimer {
+        IoTimer { inflight: VecDeque::new(), recorders: [LatencyRecorder::new(), LatencyRecorder::new()], completed: Default::default(), failures: 0 }
+    }
+
+    /// submit_io can split a request into several commands, each of them completes separately
+    pub fn submitted_request(&mut self, commands: usize, bytes: u64, lba: u64, at: Instant, write: bool) {
+        for i in 0..commands as u64 {
+            let last = i + 1 == commands as u64;
+            self.inflight.push_back(Inflight {
+                submitted: at,
+                write,
+                bytes: bytes / commands as u64 + if last { bytes % commands as u64 } else { 0 },
+                lba,
+                request: last.then_some(bytes),
+            });
+        }
+    }
+
+    /// A request submit_io returned 0 for
+    pub fn rejected(&mut self, write: bool) {
+        self.completed[write as usize].rejected += 1;
+    }
+
+    pub fn completed(&mut self, at: Instant, status: CompletionStatus) {
+        if let Some(command) = self.inflight.pop_front() {
+            self.recorders[command.write as usize].record(at.duration_since(command.submitted).as_nanos() as u64);
+            self.account(command, status);
+        }
+    }
+
+    /// A command that never completed, it fails without a latency sample
+    fn lost(&mut self) {
+        if let Some(command) = self.inflight.pop_front() {
+            self.account(command, CompletionStatus::ABORTED_BY_HOST);
+        }
+    }
+
+    fn account(&mut self, command: Inflight, status: CompletionStatus) {
+        let completed = &mut self.completed[command.write as usize];
+        completed.commands += 1;
+        if !status.is_success() {
+            completed.failed += 1;
+            completed.errors.record(status, command.write, command.lba);
+            self.failures += 1;
+        }
+        completed.bytes += command.bytes;
+        if let Some(size) = command.request {
+            completed.requests += 1;
+            *completed.sizes.entry(size).or_insert(0) += 1;
+        }
+    }
+
+    /// Latencies of reads and writes since the last call
+    pub fn take(&mut self) -> LatencyHistogram {
+        let mut histogram = self.take_op(false);
+        histogram.merge(&self.take_op(true));
+        histogram
+    }
+
+    pub fn take_op(&mut self, write: bool) -> LatencyHistogram {
+        self.recorders[write as usize].take()
+    }
+
+    /// Completions of one operation type since the last call
+    pub fn take_completed(&mut self, write: bool) -> Completed {
+        std::mem::take(&mut self.completed[write as usize])
+    }
+
+    /**
+     * Drops the completion counters of both operation types
+     * @returns (failed, rejected, errors) since the last take
+     */
+    pub fn take_errors(&mut self) -> (u64, u64, ErrorTable) {
+        let [read, mut write] = std::mem::take(&mut self.completed);
+        write.errors.merge(&read.errors);
+        ((read.failed + write.failed) as u64, (read.rejected + write.rejected) as u64, write.errors)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /**
+     * Polls without blocking
+     * @returns the number of completions
+     */
+    pub fn poll<Q: IoQueue>(&mut self, queue_pair: &mut Q) -> usize {
+        let mut n = 0;
+        while let Some(status) = queue_pair.quick_poll() {
+            self.completed(Instant::now(), status);
+            n += 1;
+        }
+        n
+    }
+
+    /**
+     * Like complete_io, but timestamps every single completion. Once no completion arrived for
+     * COMPLETION_TIMEOUT, the missing ones are recorded as failed, aborted by the host.
+     */
+    pub fn complete<Q: IoQueue>(&mut self, queue_pair: &mut Q, n: usize) {
+        self.complete_within(queue_pair, n, COMPLETION_TIMEOUT);
+    }
+
+    fn complete_within<Q: IoQueue>(&mut self, queue_pair: &mut Q, n: usize, timeout: Duration) {
+        let mut done = 0;
+        let mut deadline = Instant::now() + timeout;
+        while done < n {
+            let now = Instant::now();
+            if let Some(status) = queue_pair.quick_poll() {
+                self.completed(now, status);
+                done += 1;
+                deadline = now + timeout;
+            } else if now >= deadline {
+                (done..n).for_each(|_| self.lost());
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::NvmeBackend;
+    use crate::sim::{SimConfig, SimDevice};
+
+    fn histogram(values: impl IntoIterator<Item = u64>) -> LatencyHistogram {
+        let mut recorder = LatencyRecorder::new();
+        values.into_iter().for_each(|v| recorder.record(v));
+        recorder.take()
+    }
+
+    #[test]
+    fn buckets_keep_the_relative_error_bound() {
+        for value in (0..20).map(|shift| 1u64 << shift).flat_map(|v| [v, v + 1, v * 3 / 2, v * 2 - 1]) {
+            let upper = bucket_upper(bucket_index(value));
+            assert!(upper >= value);
+            assert!((upper - value) as f64 <= value as f64 / SUB_BUCKETS as f64, "value {} upper {}", value, upper);
+        }
+        assert!(bucket_index(u64::MAX) < NUM_BUCKETS);
+    }
+
+    #[test]
+    fn percentiles_of_a_uniform_distribution() {
+        let h = histogram((1..=10_000).map(|us| us * 1000));
+        let summary = h.summary().unwrap();
+        assert_eq!(summary.count, 10_000);
+        assert_eq!(summary.min_us, 1.0);
+        assert_eq!(summary.max_us, 10_000.0);
+        assert!((summary.mean_us - 5000.5).abs() < 1e-6);
+        for (p, expected) in [(50.0, 5000.0), (90.0, 9000.0), (99.0, 9900.0), (99.9, 9990.0)] {
+            let value = h.percentile(p) as f64 / 1000.0;
+            assert!(value >= expected && value <= expected * (1.0 + 1.0 / SUB_BUCKETS as f64), "p{} = {}", p, value);
+        }
+    }
+
+    #[test]
+    fn merging_equals_recording_everything_at_once() {
+        let mut merged = histogram((0..500).map(|v| v * 37));
+        merged.merge(&histogram((500..2000).map(|v| v * 37)));
+        merged.merge(&LatencyHistogram::new());
+        let all = histogram((0..2000).map(|v| v * 37));
+
+        assert_eq!(merged.buckets, all.buckets);
+        assert_eq!((merged.count, merged.sum, merged.min, merged.max), (all.count, all.sum, all.min, all.max));
+    }
+
+    #[test]
+    fn empty_histogram_has_no_summary() {
+        let mut recorder = LatencyRecorder::new();
+        recorder.record(42);
+        recorder.take();
+        let h = recorder.take();
+        assert!(h.summary().is_none());
+        assert_eq!(h.percentile(99.0), 0);
+    }
+
+    #[test]
+    fn timer_counts_failed_completions() {
+        let mut timer = IoTimer::new();
+        let now = Instant::now();
+        timer.submitted_request(3, 3 * 4096, 100, now, true);
+        timer.completed(now, CompletionStatus::SUCCESS);
+        timer.completed(now, CompletionStatus::WRITE_FAULT);
+        timer.completed(now, CompletionStatus(CompletionStatus::WRITE_FAULT.0 | 0x4000));
+
+        let (failed, rejected, errors) = timer.take_errors();
+        assert_eq!((failed, rejected), (2, 0));
+        assert_eq!(timer.failures(), 2);
+        let rows = errors.rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].count, 2);
+    }
+
+    #[test]
+    fn missing_completions_fail_after_the_timeout() {
+        let mut nvme = SimDevice::new(SimConfig::parse("sim:capacity=64M").unwrap());
+        let mut queue_pair = nvme.create_io_queue_pair(8).unwrap();
+        let mut timer = IoTimer::new();
+        timer.submitted_request(2, 8192, 100, Instant::now(), false);
+
+        let start = Instant::now();
+        timer.complete_within(&mut queue_pair, 2, Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let reads = timer.take_completed(false);
+        assert_eq!((reads.commands, reads.failed, reads.requests), (2, 2, 1));
+        assert_eq!(reads.errors.rows()[0].description, "command aborted by host");
+        assert!(timer.take_op(false).summary().is_none());
+        nvme.delete_io_queue_pair(queue_pair).unwrap();
+    }
+}
@@ -1,71 +1,1038 @@
 use std::error::Error;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-use std::{env, process};
+use std::path::Path;
+use std::time::Duration;
 
-use vroom::HUGE_PAGE_SIZE;
+use clap::Parser;
+use serde_json::json;
 
-use crate::util::{combine_results, IoLog};
+use crate::cli::{CacheArgs, Cli, Command, DeallocateImpactArgs, DeallocatedReadArgs, ErrorPolicy, FillArgs, FlushArgs, FuaArgs, LoadCurveArgs, Op, RangeOp, RangeOpsArgs, RunArgs, SteadyStateArgs, WafArgs, WriteCacheArgs, WritePattern};
+use crate::device::{LbaWindow, Namespace, NvmeBackend};
+use crate::health::HealthSnapshot;
+use crate::job::JobPlan;
+use crate::latency::LatencySummary;
+use crate::kernel::KernelDevice;
+use crate::report::{DeviceIdentity, Format, Reporter, RunResult, Summary};
+use crate::safety::{Guard, WriteCacheRestore};
+use crate::sim::{SimConfig, SimDevice};
+use crate::steady::SteadyStateCriteria;
+use crate::util::{AccessPattern, IoLog, Limit, Throughput, Workload};
 
 mod util;
 mod features;
 mod benchmarks;
+mod cli;
+mod job;
+mod report;
+mod latency;
+mod cliff;
+mod device;
+mod sim;
+mod kernel;
+mod steady;
+mod safety;
+mod verify;
+mod error;
+mod health;
+mod thermal;
 
 pub fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args();
-    args.next();
+    let cli = Cli::parse();
 
-    let pci_addr = match args.next() {
-        Some(arg) => arg,
-        None => {
-            eprintln!("Usage: ./nvmebench <pci bus id>");
-            process::exit(1);
+    if cli.target.starts_with("sim") {
+        run(SimDevice::new(SimConfig::parse(&cli.target)?), cli)
+    } else if KernelDevice::is_path(&cli.target) {
+        run(KernelDevice::open(Path::new(&cli.target), cli.safety.read_only)?, cli)
+    } else {
+        run(vroom::init(&cli.target)?, cli)
+    }
+}
+
+fn run<D: NvmeBackend>(mut nvme: D, cli: Cli) -> Result<(), Box<dyn Error>> {
+    check_backend(&nvme, &cli.command)?;
+    match cli.command {
+        Command::Identify => {
+            let caps = nvme.capabilities();
+            match cli.format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&caps)?),
+                Format::Text | Format::Csv => features::print_capabilities(&caps),
+            }
+            return Ok(());
         }
-    };
+        Command::IdentifyNamespace(args) => {
+            let ids = match args.ns {
+                Some(id) => vec![resolve_namespace(&nvme, Some(id))?.id],
+                None => nvme.namespaces().iter().map(|ns| ns.id).collect(),
+            };
+            let identities = ids.into_iter().map(|id| nvme.identify_namespace(id)).collect::<Result<Vec<_>, _>>()?;
+            match cli.format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&identities)?),
+                Format::Text | Format::Csv => identities.iter().for_each(features::print_namespace_identity),
+            }
+            return Ok(());
+        }
+        Command::Namespaces => {
+            features::print_namespaces(&nvme.namespaces());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let device = DeviceIdentity::new(&cli.target, nvme.controller_info());
+    let mut reporter = Reporter::new(cli.format, cli.output.as_deref(), device, cli.thread_series)?;
+    let mut guard = Guard::new(&cli.safety);
+    if !guard.dry_run {
+        guard.thermal.check(&mut nvme)?;
+    }
+    let mut failure = None;
+
+    match cli.command {
+        Command::Cache(args) => {
+            (nvme, failure) = run_cache_sweep(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::SingleLba(args) => {
+            let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+            let window = guard.prepare(&mut nvme, &ns, args.op.iter().any(|op| op.is_write()))?;
+            for op in args.op {
+                if failure.is_some() {
+                    break;
+                }
+                if guard.dry_run {
+                    guard.plan(format!("single-lba: ns {} {:?} of one random block in {}", ns.id, op, window));
+                    continue;
+                }
+                let throughput;
+                (nvme, throughput) = benchmarks::single_lba(nvme, ns.id, window, op.is_write(), args.loops, args.duration, guard.on_error)?;
+                let params = json!({
+                    "ns": ns.id, "write": op.is_write(),
+                    "loops": args.duration.is_none().then_some(args.loops), "duration_s": args.duration.map(|d| d.as_secs_f64()),
+                });
+                let run = RunResult::from_throughput("single-lba", params, throughput);
+                failure = stopped_on_error(guard.on_error, &run);
+                reporter.add(run)?;
+            }
+        }
+        Command::RandomMatrix(args) => {
+            let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+            let window = guard.prepare(&mut nvme, &ns, args.op.iter().any(|op| op.is_write()))?;
+            for op in args.op {
+                if failure.is_some() {
+                    break;
+                }
+                if guard.dry_run {
+                    guard.plan(format!("random-matrix: ns {} {:?} of one random {} byte range in {}", ns.id, op, vroom::HUGE_PAGE_SIZE, window));
+                    continue;
+                }
+                let cells;
+                (nvme, cells) = benchmarks::full_random_combinations(nvme, ns.id, window, op.is_write(), args.iterations, args.duration, guard.on_error)?;
+                for (random_from, random_to, throughput) in cells {
+                    let params = json!({
+                        "ns": ns.id, "write": op.is_write(),
+                        "iterations": args.duration.is_none().then_some(args.iterations), "duration_s": args.duration.map(|d| d.as_secs_f64()),
+                        "random_from": random_from, "random_to": random_to,
+                    });
+                    let run = RunResult::from_throughput("random-matrix", params, throughput);
+                    failure = failure.or(stopped_on_error(guard.on_error, &run));
+                    reporter.add(run)?;
+                }
+            }
+        }
+        Command::Zipf(args) => {
+            let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+            let window = guard.prepare(&mut nvme, &ns, args.op.iter().any(|op| op.is_write()))?;
+            for op in args.op {
+                if failure.is_some() {
+                    break;
+                }
+                if guard.dry_run {
+                    guard.plan(format!("zipf: ns {} {:?} of one random range of n blocks per point in {}", ns.id, op, window));
+                    continue;
+                }
+                let points;
+                (nvme, points) = benchmarks::zipf_single_action(nvme, ns.id, window, op.is_write(), &args.n.0, &args.s, args.duration, guard.on_error)?;
+                for (n, s, throughput) in points {
+                    let params = json!({ "ns": ns.id, "write": op.is_write(), "n": n, "s": s, "duration_s": args.duration.map(|d| d.as_secs_f64()) });
+                    let run = RunResult::from_throughput("zipf", params, throughput);
+                    failure = failure.or(stopped_on_error(guard.on_error, &run));
+                    reporter.add(run)?;
+                }
+            }
+        }
+        Command::LoadCurve(args) => {
+            (nvme, failure) = run_load_curve(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::Precondition(args) => {
+            let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+            let window = guard.prepare(&mut nvme, &ns, true)?;
+            (nvme, failure) = run_precondition(nvme, &ns, window, &args.fill, args.log.bucket, args.log.log_interval, &guard, &mut reporter)?;
+        }
+        Command::SteadyState(args) => {
+            (nvme, failure) = run_steady_state(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::Waf(args) => {
+            (nvme, failure) = run_waf(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::RangeOps(args) => {
+            (nvme, failure) = run_range_ops(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::DeallocateImpact(args) => {
+            (nvme, failure) = run_deallocate_impact(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::DeallocatedRead(args) => {
+            (nvme, failure) = run_deallocated_read(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::Flush(args) => {
+            (nvme, failure) = run_flush(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::Fua(args) => {
+            (nvme, failure) = run_fua(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::WriteCache(args) => {
+            (nvme, failure) = run_write_cache(nvme, &args, &mut guard, &mut reporter)?;
+        }
+        Command::Run(args) => {
+            let mut plan = JobPlan::load(&args.file)?;
+            plan.validate(&nvme.namespaces(), guard.window_spec())?;
+            for ns in nvme.namespaces() {
+                let jobs: Vec<_> = plan.jobs.iter().filter(|job| job.workload.ns_id == ns.id).collect();
+                if !jobs.is_empty() {
+                    let write = jobs.iter().any(|job| job.workload.write_ratio > 0.0);
+                    guard.prepare(&mut nvme, &ns, write)?;
+                }
+            }
+            (nvme, failure) = run_job_plan(nvme, &plan, &args, &guard, &mut reporter)?;
+        }
+        Command::Identify | Command::IdentifyNamespace(_) | Command::Namespaces => unreachable!(),
+    }
+
+    reporter.finish()?;
 
-    let mut nvme = vroom::init(&pci_addr)?;
-    let mut result = Vec::new();
-
-
-    //features::print_identify_controller_info(&nvme.identify_controller_info);
-
-    let max_io_size_per_thread = 1024*1024*1024 * 64;
-
-    for write in [true] {
-        for io_size_per_request in [8192, 1024*1024] {
-            for queue_depth in [1,32,128] {
-                for num_threads in [1, 8, 32] {
-                    (nvme, result) = benchmarks::determine_cache_size(nvme, max_io_size_per_thread/num_threads, io_size_per_request, write, queue_depth, num_threads as usize);
-                    println!(
-                    "max_io_size_per_thread: {:?}, io_size_per_request: {:?}, write: {:?}, queue_depth: {:?}, num_threads: {:?}",
-                     max_io_size_per_thread / num_threads, io_size_per_request, write, queue_depth, num_threads
-                    );
-                    eprintln!(
-                    "max_io_size_per_thread: {:?}, io_size_per_request: {:?}, write: {:?}, queue_depth: {:?}, num_threads: {:?}",
-                     max_io_size_per_thread / num_threads, io_size_per_request, write, queue_depth, num_threads
-                    );
-                    println!("Result vec: {:?}", combine_results(&result, Duration::from_secs(1)));
-                    println!("\n\n\n\n\n");
-                    sleep(Duration::from_secs(1));
+    match failure {
+        Some(message) => Err(message.into()),
+        None => Ok(()),
+    }
+}
+
+/**
+ * @returns an error message if the sweep stopped at a failed command
+ */
+fn run_cache_sweep<D: NvmeBackend>(mut nvme: D, args: &CacheArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, args.op.iter().any(|op| op.is_write()))?;
+    let (mut result, mut analysis);
+
+    for &op in &args.op {
+        let write = op.is_write();
+        for &io_size_per_request in &args.io_size.0 {
+            for &queue_depth in &args.queue_depth.0 {
+                for &num_threads in &args.threads.0 {
+                    let max_io_size_per_thread = args.max_bytes / num_threads;
+                    let limit = args.duration.map_or(Limit::Bytes(max_io_size_per_thread * num_threads), Limit::Duration);
+                    let workload = Workload {
+                        queue_depth: queue_depth as usize,
+                        num_threads: num_threads as usize,
+                        write_ratio: if write { 1.0 } else { 0.0 },
+                        warmup: args.warmup,
+                        ramp_down: args.ramp_down,
+                        log_interval: args.common.log.log_interval,
+                        on_error: guard.on_error,
+                        max_temperature: guard.thermal.abort_above,
+                        ..Workload::new(ns.id, window, io_size_per_request, limit)
+                    };
+                    if guard.dry_run {
+                        guard.plan(describe("cache", &benchmarks::cache_workload(&ns, &workload)));
+                        continue;
+                    }
+                    let health_before = HealthSnapshot::read(&mut nvme);
+                    (nvme, result, analysis) = benchmarks::determine_cache_size(nvme, &workload)?;
+                    let health = HealthSnapshot::delta(health_before, HealthSnapshot::read(&mut nvme));
+                    let params = json!({
+                        "ns": ns.id,
+                        "max_io_size_per_thread": args.duration.is_none().then_some(max_io_size_per_thread),
+                        "duration_s": args.duration.map(|d| d.as_secs_f64()),
+                        "warmup_s": args.warmup.as_secs_f64(),
+                        "ramp_down_s": args.ramp_down.as_secs_f64(),
+                        "io_size_per_request": io_size_per_request,
+                        "write": write,
+                        "queue_depth": queue_depth,
+                        "num_threads": num_threads,
+                    });
+                    let run = RunResult::from_logs("cache", params, &result, args.common.log.bucket).with_metrics(&analysis).with_health(health);
+                    let failure = stopped_on_error(guard.on_error, &run);
+                    reporter.add(run)?;
+                    if failure.is_some() {
+                        return Ok((nvme, failure));
+                    }
+                    guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
                 }
             }
         }
     }
 
-    
-    
+    Ok((nvme, None))
+}
+
+/// With the stop policy, the message that ends the command after a run with failed commands
+fn stopped_on_error(on_error: ErrorPolicy, run: &RunResult) -> Option<String> {
+    let failed = run.summary.failed_completions;
+    (on_error == ErrorPolicy::Stop && failed > 0).then(|| format!("stopped after {} failed commands in {}", failed, run.benchmark))
+}
+
+/**
+ * Rejects commands that need admin commands or IO commands besides reads and writes up front, if
+ * the backend cannot issue them. Job files are checked by the engine instead.
+ */
+fn check_backend<D: NvmeBackend>(nvme: &D, command: &Command) -> Result<(), Box<dyn Error>> {
+    let (name, admin, range_ops, durable_writes) = match command {
+        Command::IdentifyNamespace(_) => ("identify-namespace", true, vec![], false),
+        Command::Waf(_) => ("waf", true, vec![], false),
+        Command::RangeOps(args) => ("range-ops", false, args.op.clone(), false),
+        Command::DeallocateImpact(args) => ("deallocate-impact", false, vec![args.range_op], false),
+        Command::DeallocatedRead(_) => ("deallocated-read", false, vec![RangeOp::Deallocate], false),
+        Command::Flush(_) => ("flush", false, vec![], true),
+        Command::Fua(_) => ("fua", false, vec![], true),
+        Command::WriteCache(args) => ("write-cache", true, vec![], args.fua),
+        _ => return Ok(()),
+    };
+    if admin && !nvme.supports_admin_commands() {
+        return Err(format!("{} needs admin commands, which this backend cannot issue", name).into());
+    }
+    if let Some(op) = range_ops.into_iter().find(|&op| !nvme.supports_range_op(op)) {
+        return Err(format!("{} needs {} commands, which this backend cannot issue", name, op.name()).into());
+    }
+    if durable_writes && !nvme.supports_durable_writes() {
+        return Err(format!("{} needs FUA writes and flushes, which this backend cannot issue", name).into());
+    }
+    Ok(())
+}
+
+/// The given namespace if it is active, otherwise the first active one
+fn resolve_namespace<D: NvmeBackend>(nvme: &D, ns: Option<u32>) -> Result<Namespace, Box<dyn Error>> {
+    let namespaces = nvme.namespaces();
+    match ns {
+        Some(id) => nvme.namespace(id).ok_or_else(|| {
+            let available: Vec<_> = namespaces.iter().map(|ns| ns.id).collect();
+            format!("namespace {} does not exist, available: {:?}", id, available).into()
+        }),
+        None => namespaces.first().copied().ok_or_else(|| "the controller has no active namespaces".into()),
+    }
+}
+
+/// LBA range and shape of a workload for dry runs
+fn describe(label: &str, w: &Workload) -> String {
+    let blocks = w.lba_count.expect("dry runs describe workloads with a resolved LBA range");
+    let write = w.range_op.map_or(if w.fua { "FUA write" } else { "write" }, |op| op.name());
+    let op = match w.write_ratio {
+        r if r == 0.0 => "read".to_string(),
+        r if r == 1.0 => write.to_string(),
+        r => format!("{}% read, {}", (1.0 - r) * 100.0, write),
+    };
+    format!("{}: ns {} {} {} {:?}, {} threads at queue depth {}, {} byte requests",
+        label, w.ns_id, op, LbaWindow { first: w.lba_start, blocks }, w.pattern, w.num_threads, w.queue_depth, w.io_size)
+}
+
+/// Write ratio of an --op / --read-percent pair
+fn write_ratio(op: Op, read_percent: Option<f64>) -> Result<f64, Box<dyn Error>> {
+    match read_percent {
+        Some(p) if (0.0..=100.0).contains(&p) => Ok(1.0 - p / 100.0),
+        Some(p) => Err(format!("read_percent {} is not between 0 and 100", p).into()),
+        None if op.is_write() => Ok(1.0),
+        None => Ok(0.0),
+    }
+}
+
+/**
+ * @returns an error message if a step stopped at a failed command
+ */
+fn run_load_curve<D: NvmeBackend>(mut nvme: D, args: &LoadCurveArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let write_ratio = write_ratio(args.op, args.read_percent)?;
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, write_ratio > 0.0)?;
+    let workload = Workload {
+        queue_depth: args.queue_depth,
+        num_threads: args.threads,
+        write_ratio,
+        pattern: if args.sequential { AccessPattern::Sequential } else { AccessPattern::Random },
+        warmup: args.warmup,
+        log_interval: args.common.log.log_interval,
+        on_error: guard.on_error,
+        max_temperature: guard.thermal.abort_above,
+        ..Workload::new(ns.id, window, args.io_size, Limit::Duration(args.duration))
+    };
+
+    if guard.dry_run {
+        guard.plan(describe("load-curve", &workload));
+        return Ok((nvme, None));
+    }
+    let mut failure = None;
+    let (nvme, points) = benchmarks::load_curve(nvme, &workload, args.arrivals, &args.percent, args.common.cooldown, guard.thermal)?;
+    let saturation_iops = Throughput::from_logs(&points[0].1).iops();
+    for (offered_iops, results) in points {
+        let params = json!({
+            "ns": ns.id,
+            "io_size": args.io_size,
+            "queue_depth": args.queue_depth,
+            "num_threads": args.threads,
+            "read_percent": (1.0 - write_ratio) * 100.0,
+            "sequential": args.sequential,
+            "duration_s": args.duration.as_secs_f64(),
+            "warmup_s": args.warmup.as_secs_f64(),
+            "arrivals": offered_iops.map(|_| args.arrivals),
+            "offered_iops": offered_iops,
+            "offered_percent": offered_iops.map(|offered| offered / saturation_iops * 100.0),
+        });
+        let run = RunResult::from_logs("load-curve", params, &results, args.common.log.bucket);
+        failure = failure.or(stopped_on_error(guard.on_error, &run));
+        reporter.add(run)?;
+    }
+
+    Ok((nvme, failure))
+}
+
+fn run_precondition<D: NvmeBackend>(mut nvme: D, ns: &Namespace, window: LbaWindow, fill: &FillArgs, bucket: Duration, log_interval: Duration, guard: &Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    if guard.dry_run {
+        guard.plan(format!("precondition: ns {} write {} sequentially {} times, then {} times its size randomly", ns.id, window, fill.seq_fills, fill.random_fills));
+        return Ok((nvme, None));
+    }
+    let mut failure = None;
+    let stages;
+    (nvme, stages) = benchmarks::precondition(nvme, ns.id, window, fill.seq_fills, fill.random_fills, fill.fill_queue_depth, fill.fill_threads, log_interval, guard.on_error, guard.thermal.abort_above)?;
+    for (pass, (stage, results)) in stages.iter().enumerate() {
+        let params = json!({
+            "ns": ns.id, "stage": stage, "pass": pass,
+            "queue_depth": fill.fill_queue_depth, "num_threads": fill.fill_threads,
+        });
+        let run = RunResult::from_logs("precondition", params, results, bucket);
+        failure = failure.or(stopped_on_error(guard.on_error, &run));
+        reporter.add(run)?;
+    }
+    Ok((nvme, failure))
+}
+
+/**
+ * Reports every round and, only if the rounds converged, the steady-state result over the window
+ * @returns an error message if the rounds did not converge
+ */
+fn run_steady_state<D: NvmeBackend>(mut nvme: D, args: &SteadyStateArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let write_ratio = write_ratio(args.op, args.read_percent)?;
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    if args.window < 2 || args.max_rounds < args.window {
+        return Err(format!("window must be at least 2 and at most max_rounds ({})", args.max_rounds).into());
+    }
+    let window = guard.prepare(&mut nvme, &ns, write_ratio > 0.0 || !args.skip_precondition)?;
+    if !args.skip_precondition {
+        let failure;
+        (nvme, failure) = run_precondition(nvme, &ns, window, &args.fill, args.log.bucket, args.log.log_interval, guard, reporter)?;
+        if failure.is_some() {
+            return Ok((nvme, failure));
+        }
+    }
+
+    let workload = Workload {
+        queue_depth: args.queue_depth,
+        num_threads: args.threads,
+        write_ratio,
+        pattern: if args.sequential { AccessPattern::Sequential } else { AccessPattern::Random },
+        log_interval: args.log.log_interval,
+        on_error: guard.on_error,
+        max_temperature: guard.thermal.abort_above,
+        ..Workload::new(ns.id, window, args.io_size, Limit::Duration(args.round))
+    };
+    let criteria = SteadyStateCriteria {
+        window: args.window,
+        max_excursion: args.max_excursion / 100.0,
+        max_slope_excursion: args.max_slope_excursion / 100.0,
+    };
+
+    if guard.dry_run {
+        guard.plan(describe("steady-state", &workload));
+        return Ok((nvme, None));
+    }
+    let (nvme, rounds, analysis) = benchmarks::steady_state(nvme, &workload, &criteria, args.max_rounds)?;
+    let params = json!({
+        "ns": ns.id,
+        "io_size": args.io_size,
+        "queue_depth": args.queue_depth,
+        "num_threads": args.threads,
+        "read_percent": (1.0 - write_ratio) * 100.0,
+        "sequential": args.sequential,
+        "round_s": args.round.as_secs_f64(),
+    });
+    let mut failure = None;
+    for (round, results) in rounds.iter().enumerate() {
+        let mut params = params.clone();
+        params["round"] = json!(round);
+        let mut run = RunResult::from_logs("steady-state-round", params, results, args.log.bucket);
+        if round + 1 == rounds.len() {
+            run = run.with_metrics(&analysis);
+        }
+        failure = failure.or(stopped_on_error(guard.on_error, &run));
+        reporter.add(run)?;
+    }
+    if failure.is_some() {
+        return Ok((nvme, failure));
+    }
+
+    if !analysis.converged {
+        return Ok((nvme, Some(format!("no steady state within {} rounds, see the steady-state-round results", rounds.len()))));
+    }
+    let first_round = analysis.window.as_ref().unwrap().first_round;
+    let mut steady: Vec<Vec<IoLog>> = vec![Vec::new(); args.threads];
+    for results in &rounds[first_round..] {
+        for (thread, logs) in results.iter().enumerate() {
+            steady[thread].extend(logs.iter().cloned());
+        }
+    }
+    let mut params = params;
+    params["rounds"] = json!(rounds.len());
+    reporter.add(RunResult::from_logs("steady-state", params, &steady, args.log.bucket).with_metrics(&analysis))?;
+
+    Ok((nvme, None))
+}
+
+/**
+ * Writes with every pattern and reports the write amplification of each run from the health delta
+ * of the device. Devices without media counters are flagged and get no WAF.
+ * @returns an error message if a run stopped at a failed command
+ */
+fn run_waf<D: NvmeBackend>(mut nvme: D, args: &WafArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, true)?;
+    if !guard.dry_run {
+        nvme.smart_log()?;
+    }
+
+    for (i, &pattern) in args.pattern.iter().enumerate() {
+        let workload = Workload {
+            queue_depth: args.queue_depth,
+            num_threads: args.threads,
+            write_ratio: 1.0,
+            pattern: match pattern {
+                WritePattern::Sequential => AccessPattern::Sequential,
+                WritePattern::Random => AccessPattern::Random,
+                WritePattern::Zipf => AccessPattern::Zipf(args.zipf_s),
+            },
+            log_interval: args.common.log.log_interval,
+            on_error: guard.on_error,
+            max_temperature: guard.thermal.abort_above,
+            ..Workload::new(ns.id, window, args.io_size, args.bytes.map_or(Limit::Duration(args.duration), Limit::Bytes))
+        };
+        if guard.dry_run {
+            guard.plan(describe("waf", &workload));
+            continue;
+        }
+        if i > 0 {
+            guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+        }
 
-    /*
-    nvme = benchmarks::full_random_combinations(nvme);
-    println!("");
-    nvme = benchmarks::single_lba(nvme, true);
-    nvme = benchmarks::single_lba(nvme, false);
-    println!("");
-    nvme = benchmarks::zipf_single_action(nvme, true);
-    nvme = benchmarks::zipf_single_action(nvme, false); */
+        let (results, health);
+        (nvme, results, _, health) = benchmarks::run_workloads(nvme, std::slice::from_ref(&workload))?;
+        let name = format!("{:?}", pattern).to_lowercase();
+        let media_counters = health.as_ref().is_some_and(|h| h.media_bytes_written.is_some());
+        if !media_counters {
+            eprintln!("the device does not expose media write counters (OCP SMART / Health Information Extended log), no WAF for {} writes", name);
+        }
+        let params = json!({
+            "ns": ns.id,
+            "pattern": name,
+            "zipf_s": (pattern == WritePattern::Zipf).then_some(args.zipf_s),
+            "io_size": args.io_size,
+            "queue_depth": args.queue_depth,
+            "num_threads": args.threads,
+            "duration_s": args.bytes.is_none().then_some(args.duration.as_secs_f64()),
+            "bytes": args.bytes,
+        });
+        let metrics = json!({
+            "write_amplification": health.as_ref().and_then(|h| h.write_amplification),
+            "media_counters": media_counters,
+        });
+        let run = RunResult::from_logs("waf", params, &results[0], args.common.log.bucket).with_metrics(metrics).with_health(health);
+        let failure = stopped_on_error(guard.on_error, &run);
+        reporter.add(run)?;
+        if failure.is_some() {
+            return Ok((nvme, failure));
+        }
+    }
 
+    Ok((nvme, None))
+}
 
+/// Sizes a range operation can cover, whole blocks
+fn check_range_size(ns: &Namespace, range_size: u64) -> Result<(), Box<dyn Error>> {
+    if range_size == 0 || range_size % ns.block_size != 0 {
+        return Err(format!("range size {} is not a multiple of the block size {} of namespace {}", range_size, ns.block_size, ns.id).into());
+    }
     Ok(())
 }
 
+/// Relative change of a run compared to its baseline, None where the baseline has no value
+fn change_metrics(baseline: &Summary, run: &Summary) -> serde_json::Value {
+    let change = |base: f64, value: f64| (base > 0.0).then(|| (value / base - 1.0) * 100.0);
+    let latency = |summary: &Summary, percentile: fn(&LatencySummary) -> f64| summary.latency.as_ref().map(percentile);
+    let latency_change = |percentile: fn(&LatencySummary) -> f64| latency(baseline, percentile).zip(latency(run, percentile)).and_then(|(base, value)| change(base, value));
+    json!({
+        "baseline_iops": baseline.iops,
+        "iops_change_percent": change(baseline.iops, run.iops),
+        "mean_latency_change_percent": latency_change(|l| l.mean_us),
+        "p99_latency_change_percent": latency_change(|l| l.p99_us),
+        "p99_9_latency_change_percent": latency_change(|l| l.p99_9_us),
+    })
+}
+
+/**
+ * Sweeps range operation, range size and queue depth, every point issues the operation on the
+ * window for the duration
+ * @returns an error message if a point stopped at a failed command
+ */
+fn run_range_ops<D: NvmeBackend>(mut nvme: D, args: &RangeOpsArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    for &range_size in &args.range_size.0 {
+        check_range_size(&ns, range_size)?;
+    }
+    let window = guard.prepare(&mut nvme, &ns, true)?;
+    let mut first = true;
+
+    for &op in &args.op {
+        for &range_size in &args.range_size.0 {
+            for &queue_depth in &args.queue_depth.0 {
+                let workload = Workload {
+                    queue_depth: queue_depth as usize,
+                    num_threads: args.threads,
+                    write_ratio: 1.0,
+                    pattern: if args.sequential { AccessPattern::Sequential } else { AccessPattern::Random },
+                    range_op: Some(op),
+                    log_interval: args.common.log.log_interval,
+                    on_error: guard.on_error,
+                    max_temperature: guard.thermal.abort_above,
+                    ..Workload::new(ns.id, window, range_size, Limit::Duration(args.duration))
+                };
+                if guard.dry_run {
+                    guard.plan(describe("range-ops", &workload));
+                    continue;
+                }
+                if !first {
+                    guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+                }
+                first = false;
+
+                let results;
+                (nvme, results) = benchmarks::run_workload(nvme, &workload)?;
+                let params = json!({
+                    "ns": ns.id,
+                    "op": op.name(),
+                    "range_size": range_size,
+                    "queue_depth": queue_depth,
+                    "num_threads": args.threads,
+                    "sequential": args.sequential,
+                    "duration_s": args.duration.as_secs_f64(),
+                });
+                let run = RunResult::from_logs("range-ops", params, &results, args.common.log.bucket);
+                let failure = stopped_on_error(guard.on_error, &run);
+                reporter.add(run)?;
+                if failure.is_some() {
+                    return Ok((nvme, failure));
+                }
+            }
+        }
+    }
+
+    Ok((nvme, None))
+}
+
+/**
+ * Runs the foreground workload on the first half of the window alone and then next to random range
+ * operations on the second half, which should hold data (e.g. after precondition) for deallocates
+ * to have work to do. The foreground run next to them reports its change to the baseline.
+ * @returns an error message if a run stopped at a failed command
+ */
+fn run_deallocate_impact<D: NvmeBackend>(mut nvme: D, args: &DeallocateImpactArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let write_ratio = write_ratio(args.op, args.read_percent)?;
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    check_range_size(&ns, args.range_size)?;
+    let window = guard.prepare(&mut nvme, &ns, true)?;
+    let half = window.blocks / 2;
+    if half * ns.block_size < args.io_size.max(args.range_size) {
+        return Err(format!("{} is too small to split into a foreground and a background half", window).into());
+    }
+
+    let foreground = Workload {
+        queue_depth: args.queue_depth,
+        num_threads: args.threads,
+        write_ratio,
+        pattern: AccessPattern::Random,
+        log_interval: args.common.log.log_interval,
+        on_error: guard.on_error,
+        max_temperature: guard.thermal.abort_above,
+        ..Workload::new(ns.id, LbaWindow { first: window.first, blocks: half }, args.io_size, Limit::Duration(args.duration))
+    };
+    let background = Workload {
+        lba_start: window.first + half,
+        io_size: args.range_size,
+        queue_depth: args.range_queue_depth,
+        num_threads: 1,
+        write_ratio: 1.0,
+        range_op: Some(args.range_op),
+        ..foreground.clone()
+    };
+    if guard.dry_run {
+        guard.plan(describe("deallocate-impact baseline", &foreground));
+        guard.plan(format!("{}, next to {}", describe("deallocate-impact", &foreground), describe("background", &background)));
+        return Ok((nvme, None));
+    }
+
+    let params = json!({
+        "ns": ns.id,
+        "io_size": args.io_size,
+        "queue_depth": args.queue_depth,
+        "num_threads": args.threads,
+        "read_percent": (1.0 - write_ratio) * 100.0,
+        "range_op": args.range_op.name(),
+        "range_size": args.range_size,
+        "range_queue_depth": args.range_queue_depth,
+        "duration_s": args.duration.as_secs_f64(),
+    });
+    let with_role = |role: &str| {
+        let mut params = params.clone();
+        params["role"] = json!(role);
+        params
+    };
+
+    let (results, health);
+    (nvme, results, _, health) = benchmarks::run_workloads(nvme, std::slice::from_ref(&foreground))?;
+    let baseline = RunResult::from_logs("deallocate-impact", with_role("baseline"), &results[0], args.common.log.bucket).with_health(health);
+    let baseline_summary = baseline.summary.clone();
+    let failure = stopped_on_error(guard.on_error, &baseline);
+    reporter.add(baseline)?;
+    if failure.is_some() {
+        return Ok((nvme, failure));
+    }
+    guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+
+    let (results, health);
+    (nvme, results, _, health) = benchmarks::run_workloads(nvme, &[foreground, background])?;
+    let run = RunResult::from_logs("deallocate-impact", with_role("foreground"), &results[0], args.common.log.bucket).with_health(health.clone());
+    let metrics = change_metrics(&baseline_summary, &run.summary);
+    let run = run.with_metrics(metrics);
+    let background = RunResult::from_logs("deallocate-impact", with_role("background"), &results[1], args.common.log.bucket).with_health(health);
+    let failure = stopped_on_error(guard.on_error, &run).or(stopped_on_error(guard.on_error, &background));
+    reporter.add(run)?;
+    reporter.add(background)?;
+
+    Ok((nvme, failure))
+}
+
+/**
+ * Writes one region of the window, deallocates the region after it and then reads both randomly
+ * for the duration. The read of the deallocated region reports its change to the written one.
+ * @returns an error message if a run stopped at a failed command
+ */
+fn run_deallocated_read<D: NvmeBackend>(mut nvme: D, args: &DeallocatedReadArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    check_range_size(&ns, args.region)?;
+    let window = guard.prepare(&mut nvme, &ns, true)?;
+    let region = args.region / ns.block_size;
+    if region * 2 > window.blocks || args.region < args.io_size {
+        return Err(format!("two regions of {} bytes do not fit {}, or are smaller than one io", args.region, window).into());
+    }
+    // the fills cover the region once with large sequential requests
+    let fill_size = (1 << 20).min(args.region);
+
+    let read = Workload {
+        queue_depth: args.queue_depth,
+        num_threads: args.threads,
+        pattern: AccessPattern::Random,
+        log_interval: args.common.log.log_interval,
+        on_error: guard.on_error,
+        max_temperature: guard.thermal.abort_above,
+        ..Workload::new(ns.id, LbaWindow { first: window.first, blocks: region }, args.io_size, Limit::Duration(args.duration))
+    };
+    let write = Workload {
+        io_size: fill_size - fill_size % ns.block_size,
+        queue_depth: 32,
+        num_threads: 1,
+        write_ratio: 1.0,
+        pattern: AccessPattern::Sequential,
+        limit: Limit::Bytes(args.region),
+        ..read.clone()
+    };
+    let deallocate = Workload { lba_start: window.first + region, range_op: Some(RangeOp::Deallocate), ..write.clone() };
+    let stages = [
+        ("write", write),
+        ("deallocate", deallocate),
+        ("written", read.clone()),
+        ("deallocated", Workload { lba_start: window.first + region, ..read }),
+    ];
+    if guard.dry_run {
+        stages.iter().for_each(|(stage, workload)| guard.plan(describe(&format!("deallocated-read {}", stage), workload)));
+        return Ok((nvme, None));
+    }
+
+    let mut written = None;
+    for (i, (stage, workload)) in stages.iter().enumerate() {
+        if i > 0 {
+            guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+        }
+        let results;
+        (nvme, results) = benchmarks::run_workload(nvme, workload)?;
+        let params = json!({
+            "ns": ns.id,
+            "stage": stage,
+            "region": args.region,
+            "io_size": workload.io_size,
+            "queue_depth": workload.queue_depth,
+            "num_threads": workload.num_threads,
+            "duration_s": (workload.write_ratio == 0.0).then_some(args.duration.as_secs_f64()),
+        });
+        let mut run = RunResult::from_logs("deallocated-read", params, &results, args.common.log.bucket);
+        match *stage {
+            "written" => written = Some(run.summary.clone()),
+            "deallocated" => {
+                let metrics = change_metrics(written.as_ref().unwrap(), &run.summary);
+                run = run.with_metrics(metrics);
+            }
+            _ => {}
+        }
+        let failure = stopped_on_error(guard.on_error, &run);
+        reporter.add(run)?;
+        if failure.is_some() {
+            return Ok((nvme, failure));
+        }
+    }
 
+    Ok((nvme, None))
+}
+
+/**
+ * Writes each buffered size sequentially and times a Flush right after, repeatedly
+ * @returns an error message if a flush or the writes before it failed
+ */
+fn run_flush<D: NvmeBackend>(mut nvme: D, args: &FlushArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, args.buffered.0.iter().any(|&bytes| bytes > 0))?;
+    if let Some(&bytes) = args.buffered.0.iter().find(|&&bytes| bytes > window.blocks * ns.block_size) {
+        return Err(format!("{} buffered bytes do not fit {}", bytes, window).into());
+    }
+    if !nvme.capabilities().volatile_write_cache {
+        eprintln!("the controller reports no volatile write cache, the flushes have nothing to write back");
+    }
+
+    for (i, &buffered) in args.buffered.0.iter().enumerate() {
+        let workload = Workload {
+            queue_depth: args.queue_depth,
+            num_threads: args.threads,
+            write_ratio: 1.0,
+            log_interval: args.common.log.log_interval,
+            on_error: guard.on_error,
+            max_temperature: guard.thermal.abort_above,
+            ..Workload::new(ns.id, window, args.io_size, Limit::Bytes(buffered))
+        };
+        if guard.dry_run {
+            match buffered {
+                0 => guard.plan(format!("flush: ns {} {} flushes of an idle cache", ns.id, args.repeat)),
+                _ => guard.plan(format!("{}, {} bytes followed by a flush, {} times", describe("flush", &workload), buffered, args.repeat)),
+            }
+            continue;
+        }
+        if i > 0 {
+            guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+        }
+
+        let (rounds, throughput);
+        (nvme, rounds, throughput) = benchmarks::flush_after_writes(nvme, &workload, args.repeat)?;
+        let writes: Vec<Vec<IoLog>> = rounds.into_iter().flatten().collect();
+        let params = json!({
+            "ns": ns.id,
+            "buffered": buffered,
+            "io_size": args.io_size,
+            "queue_depth": args.queue_depth,
+            "num_threads": args.threads,
+            "repeat": args.repeat,
+        });
+        let write_summary = (buffered > 0).then(|| RunResult::from_logs("flush", params.clone(), &writes, args.common.log.bucket).summary);
+        let run = RunResult::from_throughput("flush", params, throughput).with_metrics(json!({ "buffered_writes": write_summary }));
+        let failure = stopped_on_error(guard.on_error, &run)
+            .or_else(|| write_summary.filter(|s| guard.on_error == ErrorPolicy::Stop && s.failed_completions > 0).map(|s| format!("stopped after {} failed writes in flush", s.failed_completions)));
+        reporter.add(run)?;
+        if failure.is_some() {
+            return Ok((nvme, failure));
+        }
+    }
+
+    Ok((nvme, None))
+}
+
+/**
+ * Runs normal and FUA writes at every queue depth, the FUA run reports its change to the normal one
+ * @returns an error message if a run stopped at a failed command
+ */
+fn run_fua<D: NvmeBackend>(mut nvme: D, args: &FuaArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, true)?;
+    let mut first = true;
+
+    for &queue_depth in &args.queue_depth.0 {
+        let mut normal = None;
+        for fua in [false, true] {
+            let workload = Workload {
+                queue_depth: queue_depth as usize,
+                num_threads: args.threads,
+                write_ratio: 1.0,
+                pattern: if args.sequential { AccessPattern::Sequential } else { AccessPattern::Random },
+                fua,
+                log_interval: args.common.log.log_interval,
+                on_error: guard.on_error,
+                max_temperature: guard.thermal.abort_above,
+                ..Workload::new(ns.id, window, args.io_size, Limit::Duration(args.duration))
+            };
+            if guard.dry_run {
+                guard.plan(describe("fua", &workload));
+                continue;
+            }
+            if !first {
+                guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+            }
+            first = false;
+
+            let results;
+            (nvme, results) = benchmarks::run_workload(nvme, &workload)?;
+            let params = json!({
+                "ns": ns.id,
+                "fua": fua,
+                "io_size": args.io_size,
+                "queue_depth": queue_depth,
+                "num_threads": args.threads,
+                "sequential": args.sequential,
+                "duration_s": args.duration.as_secs_f64(),
+            });
+            let mut run = RunResult::from_logs("fua", params, &results, args.common.log.bucket);
+            match &normal {
+                Some(normal) => {
+                    let metrics = change_metrics(normal, &run.summary);
+                    run = run.with_metrics(metrics);
+                }
+                None => normal = Some(run.summary.clone()),
+            }
+            let failure = stopped_on_error(guard.on_error, &run);
+            reporter.add(run)?;
+            if failure.is_some() {
+                return Ok((nvme, failure));
+            }
+        }
+    }
+
+    Ok((nvme, None))
+}
+
+/**
+ * Runs the workload once per setting of the volatile write cache and restores the original setting
+ * afterwards. Runs after the first report their change to it. If a run fails the device is lost,
+ * so the error names the setting the cache was left in.
+ * @returns an error message if a run stopped at a failed command
+ */
+fn run_write_cache<D: NvmeBackend>(mut nvme: D, args: &WriteCacheArgs, guard: &mut Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let write_ratio = write_ratio(args.op, args.read_percent)?;
+    let ns = resolve_namespace(&nvme, args.namespace.ns)?;
+    let window = guard.prepare(&mut nvme, &ns, write_ratio > 0.0)?;
+    if !nvme.capabilities().volatile_write_cache {
+        return Err("the controller reports no volatile write cache".into());
+    }
+    let workload = Workload {
+        queue_depth: args.queue_depth,
+        num_threads: args.threads,
+        write_ratio,
+        pattern: if args.sequential { AccessPattern::Sequential } else { AccessPattern::Random },
+        fua: args.fua,
+        log_interval: args.common.log.log_interval,
+        on_error: guard.on_error,
+        max_temperature: guard.thermal.abort_above,
+        ..Workload::new(ns.id, window, args.io_size, Limit::Duration(args.duration))
+    };
+    if guard.dry_run {
+        for state in &args.state {
+            guard.plan(describe(&format!("write-cache {}", state.name()), &workload));
+        }
+        guard.plan("write-cache: restore the original volatile write cache setting");
+        return Ok((nvme, None));
+    }
+
+    // restores the original setting also when a run fails or reporting it does
+    let mut device = WriteCacheRestore::new(nvme)?;
+    let mut first = None;
+    let mut failure = None;
+    for (i, state) in args.state.iter().enumerate() {
+        if i > 0 {
+            guard.thermal.cool_down(&mut device, args.common.cooldown)?;
+        }
+        device.set_write_cache(state.enabled())?;
+        let results;
+        (device, results) = benchmarks::run_workload(device, &workload)?;
+        let params = json!({
+            "ns": ns.id,
+            "write_cache": state.name(),
+            "io_size": args.io_size,
+            "queue_depth": args.queue_depth,
+            "num_threads": args.threads,
+            "read_percent": (1.0 - write_ratio) * 100.0,
+            "fua": args.fua,
+            "sequential": args.sequential,
+            "duration_s": args.duration.as_secs_f64(),
+        });
+        let mut run = RunResult::from_logs("write-cache", params, &results, args.common.log.bucket);
+        match &first {
+            Some(first) => {
+                let metrics = change_metrics(first, &run.summary);
+                run = run.with_metrics(metrics);
+            }
+            None => first = Some(run.summary.clone()),
+        }
+        failure = stopped_on_error(guard.on_error, &run);
+        reporter.add(run)?;
+        if failure.is_some() {
+            break;
+        }
+    }
+
+    Ok((device.finish()?, failure))
+}
+
+/**
+ * @returns an error message if verified jobs found bad blocks or a job stopped at a failed command
+ */
+fn run_job_plan<D: NvmeBackend>(mut nvme: D, plan: &JobPlan, args: &RunArgs, guard: &Guard, reporter: &mut Reporter) -> Result<(D, Option<String>), Box<dyn Error>> {
+    let (mut results, mut reports, mut health);
+    let mut failed = Vec::new();
+
+    for batch in plan.batches() {
+        if guard.dry_run {
+            batch.iter().for_each(|job| guard.plan(describe(&job.name, &job.workload)));
+            continue;
+        }
+        let workloads: Vec<_> = batch.iter().map(|job| Workload { log_interval: args.common.log.log_interval, on_error: job.on_error.unwrap_or(guard.on_error), max_temperature: guard.thermal.abort_above, ..job.workload.clone() }).collect();
+        for repetition in 0..batch[0].repeat {
+            (nvme, results, reports, health) = benchmarks::run_workloads(nvme, &workloads)?;
+            let mut stopped = None;
+            for (((job, workload), result), report) in batch.iter().zip(&workloads).zip(&results).zip(reports) {
+                let mut params = job.params();
+                params["repetition"] = json!(repetition);
+                let mut run = RunResult::from_logs("workload", params, result, args.common.log.bucket).with_health(health.clone());
+                if let Some(report) = report {
+                    if report.failed_blocks() > 0 {
+                        failed.push(format!("{}: {} bad blocks", job.name, report.failed_blocks()));
+                    }
+                    if report.unreadable > 0 {
+                        failed.push(format!("{}: {} blocks could not be read back", job.name, report.unreadable));
+                    }
+                    run = run.with_metrics(json!({ "verify": report }));
+                }
+                stopped = stopped.or(stopped_on_error(workload.on_error, &run).map(|message| format!("{}: {}", job.name, message)));
+                reporter.add(run)?;
+            }
+            if stopped.is_some() {
+                return Ok((nvme, stopped));
+            }
+            guard.thermal.cool_down(&mut nvme, args.common.cooldown)?;
+        }
+    }
+
+    let failure = (!failed.is_empty()).then(|| format!("verification failed, {}", failed.join(", ")));
+    Ok((nvme, failure))
+}
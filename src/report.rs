@@ -0,0 +1,453 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use vroom::IdentifyControllerInfo;
+
+use crate::features::ascii_to_string;
+use crate::health::HealthDelta;
+use crate::latency::{ErrorRow, LatencyHistogram, LatencySummary};
+use crate::util::{bucket_count, bucket_latencies, measured_logs, measured_span, select_op, spread_logs, IoLog, Phase, Throughput};
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceIdentity {
+    pub target: String,
+    pub vendor_id: u16,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+}
+
+impl DeviceIdentity {
+    pub fn new(target: &str, info: &IdentifyControllerInfo) -> DeviceIdentity {
+        DeviceIdentity {
+            target: target.to_string(),
+            vendor_id: info.vid,
+            model: ascii_to_string(&info.model_number),
+            serial: ascii_to_string(&info.serial_number),
+            firmware: ascii_to_string(&info.firmware_revision),
+        }
+    }
+}
+
+/// One bucket of a series, start is relative to the first measured IO of the run
+#[derive(Serialize, Clone, Debug)]
+pub struct Bucket {
+    pub start_s: f64,
+    pub actions: f64,
+    pub bytes: f64,
+    pub iops: f64,
+    pub mib_per_s: f64,
+    pub latency: Option<LatencySummary>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Spread {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub stddev: f64,
+}
+
+impl Spread {
+    fn of(values: &[f64]) -> Option<Spread> {
+        if values.is_empty() {
+            return None;
+        }
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+        Some(Spread {
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            mean,
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            stddev: variance.sqrt(),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Summary {
+    pub duration_s: f64,
+    pub actions: u64,
+    pub bytes: u64,
+    pub iops: f64,
+    pub mib_per_s: f64,
+    /// throughput spread over the buckets of the series, if there is one
+    pub bucket_mib_per_s: Option<Spread>,
+    pub latency: Option<LatencySummary>,
+    /// requests per request size in bytes
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_sizes: BTreeMap<u64, u64>,
+    /// requests the queue pairs did not accept, they are missing from the results
+    pub rejected_submissions: u64,
+    /// commands that completed with an error status, they are part of the results
+    pub failed_completions: u64,
+    /// the failed completions by operation, LBA and status
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub command_errors: Vec<ErrorRow>,
+}
+
+impl Summary {
+    fn from_throughput(throughput: &Throughput) -> Summary {
+        Summary {
+            duration_s: throughput.duration.as_secs_f64(),
+            actions: throughput.actions,
+            bytes: throughput.bytes,
+            iops: throughput.iops(),
+            mib_per_s: throughput.mib_per_s(),
+            bucket_mib_per_s: None,
+            latency: throughput.latency.summary(),
+            io_sizes: throughput.sizes.clone(),
+            rejected_submissions: throughput.rejected,
+            failed_completions: throughput.failed,
+            command_errors: throughput.errors.rows(),
+        }
+    }
+}
+
+/// Summary of the reads or writes of a mixed workload
+#[derive(Serialize, Clone, Debug)]
+pub struct OpSummary {
+    pub op: String,
+    pub summary: Summary,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RunResult {
+    pub benchmark: String,
+    pub params: Map<String, Value>,
+    /// width of the buckets and wall-clock time the first one starts at, if there is a series
+    pub bucket_s: Option<f64>,
+    pub start_unix_s: Option<f64>,
+    pub series: Vec<Bucket>,
+    /// series of every thread, aligned with the combined one, only reported with --thread-series
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub threads: Vec<Vec<Bucket>>,
+    pub summary: Summary,
+    /// per operation type, only for runs that issued both reads and writes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ops: Vec<OpSummary>,
+    /// benchmark specific results, e.g. the detected cache size
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub metrics: Map<String, Value>,
+    /// change of the SMART / Health log of the device over the run, concurrent runs share it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthDelta>,
+}
+
+impl RunResult {
+    /**
+     * @param params has to be a JSON object, e.g. built with serde_json::json!
+     */
+    pub fn from_throughput(benchmark: &str, params: Value, throughput: Throughput) -> RunResult {
+        RunResult {
+            benchmark: benchmark.to_string(),
+            params: into_map(params),
+            bucket_s: None,
+            start_unix_s: None,
+            series: Vec::new(),
+            threads: Vec::new(),
+            metrics: Map::new(),
+            summary: Summary::from_throughput(&throughput),
+            ops: Vec::new(),
+            health: None,
+        }
+    }
+
+    pub fn from_logs(benchmark: &str, params: Value, results: &Vec<Vec<IoLog>>, bucket_duration: Duration) -> RunResult {
+        let mut run = RunResult::from_throughput(benchmark, params, Throughput::from_logs(results));
+        if let Some(span) = measured_span(results) {
+            let num_buckets = bucket_count(span, bucket_duration);
+            let series = |logs: Vec<&IoLog>| bucket_series(
+                spread_logs(logs.iter().copied(), span.0, num_buckets, bucket_duration),
+                bucket_latencies(logs.iter().copied(), span.0, num_buckets, bucket_duration),
+                bucket_duration,
+            );
+            run.series = series(measured_logs(results).collect());
+            run.threads = results.iter()
+                .map(|thread| series(thread.iter().filter(|l| l.phase == Phase::Measure).collect()))
+                .collect();
+            run.bucket_s = Some(bucket_duration.as_secs_f64());
+            run.start_unix_s = Some(unix_time(span.0));
+        }
+        run.summary.bucket_mib_per_s = Spread::of(&run.series.iter().map(|b| b.mib_per_s).collect::<Vec<_>>());
+
+        let has_op = |write: bool| measured_logs(results).any(|l| l.write == write);
+        if has_op(false) && has_op(true) {
+            run.ops = [("read", false), ("write", true)].into_iter()
+                .map(|(op, write)| OpSummary { op: op.to_string(), summary: Summary::from_throughput(&Throughput::from_logs(&select_op(results, write))) })
+                .collect();
+        }
+        run
+    }
+
+    /**
+     * @param metrics has to serialize to a JSON object, its fields are added to the metrics of the run
+     */
+    pub fn with_metrics(mut self, metrics: impl Serialize) -> RunResult {
+        let metrics = serde_json::to_value(metrics).expect("metrics are serializable");
+        self.metrics.extend(into_map(metrics));
+        self
+    }
+
+    pub fn with_health(mut self, health: Option<HealthDelta>) -> RunResult {
+        self.health = health;
+        self
+    }
+}
+
+fn bucket_series(throughput: Vec<(f64, f64)>, latencies: Vec<LatencyHistogram>, bucket_duration: Duration) -> Vec<Bucket> {
+    let width = bucket_duration.as_secs_f64();
+    throughput.into_iter()
+        .zip(latencies)
+        .enumerate()
+        .map(|(i, ((actions, bytes), latency))| Bucket {
+            start_s: i as f64 * width,
+            actions,
+            bytes,
+            iops: actions / width,
+            mib_per_s: bytes / MIB / width,
+            latency: latency.summary(),
+        })
+        .collect()
+}
+
+fn unix_time(instant: Instant) -> f64 {
+    let time = SystemTime::now() - instant.elapsed();
+    time.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+fn into_map(params: Value) -> Map<String, Value> {
+    match params {
+        Value::Object(map) => map,
+        Value::Null => Map::new(),
+        other => panic!("run parameters must be a JSON object, got {}", other),
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub device: DeviceIdentity,
+    pub runs: Vec<RunResult>,
+}
+
+/**
+ * Collects the results of all runs of one invocation. Text is written as soon as a run is added,
+ * JSON and CSV are written as a whole by finish().
+ */
+pub struct Reporter {
+    format: Format,
+    thread_series: bool,
+    out: Box<dyn Write>,
+    report: Report,
+}
+
+impl Reporter {
+    /**
+     * @param output file to write to, stdout if None
+     * @param thread_series keep the per-thread series of the runs
+     */
+    pub fn new(format: Format, output: Option<&Path>, device: DeviceIdentity, thread_series: bool) -> Result<Reporter, Box<dyn Error>> {
+        let out: Box<dyn Write> = match output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        };
+
+        let mut reporter = Reporter { format, thread_series, out, report: Report { device, runs: Vec::new() } };
+        if format == Format::Text {
+            let d = &reporter.report.device;
+            writeln!(reporter.out, "Device {} ({}, SN {}, FW {})", d.target, d.model, d.serial, d.firmware)?;
+        }
+        Ok(reporter)
+    }
+
+    pub fn add(&mut self, mut run: RunResult) -> io::Result<()> {
+        if !self.thread_series {
+            run.threads.clear();
+        }
+        eprintln!("finished {} {}", run.benchmark, format_params(&run.params));
+        if self.format == Format::Text {
+            write_text(&mut self.out, &run)?;
+            self.out.flush()?;
+        }
+        self.report.runs.push(run);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            Format::Text => {}
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut self.out, &self.report)?;
+                writeln!(self.out)?;
+            }
+            Format::Csv => write_csv(&mut self.out, &self.report)?,
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn format_params(params: &Map<String, Value>) -> String {
+    params.iter().map(|(k, v)| format!("{}={}", k, format_value(v))).collect::<Vec<_>>().join(" ")
+}
+
+fn write_latency_line(out: &mut dyn Write, prefix: &str, l: &LatencySummary) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}latency [us]: min {:.1}, mean {:.1}, p50 {:.1}, p90 {:.1}, p99 {:.1}, p99.9 {:.1}, p99.99 {:.1}, max {:.1}",
+        prefix, l.min_us, l.mean_us, l.p50_us, l.p90_us, l.p99_us, l.p99_9_us, l.p99_99_us, l.max_us
+    )
+}
+
+fn write_text(out: &mut dyn Write, run: &RunResult) -> io::Result<()> {
+    let s = &run.summary;
+    writeln!(out, "\n=== {} | {}", run.benchmark, format_params(&run.params))?;
+    writeln!(out, "{:.2} MiB/s, {:.0} IOPS, {} bytes in {:.3} s", s.mib_per_s, s.iops, s.bytes, s.duration_s)?;
+    if let Some(spread) = &s.bucket_mib_per_s {
+        writeln!(out, "per bucket MiB/s: min {:.2}, mean {:.2}, max {:.2}, stddev {:.2}", spread.min, spread.mean, spread.max, spread.stddev)?;
+    }
+    if let Some(l) = &s.latency {
+        write_latency_line(out, "", l)?;
+    }
+    if s.io_sizes.len() > 1 {
+        let sizes: Vec<_> = s.io_sizes.iter().map(|(size, count)| format!("{} x {}", count, size)).collect();
+        writeln!(out, "io sizes [bytes]: {}", sizes.join(", "))?;
+    }
+    if s.rejected_submissions > 0 || s.failed_completions > 0 {
+        writeln!(out, "errors: {} rejected submissions, {} failed completions", s.rejected_submissions, s.failed_completions)?;
+    }
+    for row in &s.command_errors {
+        let lba = row.lba.map_or("other LBAs".to_string(), |lba| format!("LBA {}", lba));
+        writeln!(out, "  {} {}: SCT {:#x} SC {:#04x} {}, {} commands", row.op, lba, row.sct, row.sc, row.description, row.count)?;
+    }
+    for op in &run.ops {
+        let s = &op.summary;
+        writeln!(out, "{}: {:.2} MiB/s, {:.0} IOPS, {} bytes", op.op, s.mib_per_s, s.iops, s.bytes)?;
+        if let Some(l) = &s.latency {
+            write_latency_line(out, &format!("{} ", op.op), l)?;
+        }
+    }
+    for (key, value) in &run.metrics {
+        writeln!(out, "{}: {}", key, format_value(value))?;
+    }
+    if let Some(health) = &run.health {
+        writeln!(out, "health: {}", health.describe())?;
+    }
+    if !run.series.is_empty() {
+        writeln!(out, "{:>10} {:>12} {:>12} {:>12} {:>12} {:>12}", "time [s]", "MiB/s", "IOPS", "p50 [us]", "p99 [us]", "max [us]")?;
+        for bucket in &run.series {
+            let (p50, p99, max) = bucket.latency.as_ref().map_or((0.0, 0.0, 0.0), |l| (l.p50_us, l.p99_us, l.max_us));
+            writeln!(out, "{:>10.3} {:>12.2} {:>12.0} {:>12.1} {:>12.1} {:>12.1}", bucket.start_s, bucket.mib_per_s, bucket.iops, p50, p99, max)?;
+        }
+    }
+    if !run.threads.is_empty() {
+        let header: String = (0..run.threads.len()).map(|t| format!(" {:>12}", format!("t{} MiB/s", t))).collect();
+        writeln!(out, "{:>10}{}", "time [s]", header)?;
+        for (i, bucket) in run.series.iter().enumerate() {
+            let row: String = run.threads.iter().map(|thread| format!(" {:>12.2}", thread[i].mib_per_s)).collect();
+            writeln!(out, "{:>10.3}{}", bucket.start_s, row)?;
+        }
+    }
+    Ok(())
+}
+
+const LATENCY_COLUMNS: [&str; 8] = ["lat_min_us", "lat_mean_us", "lat_p50_us", "lat_p90_us", "lat_p99_us", "lat_p99_9_us", "lat_p99_99_us", "lat_max_us"];
+
+fn latency_fields(latency: &Option<LatencySummary>) -> String {
+    match latency {
+        Some(l) => [l.min_us, l.mean_us, l.p50_us, l.p90_us, l.p99_us, l.p99_9_us, l.p99_99_us, l.max_us].map(|v| v.to_string()).join(","),
+        None => ",".repeat(LATENCY_COLUMNS.len() - 1),
+    }
+}
+
+const HEALTH_COLUMNS: [&str; 7] = ["temperature_before_c", "temperature_after_c", "thermal_throttle_events", "thermal_throttle_s", "host_bytes_read", "host_bytes_written", "media_errors"];
+
+/// The health columns, each with a leading comma
+fn health_fields(health: &Option<HealthDelta>) -> String {
+    match health {
+        Some(h) => {
+            let temperatures = [h.temperature_before_c, h.temperature_after_c].map(|t| format!(",{}", t)).concat();
+            let counters = [h.thermal_throttle_events, h.thermal_throttle_s, h.host_bytes_read, h.host_bytes_written, h.media_errors].map(|v| format!(",{}", v)).concat();
+            temperatures + &counters
+        }
+        None => ",".repeat(HEALTH_COLUMNS.len()),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/**
+ * One row per bucket and one summary row per run, mixed runs get an extra summary_read / summary_write row. Every parameter used by any run gets its own column.
+ * Per-thread buckets have the thread column set, unix_time_s is the wall-clock start of a bucket.
+ * Error rows list the failed completions of a run by op, lba and status, counted in failed_completions.
+ * The health columns of the summary row hold the change of the SMART / Health log over the run.
+ */
+fn write_csv(out: &mut dyn Write, report: &Report) -> io::Result<()> {
+    let keys: BTreeSet<&String> = report.runs.iter().flat_map(|r| r.params.keys()).collect();
+    let metric_keys: BTreeSet<&String> = report.runs.iter().flat_map(|r| r.metrics.keys()).collect();
+
+    let mut header = vec!["run".to_string(), "benchmark".to_string()];
+    header.extend(keys.iter().map(|k| csv_field(k)));
+    header.extend(["record", "thread", "time_s", "unix_time_s", "actions", "bytes", "iops", "mib_per_s", "rejected_submissions", "failed_completions", "op", "lba", "sct", "sc"].map(String::from));
+    header.extend(LATENCY_COLUMNS.map(String::from));
+    header.extend(HEALTH_COLUMNS.map(String::from));
+    header.extend(metric_keys.iter().map(|k| csv_field(k)));
+    writeln!(out, "{}", header.join(","))?;
+
+    for (index, run) in report.runs.iter().enumerate() {
+        let mut prefix = vec![index.to_string(), csv_field(&run.benchmark)];
+        prefix.extend(keys.iter().map(|k| csv_field(&run.params.get(*k).map(format_value).unwrap_or_default())));
+        let prefix = prefix.join(",");
+
+        let unix_time = |bucket: &Bucket| run.start_unix_s.map(|t| (t + bucket.start_s).to_string()).unwrap_or_default();
+        let threads = run.threads.iter().enumerate().map(|(t, series)| (t.to_string(), series));
+        for (thread, series) in std::iter::once((String::new(), &run.series)).chain(threads) {
+            for bucket in series {
+                writeln!(out, "{},bucket,{},{},{},{},{},{},{},,,,,,,{}{}{}", prefix, thread, bucket.start_s, unix_time(bucket), bucket.actions, bucket.bytes, bucket.iops, bucket.mib_per_s, latency_fields(&bucket.latency), health_fields(&None), ",".repeat(metric_keys.len()))?;
+            }
+        }
+        let s = &run.summary;
+        let start = run.start_unix_s.map(|t| t.to_string()).unwrap_or_default();
+        let metrics: String = metric_keys.iter().map(|k| format!(",{}", csv_field(&run.metrics.get(*k).map(format_value).unwrap_or_default()))).collect();
+        writeln!(out, "{},summary,,{},{},{},{},{},{},{},{},,,,,{}{}{}", prefix, s.duration_s, start, s.actions, s.bytes, s.iops, s.mib_per_s, s.rejected_submissions, s.failed_completions, latency_fields(&s.latency), health_fields(&run.health), metrics)?;
+        for op in &run.ops {
+            let s = &op.summary;
+            writeln!(out, "{},summary_{},,{},{},{},{},{},{},{},{},,,,,{}{}{}", prefix, op.op, s.duration_s, start, s.actions, s.bytes, s.iops, s.mib_per_s, s.rejected_submissions, s.failed_completions, latency_fields(&s.latency), health_fields(&None), ",".repeat(metric_keys.len()))?;
+        }
+        for row in &s.command_errors {
+            let lba = row.lba.map(|lba| lba.to_string()).unwrap_or_default();
+            writeln!(out, "{},error,,,,,,,,,{},{},{},{},{},{}{}{}", prefix, row.count, row.op, lba, row.sct, row.sc, latency_fields(&None), health_fields(&None), ",".repeat(metric_keys.len()))?;
+        }
+    }
+    Ok(())
+}
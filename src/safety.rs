@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use crate::cli::{parse_size, ErrorPolicy, RangeOp, SafetyArgs};
+use crate::device::{IoBuffer, IoQueue, LbaWindow, Namespace, NvmeBackend};
+use crate::error::BenchmarkError;
+use crate::features::NamespaceIdentity;
+use crate::thermal::ThermalPolicy;
+use vroom::IdentifyControllerInfo;
+
+/// Bytes read from the start of a namespace, enough for the btrfs superblock at 64 KiB
+const HEAD_BYTES: u64 = 128 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Amount {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Amount {
+    fn parse(s: &str) -> Result<Amount, String> {
+        match s.trim().strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Amount::Percent(p)),
+                _ => Err(format!("invalid percentage '{}'", s)),
+            },
+            None => parse_size(s).map(Amount::Bytes),
+        }
+    }
+
+    fn bytes(self, capacity: u64) -> u64 {
+        match self {
+            Amount::Bytes(bytes) => bytes,
+            Amount::Percent(p) => (capacity as f64 * p / 100.0) as u64,
+        }
+    }
+}
+
+/**
+ * Allowed LBA window as "offset+length" or just "offset" (up to the end of the namespace). Both are
+ * sizes (e.g. "1G+16G") or percentages of the namespace capacity (e.g. "50%+25%").
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LbaWindowSpec {
+    offset: Amount,
+    length: Option<Amount>,
+}
+
+impl LbaWindowSpec {
+    pub fn parse(s: &str) -> Result<LbaWindowSpec, String> {
+        let (offset, length) = match s.split_once('+') {
+            Some((offset, length)) => (offset, Some(Amount::parse(length)?)),
+            None => (s, None),
+        };
+        Ok(LbaWindowSpec { offset: Amount::parse(offset)?, length })
+    }
+
+    /// The offset is rounded up and the length down to whole blocks
+    pub fn resolve(&self, ns: &Namespace) -> Result<LbaWindow, String> {
+        let capacity = ns.blocks * ns.block_size;
+        let first = self.offset.bytes(capacity).div_ceil(ns.block_size);
+        let blocks = match self.length {
+            Some(length) => length.bytes(capacity) / ns.block_size,
+            None => ns.blocks.saturating_sub(first),
+        };
+        let window = LbaWindow { first, blocks };
+        if blocks == 0 || !LbaWindow::whole(ns).contains(first, blocks) {
+            return Err(format!("LBA window {} is empty or exceeds namespace {} with {} blocks", window, ns.id, ns.blocks));
+        }
+        Ok(window)
+    }
+}
+
+/**
+ * Keeps the benchmarks away from data: restricts them to the LBA window, rejects writes in read-only
+ * mode and refuses to write to namespaces that carry a partition table or file system, unless
+ * overridden. In a dry run the commands only print the LBA ranges they would access. With the stop
+ * policy, the first failed command ends the benchmark. The thermal policy keeps runs from starting
+ * on a hot device and aborts them when it overheats.
+ */
+pub struct Guard {
+    window: Option<LbaWindowSpec>,
+    read_only: bool,
+    allow_overwrite: bool,
+    pub dry_run: bool,
+    pub on_error: ErrorPolicy,
+    pub thermal: ThermalPolicy,
+    /// namespaces already checked for signatures
+    checked: HashSet<u32>,
+}
+
+impl Guard {
+    pub fn new(args: &SafetyArgs) -> Guard {
+        Guard {
+            window: args.lba_window,
+            read_only: args.read_only,
+            allow_overwrite: args.allow_overwrite,
+            dry_run: args.dry_run,
+            on_error: args.on_error,
+            thermal: ThermalPolicy { cool_below: args.cool_below, cool_timeout: args.cool_timeout, abort_above: args.abort_above },
+            checked: HashSet::new(),
+        }
+    }
+
+    pub fn window_spec(&self) -> Option<LbaWindowSpec> {
+        self.window
+    }
+
+    pub fn window(&self, ns: &Namespace) -> Result<LbaWindow, String> {
+        self.window.map_or(Ok(LbaWindow::whole(ns)), |spec| spec.resolve(ns))
+    }
+
+    /**
+     * Checks that a command may access the namespace before it issues any IO
+     * @returns the LBA window it has to stay in
+     */
+    pub fn prepare<D: NvmeBackend>(&mut self, nvme: &mut D, ns: &Namespace, write: bool) -> Result<LbaWindow, Box<dyn Error>> {
+        let window = self.window(ns)?;
+        if !write {
+            return Ok(window);
+        }
+        if self.read_only {
+            return Err(format!("namespace {}: writes are not allowed in read-only mode", ns.id).into());
+        }
+        if self.allow_overwrite || self.checked.contains(&ns.id) {
+            return Ok(window);
+        }
+
+        // a namespace only counts as checked once the check passed, so a failed one is repeated
+        let signatures = detect_signatures(nvme, ns)?;
+        if !signatures.is_empty() {
+            let message = format!("namespace {} contains {}, refusing to write without --allow-overwrite", ns.id, signatures.join(", "));
+            if !self.dry_run {
+                return Err(message.into());
+            }
+            println!("dry run: {}", message);
+        }
+        self.checked.insert(ns.id);
+        Ok(window)
+    }
+
+    pub fn plan(&self, line: impl std::fmt::Display) {
+        println!("dry run: {}", line);
+    }
+}
+
+/**
+ * Wraps a device whose volatile write cache setting gets changed. The original setting is restored
+ * by finish, or when the wrapper is dropped on an early return, also when a benchmark failed and
+ * dropped the device with it.
+ */
+pub struct WriteCacheRestore<D: NvmeBackend> {
+    nvme: Option<D>,
+    original: bool,
+}
+
+impl<D: NvmeBackend> WriteCacheRestore<D> {
+    pub fn new(mut nvme: D) -> Result<WriteCacheRestore<D>, BenchmarkError> {
+        let original = nvme.write_cache_enabled()?;
+        Ok(WriteCacheRestore { nvme: Some(nvme), original })
+    }
+
+    /**
+     * Restores the original setting
+     * @returns the device, unless restoring failed
+     */
+    pub fn finish(mut self) -> Result<D, BenchmarkError> {
+        let mut nvme = self.nvme.take().unwrap();
+        nvme.set_write_cache(self.original)?;
+        Ok(nvme)
+    }
+
+    fn device(&self) -> &D {
+        self.nvme.as_ref().unwrap()
+    }
+
+    fn device_mut(&mut self) -> &mut D {
+        self.nvme.as_mut().unwrap()
+    }
+}
+
+impl<D: NvmeBackend> Drop for WriteCacheRestore<D> {
+    fn drop(&mut self) {
+        let Some(nvme) = self.nvme.as_mut() else {
+            return;
+        };
+        if let Err(e) = nvme.set_write_cache(self.original) {
+            let setting = if self.original { "enabled" } else { "disabled" };
+            eprintln!("failed to restore the volatile write cache to {}: {}", setting, e);
+        }
+    }
+}
+
+impl<D: NvmeBackend> NvmeBackend for WriteCacheRestore<D> {
+    type Queue = D::Queue;
+
+    fn namespaces(&self) -> Vec<Namespace> {
+        self.device().namespaces()
+    }
+
+    fn controller_info(&self) -> &IdentifyControllerInfo {
+        self.device().controller_info()
+    }
+
+    fn min_page_size(&self) -> u64 {
+        self.device().min_page_size()
+    }
+
+    fn identify_namespace(&mut self, id: u32) -> Result<NamespaceIdentity, BenchmarkError> {
+        self.device_mut().identify_namespace(id)
+    }
+
+    fn get_log_page(&mut self, log_id: u8, data: &mut [u8]) -> Result<(), BenchmarkError> {
+        self.device_mut().get_log_page(log_id, data)
+    }
+
+    fn supports_range_op(&self, op: RangeOp) -> bool {
+        self.device().supports_range_op(op)
+    }
+
+    fn supports_durable_writes(&self) -> bool {
+        self.device().supports_durable_writes()
+    }
+
+    fn supports_admin_commands(&self) -> bool {
+        self.device().supports_admin_commands()
+    }
+
+    fn reports_completion_status(&self) -> bool {
+        self.device().reports_completion_status()
+    }
+
+    fn get_features(&mut self, fid: u8) -> Result<u32, BenchmarkError> {
+        self.device_mut().get_features(fid)
+    }
+
+    fn set_features(&mut self, fid: u8, value: u32) -> Result<(), BenchmarkError> {
+        self.device_mut().set_features(fid, value)
+    }
+
+    fn create_io_queue_pair(&mut self, len: usize) -> Result<D::Queue, BenchmarkError> {
+        self.device_mut().create_io_queue_pair(len)
+    }
+
+    fn delete_io_queue_pair(&mut self, queue_pair: D::Queue) -> Result<(), BenchmarkError> {
+        self.device_mut().delete_io_queue_pair(queue_pair)
+    }
+}
+
+/**
+ * Reads the start and the last block of the namespace and looks for partition tables and the
+ * superblocks of common file systems and volume managers
+ * @returns a description of every signature found
+ */
+pub fn detect_signatures<D: NvmeBackend>(nvme: &mut D, ns: &Namespace) -> Result<Vec<&'static str>, Box<dyn Error>> {
+    let head_blocks = (HEAD_BYTES / ns.block_size).clamp(1, ns.blocks);
+    let head = read_blocks(nvme, ns, 0, head_blocks)?;
+    let tail = read_blocks(nvme, ns, ns.blocks - 1, 1)?;
+
+    let at = |data: &[u8], offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+    let bs = ns.block_size as usize;
+    let checks: [(&'static str, bool); 11] = [
+        ("an MBR partition table", at(&head, 510, &[0x55, 0xaa])),
+        ("a GPT header", at(&head, bs, b"EFI PART")),
+        ("a backup GPT header", at(&tail, 0, b"EFI PART")),
+        ("an ext2/3/4 file system", at(&head, 1080, &[0x53, 0xef])),
+        ("an XFS file system", at(&head, 0, b"XFSB")),
+        ("a btrfs file system", at(&head, 0x10040, b"_BHRfS_M")),
+        ("an NTFS file system", at(&head, 3, b"NTFS    ")),
+        ("a FAT file system", at(&head, 54, b"FAT") || at(&head, 82, b"FAT32")),
+        ("an LVM physical volume", at(&head, 512 + 24, b"LVM2") || at(&head, 512, b"LABELONE")),
+        ("a LUKS volume", at(&head, 0, b"LUKS\xba\xbe")),
+        ("a swap area", at(&head, 4096 - 10, b"SWAPSPACE2")),
+    ];
+    Ok(checks.into_iter().filter(|c| c.1).map(|c| c.0).collect())
+}
+
+fn read_blocks<D: NvmeBackend>(nvme: &mut D, ns: &Namespace, lba: u64, blocks: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = (blocks * ns.block_size) as usize;
+    let data = nvme.with_queue_pair(512, |queue_pair| {
+        let buffer = queue_pair.allocate_buffer(len)?;
+        let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..len, lba, false);
+        let failed = queue_pair.complete_io(commands);
+        Ok((commands > 0 && failed == 0).then(|| buffer.as_slice().to_vec()))
+    })?;
+    data.ok_or_else(|| format!("namespace {}: reading {} blocks at LBA {} to check for signatures failed", ns.id, blocks, lba).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::sim::{SimConfig, SimDevice};
+
+    fn guard() -> Guard {
+        Guard {
+            window: None,
+            read_only: false,
+            allow_overwrite: false,
+            dry_run: false,
+            on_error: ErrorPolicy::Continue,
+            thermal: ThermalPolicy { cool_below: None, cool_timeout: Duration::ZERO, abort_above: None },
+            checked: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn failed_checks_are_repeated() {
+        let mut nvme = SimDevice::new(SimConfig::parse("sim:capacity=64M,store=true").unwrap());
+        let ns = nvme.namespaces()[0];
+        nvme.with_queue_pair(8, |queue_pair| {
+            let mut buffer = queue_pair.allocate_buffer(ns.block_size as usize)?;
+            buffer.as_mut_slice()[510..512].copy_from_slice(&[0x55, 0xaa]);
+            let commands = queue_pair.submit_io(ns.id, ns.block_size, &buffer, 0..ns.block_size as usize, 0, true);
+            assert_eq!(queue_pair.complete_io(commands), 0);
+            Ok(())
+        }).unwrap();
+
+        let mut guard = guard();
+        assert!(guard.prepare(&mut nvme, &ns, false).is_ok());
+        for _ in 0..2 {
+            let error = guard.prepare(&mut nvme, &ns, true).unwrap_err();
+            assert!(error.to_string().contains("an MBR partition table"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn clean_namespaces_pass() {
+        let mut nvme = SimDevice::new(SimConfig::parse("sim:capacity=64M,store=true").unwrap());
+        let ns = nvme.namespaces()[0];
+        let mut guard = guard();
+        assert_eq!(guard.prepare(&mut nvme, &ns, true).unwrap(), LbaWindow::whole(&ns));
+        assert!(guard.checked.contains(&ns.id));
+    }
+}
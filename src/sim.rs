@@ -0,0 +1,793 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_distr::{Distribution, Exp, LogNormal};
+use vroom::IdentifyControllerInfo;
+
+use crate::cli::{parse_duration, parse_size, RangeOp};
+use crate::device::{ascii_field, CompletionStatus, HostBuffer, IoQueue, Namespace, NvmeBackend};
+use crate::error::BenchmarkError;
+use crate::features::{NamespaceIdentity, IDENTIFY_SIZE, VOLATILE_WRITE_CACHE};
+use crate::health::{OCP_SMART_LOG_GUID, OCP_SMART_LOG_ID, SMART_LOG_ID, SMART_LOG_SIZE};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LatencyDistribution {
+    Constant,
+    Exponential,
+    /// cv is the coefficient of variation (stddev / mean)
+    LogNormal { cv: f64 },
+}
+
+/**
+ * Parameters of the simulated controller. Every command first occupies a transfer pipe shared by
+ * all queues for size / bandwidth and then completes after a latency drawn from the distribution.
+ * Once cache_size bytes have been written, the write bandwidth drops by post_cache_factor.
+ * With store set, written blocks are kept in memory and read back, faults then damages that
+ * fraction of the written blocks to exercise verification. errors is the fraction of commands that
+ * complete with a media error status, independent of store. npwg is reported as preferred write
+ * and deallocate granularity, alignment and as optimal write size of every namespace.
+ * The temperature rises by heat degrees per GiB/s of sustained transfers and cools down towards
+ * temperature with the time constant cooling. Above tmt1 the controller throttles to half its
+ * bandwidth, which shows up as thermal management transitions in the SMART / Health log.
+ * Writes that do not continue the previous write of their queue are amplified by waf on the media,
+ * reported in the OCP extended SMART log unless ocp is off.
+ * Deallocate and Write Zeroes commands occupy the pipe for their range / dealloc_bw and complete
+ * after dealloc_lat respectively write_lat, they never fail. Reads of deallocated LBAs skip the
+ * media and complete after dealloc_read_lat.
+ * With vwc the controller has a volatile write cache, enabled at start. Writes that bypass it, FUA
+ * writes or all writes while it is disabled, take media_write_lat longer. The cache drains to the
+ * media at flush_bw, a Flush waits for the rest.
+ */
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub capacity: u64,
+    pub block_size: u64,
+    /// the capacity is split evenly between namespaces 1..=namespaces
+    pub namespaces: u32,
+    pub read_latency: Duration,
+    pub write_latency: Duration,
+    pub distribution: LatencyDistribution,
+    /// bytes per second
+    pub read_bandwidth: u64,
+    pub write_bandwidth: u64,
+    pub cache_size: u64,
+    pub post_cache_factor: f64,
+    pub max_queues: usize,
+    pub max_queue_len: usize,
+    /// larger requests are split into several commands, vroom uses 8 KiB
+    pub max_transfer: u64,
+    /// reported MDTS in bytes, a power of two of at least 8 KiB or 0 for no limit
+    pub mdts: u64,
+    /// bytes, 0 does not report preferred granularities
+    pub npwg: u64,
+    pub store: bool,
+    pub faults: f64,
+    pub errors: f64,
+    /// idle temperature in degrees Celsius
+    pub temperature: f64,
+    pub heat: f64,
+    pub cooling: Duration,
+    /// throttling temperature, 0 never throttles
+    pub tmt1: f64,
+    pub waf: f64,
+    pub ocp: bool,
+    pub dealloc_latency: Duration,
+    /// LBA bytes per second
+    pub dealloc_bandwidth: u64,
+    pub dealloc_read_latency: Duration,
+    pub vwc: bool,
+    pub media_write_latency: Duration,
+    pub flush_bandwidth: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            capacity: 256 << 30,
+            block_size: 512,
+            namespaces: 1,
+            read_latency: Duration::from_micros(80),
+            write_latency: Duration::from_micros(20),
+            distribution: LatencyDistribution::LogNormal { cv: 0.3 },
+            read_bandwidth: 3 << 30,
+            write_bandwidth: 2 << 30,
+            cache_size: 16 << 30,
+            post_cache_factor: 0.25,
+            max_queues: 64,
+            max_queue_len: 4096,
+            max_transfer: 8192,
+            mdts: 2 << 20,
+            npwg: 0,
+            store: false,
+            faults: 0.0,
+            errors: 0.0,
+            temperature: 35.0,
+            heat: 10.0,
+            cooling: Duration::from_secs(30),
+            tmt1: 0.0,
+            waf: 2.5,
+            ocp: true,
+            dealloc_latency: Duration::from_micros(10),
+            dealloc_bandwidth: 64 << 30,
+            dealloc_read_latency: Duration::from_micros(5),
+            vwc: true,
+            media_write_latency: Duration::from_micros(100),
+            flush_bandwidth: 1 << 30,
+        }
+    }
+}
+
+impl SimConfig {
+    /**
+     * Parses "sim" or "sim:key=value,..." with the keys capacity, block_size, namespaces, read_lat,
+     * write_lat, dist (const, exp, lognormal), cv, read_bw, write_bw, cache, post_cache, max_queues,
+     * queue_len, max_transfer, mdts, npwg, store (true, false), faults, errors, temp, heat, cooling,
+     * tmt1, waf, ocp (true, false), dealloc_lat, dealloc_bw, dealloc_read_lat, vwc (true, false),
+     * media_write_lat and flush_bw, e.g. "sim:cache=4G,write_bw=1G,dist=exp"
+     */
+    pub fn parse(spec: &str) -> Result<SimConfig, Box<dyn Error>> {
+        let mut config = SimConfig::default();
+        let Some(options) = spec.strip_prefix("sim") else {
+            return Err(format!("'{}' is not a simulated device", spec).into());
+        };
+        let options = match options.strip_prefix(':') {
+            Some(options) => options,
+            None if options.is_empty() => return Ok(config),
+            None => return Err(format!("'{}' is not a simulated device", spec).into()),
+        };
+
+        let mut cv = 0.3;
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", option))?;
+            match key {
+                "capacity" => config.capacity = parse_size(value)?,
+                "block_size" => config.block_size = parse_size(value)?,
+                "namespaces" => config.namespaces = value.parse()?,
+                "read_lat" => config.read_latency = parse_duration(value)?,
+                "write_lat" => config.write_latency = parse_duration(value)?,
+                "dist" => {
+                    config.distribution = match value {
+                        "const" => LatencyDistribution::Constant,
+                        "exp" => LatencyDistribution::Exponential,
+                        "lognormal" => LatencyDistribution::LogNormal { cv },
+                        _ => return Err(format!("unknown latency distribution '{}'", value).into()),
+                    }
+                }
+                "cv" => cv = value.parse()?,
+                "read_bw" => config.read_bandwidth = parse_size(value)?,
+                "write_bw" => config.write_bandwidth = parse_size(value)?,
+                "cache" => config.cache_size = parse_size(value)?,
+                "post_cache" => config.post_cache_factor = value.parse()?,
+                "max_queues" => config.max_queues = value.parse()?,
+                "queue_len" => config.max_queue_len = value.parse()?,
+                "max_transfer" => config.max_transfer = parse_size(value)?,
+                "mdts" => config.mdts = parse_size(value)?,
+                "npwg" => config.npwg = parse_size(value)?,
+                "store" => config.store = value.parse()?,
+                "faults" => config.faults = value.parse()?,
+                "errors" => config.errors = value.parse()?,
+                "temp" => config.temperature = value.parse()?,
+                "heat" => config.heat = value.parse()?,
+                "cooling" => config.cooling = parse_duration(value)?,
+                "tmt1" => config.tmt1 = value.parse()?,
+                "waf" => config.waf = value.parse()?,
+                "ocp" => config.ocp = value.parse()?,
+                "dealloc_lat" => config.dealloc_latency = parse_duration(value)?,
+                "dealloc_bw" => config.dealloc_bandwidth = parse_size(value)?,
+                "dealloc_read_lat" => config.dealloc_read_latency = parse_duration(value)?,
+                "vwc" => config.vwc = value.parse()?,
+                "media_write_lat" => config.media_write_latency = parse_duration(value)?,
+                "flush_bw" => config.flush_bandwidth = parse_size(value)?,
+                _ => return Err(format!("unknown simulator option '{}'", key).into()),
+            }
+        }
+        if let LatencyDistribution::LogNormal { .. } = config.distribution {
+            config.distribution = LatencyDistribution::LogNormal { cv };
+        }
+
+        if config.block_size == 0 || config.namespaces == 0 || config.max_transfer < config.block_size
+            || config.read_bandwidth == 0 || config.write_bandwidth == 0 || config.dealloc_bandwidth == 0 || config.flush_bandwidth == 0 || !(config.post_cache_factor > 0.0)
+            || config.npwg % config.block_size != 0 || config.npwg / config.block_size > 1 << 16
+            || (config.mdts != 0 && (!config.mdts.is_power_of_two() || config.mdts < 8192))
+            || !(0.0..=1.0).contains(&config.faults) || !(0.0..=1.0).contains(&config.errors)
+            || !(config.heat >= 0.0) || config.cooling.is_zero() || !(config.waf >= 1.0) {
+            return Err(format!("invalid simulator configuration {:?}", config).into());
+        }
+        Ok(config)
+    }
+}
+
+/// degrees Celsius the temperature has to fall below tmt1 to end throttling
+const THROTTLE_HYSTERESIS: f64 = 3.0;
+/// Write Zeroes has a 16 bit block count, a Dataset Management range a 32 bit one
+const MAX_WRITE_ZEROES_BLOCKS: u64 = 1 << 16;
+const MAX_DEALLOCATE_BLOCKS: u64 = 1 << 32;
+
+/// Deallocated LBAs per namespace, as disjoint ranges from their first to their end LBA
+#[derive(Default)]
+struct DeallocatedRanges(HashMap<u32, BTreeMap<u64, u64>>);
+
+impl DeallocatedRanges {
+    fn insert(&mut self, ns_id: u32, mut lbas: Range<u64>) {
+        let ranges = self.0.entry(ns_id).or_default();
+        let touching: Vec<_> = ranges.range(..=lbas.end).rev().take_while(|(_, &end)| end >= lbas.start).map(|(&start, &end)| start..end).collect();
+        for range in touching {
+            ranges.remove(&range.start);
+            lbas = lbas.start.min(range.start)..lbas.end.max(range.end);
+        }
+        ranges.insert(lbas.start, lbas.end);
+    }
+
+    fn remove(&mut self, ns_id: u32, lbas: Range<u64>) {
+        let Some(ranges) = self.0.get_mut(&ns_id) else {
+            return;
+        };
+        let overlapping: Vec<_> = ranges.range(..lbas.end).rev().take_while(|(_, &end)| end > lbas.start).map(|(&start, &end)| start..end).collect();
+        for range in overlapping {
+            ranges.remove(&range.start);
+            if range.start < lbas.start {
+                ranges.insert(range.start, lbas.start);
+            }
+            if range.end > lbas.end {
+                ranges.insert(lbas.end, range.end);
+            }
+        }
+    }
+
+    /// Whether all of the LBAs are deallocated
+    fn contains(&self, ns_id: u32, lbas: Range<u64>) -> bool {
+        self.0.get(&ns_id).and_then(|ranges| ranges.range(..=lbas.start).next_back()).is_some_and(|(_, &end)| end >= lbas.end)
+    }
+}
+
+struct SimState {
+    /// when the shared transfer pipe is free again
+    pipe_free: Instant,
+    cache_used: u64,
+    queues: usize,
+    /// degrees above the idle temperature as of heated
+    excess_temperature: f64,
+    heated: Instant,
+    throttled_since: Option<Instant>,
+    throttle_transitions: u32,
+    throttle_time: Duration,
+    /// counters of the SMART / Health log
+    bytes_read: u64,
+    bytes_written: u64,
+    media_bytes_written: u64,
+    read_commands: u64,
+    write_commands: u64,
+    media_errors: u64,
+    deallocated: DeallocatedRanges,
+    write_cache: bool,
+    /// bytes in the write cache that are not on the media yet, as of drained
+    dirty_bytes: f64,
+    drained: Instant,
+}
+
+impl SimState {
+    fn new(config: &SimConfig, now: Instant) -> SimState {
+        SimState {
+            pipe_free: now,
+            cache_used: 0,
+            queues: 0,
+            excess_temperature: 0.0,
+            heated: now,
+            throttled_since: None,
+            throttle_transitions: 0,
+            throttle_time: Duration::ZERO,
+            bytes_read: 0,
+            bytes_written: 0,
+            media_bytes_written: 0,
+            read_commands: 0,
+            write_commands: 0,
+            media_errors: 0,
+            deallocated: DeallocatedRanges::default(),
+            write_cache: config.vwc,
+            dirty_bytes: 0.0,
+            drained: now,
+        }
+    }
+
+    /// Writes back the cache since the last update
+    fn drain(&mut self, config: &SimConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.drained).as_secs_f64();
+        self.dirty_bytes = (self.dirty_bytes - elapsed * config.flush_bandwidth as f64).max(0.0);
+        self.drained = self.drained.max(now);
+    }
+
+    fn temperature(&self, config: &SimConfig) -> f64 {
+        config.temperature + self.excess_temperature
+    }
+
+    /// Cools the device down since the last update, heats it by a transfer of bytes and enters or leaves throttling
+    fn heat(&mut self, config: &SimConfig, now: Instant, bytes: u64) {
+        let tau = config.cooling.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.heated).as_secs_f64();
+        self.excess_temperature = self.excess_temperature * (-elapsed / tau).exp() + config.heat * bytes as f64 / (1u64 << 30) as f64 / tau;
+        self.heated = self.heated.max(now);
+
+        // like real controllers, throttling only ends some degrees below the threshold
+        let temperature = self.temperature(config);
+        match self.throttled_since {
+            None if config.tmt1 > 0.0 && temperature >= config.tmt1 => {
+                self.throttle_transitions += 1;
+                self.throttled_since = Some(now);
+            }
+            Some(since) if temperature < config.tmt1 - THROTTLE_HYSTERESIS => {
+                self.throttle_time += now.saturating_duration_since(since);
+                self.throttled_since = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stored blocks by namespace and LBA
+type BlockStore = Arc<Mutex<HashMap<(u32, u64), Box<[u8]>>>>;
+
+pub struct SimDevice {
+    config: Arc<SimConfig>,
+    state: Arc<Mutex<SimState>>,
+    blocks: BlockStore,
+    namespaces: Vec<Namespace>,
+    info: IdentifyControllerInfo,
+}
+
+impl SimDevice {
+    pub fn new(config: SimConfig) -> SimDevice {
+        let blocks = config.capacity / config.block_size / config.namespaces as u64;
+        let namespaces = (1..=config.namespaces).map(|id| Namespace { id, blocks, block_size: config.block_size }).collect();
+
+        // plain old data, all fields not set below are zero
+        let mut info: IdentifyControllerInfo = unsafe { std::mem::zeroed() };
+        info.model_number = ascii_field("nvmebench simulated device");
+        info.serial_number = ascii_field("SIM0001");
+        info.firmware_revision = ascii_field("1.0");
+        info.version = 0x0001_0400;
+        info.submission_queue_entry_size = 0x66;
+        info.completion_queue_entry_size = 0x44;
+        info.num_namespaces = config.namespaces;
+        info.max_data_transfer_size = if config.mdts == 0 { 0 } else { (config.mdts / 4096).ilog2() as u8 };
+        info.optional_nvm_cmd_support = 1 << 2 | 1 << 3;
+        info.volatile_write_cache = config.vwc as u8;
+
+        let state = SimState::new(&config, Instant::now());
+        SimDevice { config: Arc::new(config), state: Arc::new(Mutex::new(state)), blocks: BlockStore::default(), namespaces, info }
+    }
+}
+
+impl NvmeBackend for SimDevice {
+    type Queue = SimQueue;
+
+    fn namespaces(&self) -> Vec<Namespace> {
+        self.namespaces.clone()
+    }
+
+    fn controller_info(&self) -> &IdentifyControllerInfo {
+        &self.info
+    }
+
+    /// Offers a 512 byte and a 4 KiB format, and the configured block size if it is neither
+    fn identify_namespace(&mut self, id: u32) -> Result<NamespaceIdentity, BenchmarkError> {
+        let ns = self.namespace(id).ok_or_else(|| BenchmarkError::InvalidParameter(format!("namespace {} does not exist", id)))?;
+        let used = match self.config.store {
+            true => self.blocks.lock().unwrap().keys().filter(|key| key.0 == id).count() as u64,
+            false => ns.blocks,
+        };
+        let mut data = [0u8; IDENTIFY_SIZE];
+        data[0..8].copy_from_slice(&ns.blocks.to_le_bytes());
+        data[8..16].copy_from_slice(&ns.blocks.to_le_bytes());
+        data[16..24].copy_from_slice(&used.to_le_bytes());
+
+        let mut formats = vec![(512, 2), (4096, 0)];
+        if !formats.iter().any(|&(size, _)| size == ns.block_size) {
+            formats.push((ns.block_size, 1));
+        }
+        data[25] = formats.len() as u8 - 1;
+        data[26] = formats.iter().position(|&(size, _)| size == ns.block_size).unwrap() as u8;
+        for (i, (size, performance)) in formats.into_iter().enumerate() {
+            let lbaf = (performance << 24) | ((size as u32).ilog2() << 16);
+            data[128 + 4 * i..132 + 4 * i].copy_from_slice(&lbaf.to_le_bytes());
+        }
+
+        if self.config.npwg > 0 {
+            data[24] |= 1 << 4;
+            let blocks = (self.config.npwg / ns.block_size - 1) as u16;
+            for offset in [64, 66, 68, 70, 72] {
+                data[offset..offset + 2].copy_from_slice(&blocks.to_le_bytes());
+            }
+        }
+        Ok(NamespaceIdentity::parse(id, &data))
+    }
+
+    /**
+     * The SMART / Health log, with the temperature of the thermal model as composite and only sensor,
+     * and the media counters of the OCP extended SMART log
+     */
+    fn get_log_page(&mut self, log_id: u8, data: &mut [u8]) -> Result<(), BenchmarkError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        // both logs are 512 bytes
+        let mut log = [0u8; SMART_LOG_SIZE];
+        match log_id {
+            SMART_LOG_ID => {
+                state.heat(&self.config, now, 0);
+                let kelvin = (state.temperature(&self.config) + 273.15).round() as u16;
+                log[1..3].copy_from_slice(&kelvin.to_le_bytes());
+                log[200..202].copy_from_slice(&kelvin.to_le_bytes());
+                log[3] = 100;
+                log[4] = 10;
+                let counters = [
+                    (32, state.bytes_read.div_ceil(512_000)),
+                    (48, state.bytes_written.div_ceil(512_000)),
+                    (64, state.read_commands),
+                    (80, state.write_commands),
+                    (112, 1),
+                    (160, state.media_errors),
+                    (176, state.media_errors),
+                ];
+                for (offset, value) in counters {
+                    log[offset..offset + 16].copy_from_slice(&(value as u128).to_le_bytes());
+                }
+                let throttle_time = state.throttle_time + state.throttled_since.map_or(Duration::ZERO, |since| now - since);
+                log[216..220].copy_from_slice(&state.throttle_transitions.to_le_bytes());
+                log[224..228].copy_from_slice(&(throttle_time.as_secs() as u32).to_le_bytes());
+            }
+            OCP_SMART_LOG_ID if self.config.ocp => {
+                log[0..16].copy_from_slice(&(state.media_bytes_written as u128).to_le_bytes());
+                log[16..32].copy_from_slice(&(state.bytes_read as u128).to_le_bytes());
+                log[496..512].copy_from_slice(&OCP_SMART_LOG_GUID.to_le_bytes());
+            }
+            _ => return Err(BenchmarkError::Unsupported(format!("log page {:#04x} on the simulator", log_id))),
+        }
+
+        let len = data.len().min(log.len());
+        data[..len].copy_from_slice(&log[..len]);
+        Ok(())
+    }
+
+    fn supports_range_op(&self, op: RangeOp) -> bool {
+        self.capabilities().supports(op.command())
+    }
+
+    fn supports_durable_writes(&self) -> bool {
+        true
+    }
+
+    fn supports_admin_commands(&self) -> bool {
+        true
+    }
+
+    fn reports_completion_status(&self) -> bool {
+        true
+    }
+
+    fn get_features(&mut self, fid: u8) -> Result<u32, BenchmarkError> {
+        match fid {
+            VOLATILE_WRITE_CACHE => Ok(self.state.lock().unwrap().write_cache as u32),
+            _ => Err(BenchmarkError::Unsupported(format!("feature {:#04x} on the simulator", fid))),
+        }
+    }
+
+    /// Disabling the write cache writes it back first
+    fn set_features(&mut self, fid: u8, value: u32) -> Result<(), BenchmarkError> {
+        match fid {
+            VOLATILE_WRITE_CACHE if self.config.vwc => {
+                let mut state = self.state.lock().unwrap();
+                state.write_cache = value & 1 != 0;
+                if !state.write_cache {
+                    state.dirty_bytes = 0.0;
+                }
+                Ok(())
+            }
+            // Invalid Field in Command, as the controller has no volatile write cache
+            VOLATILE_WRITE_CACHE => Err(BenchmarkError::Device(format!("Set Features {:#04x} failed with {}", fid, CompletionStatus(0x02)))),
+            _ => Err(BenchmarkError::Unsupported(format!("feature {:#04x} on the simulator", fid))),
+        }
+    }
+
+    fn create_io_queue_pair(&mut self, len: usize) -> Result<SimQueue, BenchmarkError> {
+        if len < 2 || len > self.config.max_queue_len {
+            return Err(BenchmarkError::Device(format!("queue length {} not in 2..={}", len, self.config.max_queue_len)));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.queues >= self.config.max_queues {
+            return Err(BenchmarkError::Device(format!("all {} queue pairs are in use", self.config.max_queues)));
+        }
+        state.queues += 1;
+
+        Ok(SimQueue {
+            config: self.config.clone(),
+            state: self.state.clone(),
+            blocks: self.blocks.clone(),
+            namespaces: self.namespaces.clone(),
+            len,
+            inflight: VecDeque::new(),
+            write_end: None,
+            last_completion: Instant::now(),
+            rng: SmallRng::seed_from_u64(rand::rng().next_u64()),
+        })
+    }
+
+    fn delete_io_queue_pair(&mut self, queue_pair: SimQueue) -> Result<(), BenchmarkError> {
+        if !queue_pair.inflight.is_empty() {
+            return Err(BenchmarkError::QueuePair(format!("queue pair still had {} commands in flight", queue_pair.inflight.len())));
+        }
+        self.state.lock().unwrap().queues -= 1;
+        Ok(())
+    }
+}
+
+/**
+ * Completes commands in submission order, a command that finished early waits for its predecessors
+ */
+pub struct SimQueue {
+    config: Arc<SimConfig>,
+    state: Arc<Mutex<SimState>>,
+    blocks: BlockStore,
+    namespaces: Vec<Namespace>,
+    len: usize,
+    /// completion times and statuses of the commands in flight
+    inflight: VecDeque<(Instant, CompletionStatus)>,
+    /// namespace and LBA right after the last write, a write starting there is sequential
+    write_end: Option<(u32, u64)>,
+    last_completion: Instant,
+    rng: SmallRng,
+}
+
+impl SimQueue {
+    fn sample_latency(&mut self, mean: Duration) -> Duration {
+        let mean = mean.as_secs_f64();
+        let secs = match self.config.distribution {
+            LatencyDistribution::Constant => mean,
+            LatencyDistribution::Exponential => Exp::new(1.0 / mean).unwrap().sample(&mut self.rng),
+            LatencyDistribution::LogNormal { cv } => LogNormal::from_mean_cv(mean, cv).unwrap().sample(&mut self.rng),
+        };
+        Duration::from_secs_f64(secs)
+    }
+
+    /**
+     * Transfers the data right away, reads see the writes submitted before them. A damaged block is
+     * dropped (stale), stored at the next LBA (misdirected), only partially written (torn) or has a
+     * byte flipped (corrupted).
+     */
+    fn transfer(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64, write: bool) {
+        let block_size = block_size as usize;
+        let buffer = unsafe { std::slice::from_raw_parts_mut(data.as_ptr().add(range.start), range.len()) };
+        let mut blocks = self.blocks.lock().unwrap();
+        for (i, block) in buffer.chunks_mut(block_size).enumerate() {
+            let key = (ns_id, lba + i as u64);
+            if !write {
+                match blocks.get(&key) {
+                    Some(stored) => block.copy_from_slice(stored),
+                    None => block.fill(0),
+                }
+                continue;
+            }
+            if self.config.faults == 0.0 || !self.rng.random_bool(self.config.faults) {
+                blocks.insert(key, block.into());
+                continue;
+            }
+            match self.rng.random_range(0..4) {
+                0 => {}
+                1 => {
+                    blocks.insert((ns_id, key.1 + 1), block.into());
+                }
+                2 => {
+                    let stored = blocks.entry(key).or_insert_with(|| vec![0; block_size].into());
+                    let sectors = (block_size / 512).max(2) / 2;
+                    stored[..sectors * 512].copy_from_slice(&block[..sectors * 512]);
+                }
+                _ => {
+                    let mut damaged: Box<[u8]> = block.into();
+                    let byte = self.rng.random_range(0..block_size);
+                    damaged[byte] ^= 0xff;
+                    blocks.insert(key, damaged);
+                }
+            }
+        }
+    }
+
+    fn submit(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64, write: bool, fua: bool) -> usize {
+        let Some(ns) = self.namespaces.iter().find(|ns| ns.id == ns_id) else {
+            return 0;
+        };
+        let bytes = range.len() as u64;
+        if block_size != ns.block_size || bytes == 0 || lba + bytes.div_ceil(block_size) > ns.blocks {
+            return 0;
+        }
+
+        let commands = bytes.div_ceil(self.config.max_transfer) as usize;
+        if self.inflight.len() + commands >= self.len {
+            return 0;
+        }
+
+        if self.config.store {
+            self.transfer(ns_id, block_size, data, range, lba, write);
+        }
+
+        let amplification = match write && self.write_end != Some((ns_id, lba)) {
+            true => self.config.waf,
+            false => 1.0,
+        };
+        if write {
+            self.write_end = Some((ns_id, lba + bytes.div_ceil(block_size)));
+        }
+
+        let lbas = lba..lba + bytes.div_ceil(block_size);
+        let (deallocated, cached) = {
+            let state = self.state.lock().unwrap();
+            (!write && state.deallocated.contains(ns_id, lbas.clone()), write && state.write_cache && !fua)
+        };
+        let mean = match (write, deallocated) {
+            (true, _) if cached => self.config.write_latency,
+            (true, _) => self.config.write_latency + self.config.media_write_latency,
+            (false, true) => self.config.dealloc_read_latency,
+            (false, false) => self.config.read_latency,
+        };
+        let latencies: Vec<_> = (0..commands).map(|_| self.sample_latency(mean)).collect();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if write {
+            state.deallocated.remove(ns_id, lbas);
+        }
+        if cached {
+            state.drain(&self.config, now);
+            state.dirty_bytes += bytes as f64;
+        }
+
+        for (i, latency) in latencies.into_iter().enumerate() {
+            let size = (bytes - i as u64 * self.config.max_transfer).min(self.config.max_transfer);
+            let mut bandwidth = if write { self.config.write_bandwidth } else { self.config.read_bandwidth } as f64;
+            if write {
+                if state.cache_used >= self.config.cache_size {
+                    bandwidth *= self.config.post_cache_factor;
+                }
+                state.cache_used += size;
+                state.bytes_written += size;
+                state.media_bytes_written += (size as f64 * amplification) as u64;
+                state.write_commands += 1;
+            } else {
+                state.bytes_read += size;
+                state.read_commands += 1;
+            }
+            state.heat(&self.config, now, size);
+            if state.throttled_since.is_some() {
+                bandwidth /= 2.0;
+            }
+
+            let transfer_start = state.pipe_free.max(now);
+            state.pipe_free = transfer_start + Duration::from_secs_f64(size as f64 / bandwidth);
+            let done = (state.pipe_free + latency).max(self.last_completion);
+            self.last_completion = done;
+            let status = match self.config.errors > 0.0 && self.rng.random_bool(self.config.errors) {
+                true if write => CompletionStatus::WRITE_FAULT,
+                true => CompletionStatus::UNRECOVERED_READ_ERROR,
+                false => CompletionStatus::SUCCESS,
+            };
+            state.media_errors += !status.is_success() as u64;
+            self.inflight.push_back((done, status));
+        }
+        commands
+    }
+
+    fn wait_for_head(&mut self) {
+        let Some(&(done, _)) = self.inflight.front() else {
+            return;
+        };
+        loop {
+            let now = Instant::now();
+            if now >= done {
+                return;
+            }
+            if done - now > Duration::from_micros(200) {
+                std::thread::sleep(done - now - Duration::from_micros(100));
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl IoQueue for SimQueue {
+    type Buffer = HostBuffer;
+
+    fn allocate_buffer(&self, size: usize) -> Result<HostBuffer, BenchmarkError> {
+        HostBuffer::allocate(size)
+    }
+
+    fn submit_io(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64, write: bool) -> usize {
+        self.submit(ns_id, block_size, data, range, lba, write, false)
+    }
+
+    fn submit_fua(&mut self, ns_id: u32, block_size: u64, data: &HostBuffer, range: Range<usize>, lba: u64) -> usize {
+        self.submit(ns_id, block_size, data, range, lba, true, true)
+    }
+
+    /// Completes once the write cache is drained, the writes before it have been cached already
+    fn submit_flush(&mut self, ns_id: u32) -> usize {
+        if !self.namespaces.iter().any(|ns| ns.id == ns_id) || self.inflight.len() + 1 >= self.len {
+            return 0;
+        }
+        let latency = self.sample_latency(self.config.write_latency);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.drain(&self.config, now);
+        let write_back = Duration::from_secs_f64(state.dirty_bytes / self.config.flush_bandwidth as f64);
+        state.dirty_bytes = 0.0;
+        let done = (now + write_back + latency).max(self.last_completion);
+        self.last_completion = done;
+        self.inflight.push_back((done, CompletionStatus::SUCCESS));
+        1
+    }
+
+    /// Deallocated blocks read back as zeroes, also with store, while written zeroes stay allocated
+    fn submit_range(&mut self, ns_id: u32, block_size: u64, lba: u64, blocks: u64, op: RangeOp) -> usize {
+        let Some(ns) = self.namespaces.iter().find(|ns| ns.id == ns_id) else {
+            return 0;
+        };
+        if block_size != ns.block_size || blocks == 0 || lba + blocks > ns.blocks {
+            return 0;
+        }
+
+        let (max_blocks, mean) = match op {
+            RangeOp::Deallocate => (MAX_DEALLOCATE_BLOCKS, self.config.dealloc_latency),
+            RangeOp::WriteZeroes => (MAX_WRITE_ZEROES_BLOCKS, self.config.write_latency),
+        };
+        let commands = blocks.div_ceil(max_blocks) as usize;
+        if self.inflight.len() + commands >= self.len {
+            return 0;
+        }
+
+        if self.config.store {
+            let mut stored = self.blocks.lock().unwrap();
+            (lba..lba + blocks).for_each(|lba| {
+                stored.remove(&(ns_id, lba));
+            });
+        }
+
+        let latencies: Vec<_> = (0..commands).map(|_| self.sample_latency(mean)).collect();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match op {
+            RangeOp::Deallocate => state.deallocated.insert(ns_id, lba..lba + blocks),
+            RangeOp::WriteZeroes => state.deallocated.remove(ns_id, lba..lba + blocks),
+        }
+
+        for (i, latency) in latencies.into_iter().enumerate() {
+            let size = (blocks - i as u64 * max_blocks).min(max_blocks) * block_size;
+            let transfer_start = state.pipe_free.max(now);
+            state.pipe_free = transfer_start + Duration::from_secs_f64(size as f64 / self.config.dealloc_bandwidth as f64);
+            let done = (state.pipe_free + latency).max(self.last_completion);
+            self.last_completion = done;
+            self.inflight.push_back((done, CompletionStatus::SUCCESS));
+        }
+        commands
+    }
+
+    fn complete_io(&mut self, n: usize) -> usize {
+        let mut failed = 0;
+        for _ in 0..n.min(self.inflight.len()) {
+            self.wait_for_head();
+            let (_, status) = self.inflight.pop_front().unwrap();
+            failed += !status.is_success() as usize;
+        }
+        failed
+    }
+
+    fn quick_poll(&mut self) -> Option<CompletionStatus> {
+        match self.inflight.front() {
+            Some(&(done, status)) if done <= Instant::now() => {
+                self.inflight.pop_front();
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+}
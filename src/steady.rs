@@ -0,0 +1,125 @@
+use serde::Serialize;
+
+/**
+ * Steady-state criteria of the SNIA Solid State Storage Performance Test Specification: the tracking
+ * variable of the last `window` rounds may not deviate from their average by more than
+ * max_excursion (max - min), and the best-fit line through them may not rise or fall by more than
+ * max_slope_excursion over the window. Both are relative to the window average.
+ */
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct SteadyStateCriteria {
+    pub window: usize,
+    pub max_excursion: f64,
+    pub max_slope_excursion: f64,
+}
+
+impl Default for SteadyStateCriteria {
+    fn default() -> SteadyStateCriteria {
+        SteadyStateCriteria { window: 5, max_excursion: 0.20, max_slope_excursion: 0.10 }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SteadyStateWindow {
+    /// index of the first round in the window
+    pub first_round: usize,
+    pub average: f64,
+    /// (max - min) / average
+    pub excursion: f64,
+    /// |slope| * (window - 1) / average
+    pub slope_excursion: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SteadyStateAnalysis {
+    pub criteria: SteadyStateCriteria,
+    /// tracking variable (IOPS) per round
+    pub rounds: Vec<f64>,
+    pub converged: bool,
+    /// the converged window, or the last one evaluated if the rounds did not converge
+    pub window: Option<SteadyStateWindow>,
+}
+
+impl SteadyStateCriteria {
+    /// Fits the last `window` rounds, None if there are not enough of them yet
+    pub fn evaluate(&self, rounds: &[f64]) -> Option<SteadyStateWindow> {
+        if self.window < 2 || rounds.len() < self.window {
+            return None;
+        }
+        let first_round = rounds.len() - self.window;
+        let ys = &rounds[first_round..];
+        let n = ys.len() as f64;
+        let average = ys.iter().sum::<f64>() / n;
+        let max = ys.iter().copied().fold(f64::MIN, f64::max);
+        let min = ys.iter().copied().fold(f64::MAX, f64::min);
+
+        // least squares over x = 0..n
+        let x_mean = (n - 1.0) / 2.0;
+        let (cov, var) = ys.iter().enumerate().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            let dx = x as f64 - x_mean;
+            (cov + dx * (y - average), var + dx * dx)
+        });
+        let slope = cov / var;
+
+        Some(SteadyStateWindow {
+            first_round,
+            average,
+            excursion: (max - min) / average,
+            slope_excursion: slope.abs() * (n - 1.0) / average,
+        })
+    }
+
+    pub fn is_steady(&self, window: &SteadyStateWindow) -> bool {
+        window.average > 0.0 && window.excursion <= self.max_excursion && window.slope_excursion <= self.max_slope_excursion
+    }
+
+    pub fn analyze(&self, rounds: &[f64]) -> SteadyStateAnalysis {
+        let window = self.evaluate(rounds);
+        SteadyStateAnalysis {
+            criteria: *self,
+            rounds: rounds.to_vec(),
+            converged: window.as_ref().is_some_and(|w| self.is_steady(w)),
+            window,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_rounds_converge() {
+        let criteria = SteadyStateCriteria::default();
+        let analysis = criteria.analyze(&[50_000.0, 30_000.0, 20_500.0, 20_000.0, 19_800.0, 20_100.0, 20_300.0]);
+
+        assert!(analysis.converged);
+        let window = analysis.window.unwrap();
+        assert_eq!(window.first_round, 2);
+        assert!((window.average - 20_140.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn falling_rounds_do_not_converge() {
+        let criteria = SteadyStateCriteria::default();
+        // within the excursion limit, but the trend exceeds the slope limit
+        let window = criteria.evaluate(&[22_000.0, 21_000.0, 20_000.0, 19_000.0, 18_000.0]).unwrap();
+
+        assert!(window.excursion <= criteria.max_excursion);
+        assert!(window.slope_excursion > criteria.max_slope_excursion);
+        assert!(!criteria.is_steady(&window));
+    }
+
+    #[test]
+    fn too_few_rounds() {
+        let criteria = SteadyStateCriteria::default();
+        assert!(criteria.evaluate(&[1.0, 1.0, 1.0, 1.0]).is_none());
+        assert!(!criteria.analyze(&[1.0, 1.0, 1.0, 1.0]).converged);
+    }
+
+    #[test]
+    fn zero_throughput_is_not_steady() {
+        let criteria = SteadyStateCriteria::default();
+        assert!(!criteria.analyze(&[0.0; 5]).converged);
+    }
+}
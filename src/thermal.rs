@@ -0,0 +1,61 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::device::NvmeBackend;
+use crate::error::BenchmarkError;
+
+/// How often the composite temperature is read while cooling down or while a run is limited
+pub const THERMAL_POLL: Duration = Duration::from_secs(1);
+
+/**
+ * Temperature limits of the benchmarks: between runs they wait until the composite temperature
+ * falls below cool_below, for at most cool_timeout, and a run whose device gets hotter than
+ * abort_above is aborted. Both need the SMART / Health log.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct ThermalPolicy {
+    pub cool_below: Option<i32>,
+    pub cool_timeout: Duration,
+    pub abort_above: Option<i32>,
+}
+
+impl ThermalPolicy {
+    /// Checks that the backend can read the temperature if the policy needs it
+    pub fn check<D: NvmeBackend>(&self, nvme: &mut D) -> Result<(), BenchmarkError> {
+        if self.cool_below.is_none() && self.abort_above.is_none() {
+            return Ok(());
+        }
+        if !nvme.supports_admin_commands() {
+            return Err(BenchmarkError::Unsupported("temperature limits on this backend, they need the SMART / Health log".into()));
+        }
+        match nvme.smart_log() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(BenchmarkError::Unsupported(format!("temperature limits need the SMART / Health log, {}", e))),
+        }
+    }
+
+    /**
+     * Pauses between two runs, at least for pause and then until the device cooled down below
+     * cool_below. After cool_timeout it warns and lets the next run start anyway.
+     */
+    pub fn cool_down<D: NvmeBackend>(&self, nvme: &mut D, pause: Duration) -> Result<(), BenchmarkError> {
+        sleep(pause);
+        let Some(below) = self.cool_below else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + self.cool_timeout;
+        let mut temperature = nvme.smart_log()?.composite_temperature_c;
+        if temperature >= below {
+            eprintln!("cooling down from {} C to below {} C", temperature, below);
+        }
+        while temperature >= below {
+            if Instant::now() >= deadline {
+                eprintln!("still {} C after {:?}, starting the next run anyway", temperature, self.cool_timeout);
+                break;
+            }
+            sleep(THERMAL_POLL);
+            temperature = nvme.smart_log()?.composite_temperature_c;
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,128 @@
-use core::num;
-use std::{cmp::min, io, result, time::{Duration, Instant}};
-use rand::{rngs::SmallRng, seq::SliceRandom, RngCore, SeedableRng};
+use std::{cmp::{max, min}, collections::BTreeMap, time::{Duration, Instant}};
+use rand::{rngs::SmallRng, seq::SliceRandom, Rng, RngCore, SeedableRng};
 use std::error::Error;
-use vroom::{memory::{Dma, DmaSlice}, NvmeQueuePair};
-use rand_distr::{num_traits, Distribution};
+use rand_distr::{num_traits, Distribution, Zipf};
+use serde::Serialize;
+
+use crate::cli::{Arrivals, ErrorPolicy, RangeOp};
+use crate::verify::VerifyMode;
+use crate::device::{IoBuffer, IoQueue, LbaWindow};
+use crate::latency::{ErrorTable, IoTimer, LatencyHistogram};
+use crate::error::BenchmarkError;
 
 
 pub const ONE_GIB: u64 = 1024 * 1024 * 1024;
+/// Default log interval of a workload thread
+pub const LOG_INTERVAL: Duration = Duration::from_millis(10);
 
-pub struct QueuePairError {
-    pub(crate) queue_pair: NvmeQueuePair,
-    message: String,
+pub struct QueuePairError<Q> {
+    pub(crate) queue_pair: Q,
+    pub(crate) message: String,
 }
 
-impl std::fmt::Debug for QueuePairError {
+impl<Q> std::fmt::Debug for QueuePairError<Q> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "submit_io error: {}", self.message)
     }
 }
 
-impl Error for QueuePairError {}
-impl std::fmt::Display for QueuePairError { 
+impl<Q> Error for QueuePairError<Q> {}
+impl<Q> std::fmt::Display for QueuePairError<Q> { 
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "submit_io error: {}", self.message)
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// IOs are executed but excluded from all statistics
+    Warmup,
+    Measure,
+    /// keeps the device under load while the other threads finish measuring, excluded as well
+    RampDown,
+}
+
 #[derive(Debug, Clone)]
 pub struct IoLog {
     pub start: Instant,
     pub end: Instant,
+    /// requests completed between start and end
     pub actions: usize,
-    pub cumulative_size: usize,
+    /// bytes of the completed commands
+    pub bytes: u64,
+    /// completed requests per request size
+    pub sizes: BTreeMap<u64, u64>,
+    /// completed commands with an error status and requests the queue pair did not accept
+    pub failed: usize,
+    pub rejected: usize,
+    /// the failed commands by status and LBA
+    pub errors: ErrorTable,
+    /// latencies of the commands completed between start and end
+    pub latency: LatencyHistogram,
+    pub phase: Phase,
+    /// every log covers either reads or writes, mixed workloads write one log per operation
+    pub write: bool,
+}
+
+/// Aggregate of a benchmark that only measures start and end of all transfers
+#[derive(Clone, Debug, Default)]
+pub struct Throughput {
+    pub actions: u64,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub latency: LatencyHistogram,
+    /// requests per request size, empty if the benchmark does not log them
+    pub sizes: BTreeMap<u64, u64>,
+    /// failed commands and rejected requests, including those of warmup and ramp-down
+    pub failed: u64,
+    pub rejected: u64,
+    pub errors: ErrorTable,
+}
+
+impl Throughput {
+    /// Spans from the earliest start to the latest end of the measured logs over all threads
+    pub fn from_logs(results: &Vec<Vec<IoLog>>) -> Throughput {
+        let logs = || measured_logs(results);
+        let all_logs = || results.iter().flatten();
+        let failed = all_logs().map(|l| l.failed as u64).sum();
+        let rejected = all_logs().map(|l| l.rejected as u64).sum();
+        let mut errors = ErrorTable::default();
+        all_logs().for_each(|l| errors.merge(&l.errors));
+        let (Some(start), Some(end)) = (logs().map(|l| l.start).min(), logs().map(|l| l.end).max()) else {
+            return Throughput { failed, rejected, errors, ..Throughput::default() };
+        };
+        let mut latency = LatencyHistogram::new();
+        let mut sizes = BTreeMap::new();
+        for log in logs() {
+            latency.merge(&log.latency);
+            log.sizes.iter().for_each(|(&size, &count)| *sizes.entry(size).or_insert(0) += count);
+        }
+        Throughput {
+            actions: logs().map(|l| l.actions as u64).sum(),
+            bytes: logs().map(|l| l.bytes).sum(),
+            duration: end.duration_since(start),
+            latency,
+            sizes,
+            failed,
+            rejected,
+            errors,
+        }
+    }
+
+    pub fn mib_per_s(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / (1024.0 * 1024.0) / self.duration.as_secs_f64()
+    }
+
+    pub fn iops(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.actions as f64 / self.duration.as_secs_f64()
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -41,55 +132,256 @@ pub struct Allocation {
     pub stop: usize,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccessPattern {
+    Sequential,
+    Random,
+    Zipf(f64),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Limit {
+    Bytes(u64),
+    Duration(Duration),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rate {
+    Iops(f64),
+    /// bytes per second
+    Bandwidth(u64),
+}
+
 /**
- * @returns a vector of chronological sorted (actions, cumulative_size) tuples, each representing a bucket of the given duration
+ * Open-loop load: requests are issued on a schedule independent of completions and their latency
+ * counts from the intended issue time, so queueing delay is not hidden (no coordinated omission).
  */
-pub fn combine_results(results: &Vec<Vec<IoLog>>, bucket_duration: Duration) -> Vec<(f64, f64)> {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// total over all threads
+    pub target: Rate,
+    pub arrivals: Arrivals,
+}
 
-    let results : Vec<_> = results.iter().filter(|v| !v.is_empty()).collect();
-    if results.is_empty() {
-        return Vec::new();
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub ns_id: u32,
+    pub lba_start: u64,
+    /// None uses the namespace from lba_start up to its end
+    pub lba_count: Option<u64>,
+    /// applies to reads, and to writes unless write_io_size / write_pattern are set
+    pub io_size: u64,
+    pub queue_depth: usize,
+    pub num_threads: usize,
+    /// share of requests that are writes, 0.0 reads only, 1.0 writes only
+    pub write_ratio: f64,
+    pub pattern: AccessPattern,
+    pub write_io_size: Option<u64>,
+    pub write_pattern: Option<AccessPattern>,
+    /// total over all threads, bytes only count IOs submitted after the warmup
+    pub limit: Limit,
+    pub warmup: Duration,
+    pub ramp_down: Duration,
+    /// None runs closed-loop, queue_depth then also caps the requests in flight of an open-loop run
+    pub rate: Option<RateLimit>,
+    /// write self-describing blocks and check them when reading back
+    pub verify: Option<VerifyMode>,
+    /// issue the writes as this command on their LBAs instead, without transferring data
+    pub range_op: Option<RangeOp>,
+    /// submit the writes with Force Unit Access
+    pub fua: bool,
+    /// every thread starts a new log after this long, should be well below the bucket width
+    pub log_interval: Duration,
+    pub on_error: ErrorPolicy,
+    /// the run is aborted once the composite temperature in degrees Celsius exceeds this
+    pub max_temperature: Option<i32>,
+}
+
+impl Workload {
+    /**
+     * Sequential reads of one thread at queue depth 1 over the LBA window, closed-loop and without
+     * warmup, verification or special write commands. Set the other fields with struct update syntax.
+     */
+    pub fn new(ns_id: u32, window: LbaWindow, io_size: u64, limit: Limit) -> Workload {
+        Workload {
+            ns_id,
+            lba_start: window.first,
+            lba_count: Some(window.blocks),
+            io_size,
+            queue_depth: 1,
+            num_threads: 1,
+            write_ratio: 0.0,
+            pattern: AccessPattern::Sequential,
+            write_io_size: None,
+            write_pattern: None,
+            limit,
+            warmup: Duration::ZERO,
+            ramp_down: Duration::ZERO,
+            rate: None,
+            verify: None,
+            range_op: None,
+            fua: false,
+            log_interval: LOG_INTERVAL,
+            on_error: ErrorPolicy::Continue,
+            max_temperature: None,
+        }
+    }
+
+    pub fn io_size(&self, write: bool) -> u64 {
+        if write { self.write_io_size.unwrap_or(self.io_size) } else { self.io_size }
+    }
+
+    pub fn pattern(&self, write: bool) -> AccessPattern {
+        if write { self.write_pattern.unwrap_or(self.pattern) } else { self.pattern }
+    }
+
+    pub fn mean_io_size(&self) -> f64 {
+        (1.0 - self.write_ratio) * self.io_size(false) as f64 + self.write_ratio * self.io_size(true) as f64
+    }
+
+    /// Offered requests per second over all threads
+    pub fn offered_iops(&self) -> Option<f64> {
+        self.rate.map(|rate| match rate.target {
+            Rate::Iops(iops) => iops,
+            Rate::Bandwidth(bytes) => bytes as f64 / self.mean_io_size(),
+        })
+    }
+
+    /// The operations the workload issues at all
+    pub fn ops(&self) -> Vec<bool> {
+        [false, true].into_iter().filter(|&write| if write { self.write_ratio > 0.0 } else { self.write_ratio < 1.0 }).collect()
+    }
+}
+
+/**
+ * Generates the io_size aligned slots one thread of a workload accesses.
+ * Sequential threads each walk their own share of the slots, wrapping around at its end.
+ */
+pub enum SlotGenerator {
+    Sequential { first: u64, len: u64, next: u64 },
+    Random { slots: u64, rng: SmallRng },
+    Zipf { distr: Zipf<f64>, rng: SmallRng },
+}
+
+impl SlotGenerator {
+    pub fn new(pattern: AccessPattern, slots: u64, thread: usize, num_threads: usize, seed: u64) -> SlotGenerator {
+        let rng = SmallRng::seed_from_u64(seed ^ thread as u64);
+        match pattern {
+            AccessPattern::Sequential => {
+                let first = min(slots * thread as u64 / num_threads as u64, slots - 1);
+                let len = max(slots * (thread as u64 + 1) / num_threads as u64 - first, 1);
+                SlotGenerator::Sequential { first, len, next: 0 }
+            }
+            AccessPattern::Random => SlotGenerator::Random { slots, rng },
+            AccessPattern::Zipf(s) => SlotGenerator::Zipf { distr: Zipf::new(slots as f64, s).unwrap(), rng },
+        }
+    }
+
+    pub fn next_slot(&mut self) -> u64 {
+        match self {
+            SlotGenerator::Sequential { first, len, next } => {
+                let slot = *first + *next;
+                *next = (*next + 1) % *len;
+                slot
+            }
+            SlotGenerator::Random { slots, rng } => rng.random_range(0..*slots),
+            SlotGenerator::Zipf { distr, rng } => distr.sample(rng) as u64 - 1,
+        }
     }
+}
+
+pub fn measured_logs(results: &Vec<Vec<IoLog>>) -> impl Iterator<Item = &IoLog> {
+    results.iter().flat_map(|x| x.iter()).filter(|log| log.phase == Phase::Measure)
+}
 
-    let min_start = results.iter().map(|x| x.into_iter().min_by_key(|log| log.start).unwrap()).min_by_key(|log| log.start).unwrap().start;
-    let max_end = results.iter().map(|x| x.into_iter().max_by_key(|log| log.end).unwrap()).max_by_key(|log| log.end).unwrap().end;
-    let total_duration = max_end.duration_since(min_start);
-    let num_buckets = (total_duration.as_micros() / bucket_duration.as_micros() + 1) as usize;
+/// The logs of one operation type, per thread
+pub fn select_op(results: &Vec<Vec<IoLog>>, write: bool) -> Vec<Vec<IoLog>> {
+    results.iter().map(|x| x.iter().filter(|log| log.write == write).cloned().collect()).collect()
+}
+
+/// Start of the earliest and end of the latest measured log over all threads
+pub fn measured_span(results: &Vec<Vec<IoLog>>) -> Option<(Instant, Instant)> {
+    let logs = || measured_logs(results);
+    logs().map(|l| l.start).min().zip(logs().map(|l| l.end).max())
+}
 
+pub fn bucket_count(span: (Instant, Instant), bucket_duration: Duration) -> usize {
+    (span.1.duration_since(span.0).as_micros() / bucket_duration.as_micros() + 1) as usize
+}
+
+/**
+ * Only logs of the measured phase are combined, the first bucket starts with the earliest of them
+ * @returns a vector of chronological sorted (actions, bytes) tuples, each representing a bucket of the given duration
+ */
+pub fn combine_results(results: &Vec<Vec<IoLog>>, bucket_duration: Duration) -> Vec<(f64, f64)> {
+    let Some(span) = measured_span(results) else {
+        return Vec::new();
+    };
+    spread_logs(measured_logs(results), span.0, bucket_count(span, bucket_duration), bucket_duration)
+}
+
+/**
+ * Spreads each log proportionally over the buckets it overlaps, the first bucket starts at origin.
+ * Used with the span of all threads to get per-thread series aligned with the combined one.
+ */
+pub fn spread_logs<'a>(logs: impl Iterator<Item = &'a IoLog>, origin: Instant, num_buckets: usize, bucket_duration: Duration) -> Vec<(f64, f64)> {
     let mut results_combined = vec![(0.0,0.0); num_buckets];
+    if num_buckets == 0 {
+        return results_combined;
+    }
 
-    for io_log in results.iter().flat_map(|x| x.iter()) {
+    for io_log in logs {
         let log_duration = io_log.end.duration_since(io_log.start);
         if log_duration.is_zero() {
             continue;
         }
 
         // normalize all duration to the first logged start time
-        let start_offset = io_log.start - min_start;
-        let end_offset = io_log.end - min_start;
+        let start_offset = io_log.start - origin;
+        let end_offset = io_log.end - origin;
 
-        let mut current_time = start_offset;
+        let width = bucket_duration.as_secs_f64();
+        let (start, end) = (start_offset.as_secs_f64(), end_offset.as_secs_f64());
 
-        while current_time < end_offset {
-            let bucket_index = (current_time.as_secs_f64() / bucket_duration.as_secs_f64()) as usize;
-            if bucket_index >= num_buckets { break; }
+        // iterate over bucket indices, stepping through time in floats can get stuck on a bucket boundary
+        let first_bucket = (start / width) as usize;
+        let last_bucket = min((end / width) as usize, num_buckets - 1);
 
-            let bucket_end_time = (bucket_index + 1) as f64 * bucket_duration.as_secs_f64();
-            let overlap_end = end_offset.as_secs_f64().min(bucket_end_time);
+        for bucket_index in first_bucket..=last_bucket {
+            let overlap_start = start.max(bucket_index as f64 * width);
+            let overlap_end = end.min((bucket_index + 1) as f64 * width);
+            if overlap_end <= overlap_start {
+                continue;
+            }
 
-            let overlap_share = (overlap_end - current_time.as_secs_f64()) / log_duration.as_secs_f64();
+            let overlap_share = (overlap_end - overlap_start) / log_duration.as_secs_f64();
 
             results_combined[bucket_index].0 += overlap_share * io_log.actions as f64;
-            results_combined[bucket_index].1 += overlap_share * io_log.cumulative_size as f64;
-
-            current_time = Duration::from_secs_f64(overlap_end);
+            results_combined[bucket_index].1 += overlap_share * io_log.bytes as f64;
         }
     }
 
     results_combined
 }
 
-pub fn threadsafe_io_batch_complete_64(mut queue_pair: NvmeQueuePair, ns_id: u32, block_size: u64, data: (&Dma<u8>, &Vec<Allocation >), write: bool) -> Result<NvmeQueuePair, Box<QueuePairError>> {
+/**
+ * Merges the latency histograms of the logs into buckets of the given duration. Unlike throughput,
+ * latencies cannot be split, so each log counts towards the bucket its end falls into.
+ * @returns one histogram per bucket, aligned with the buckets of spread_logs
+ */
+pub fn bucket_latencies<'a>(logs: impl Iterator<Item = &'a IoLog>, origin: Instant, num_buckets: usize, bucket_duration: Duration) -> Vec<LatencyHistogram> {
+    let mut combined = vec![LatencyHistogram::new(); num_buckets];
+    if num_buckets == 0 {
+        return combined;
+    }
+    for io_log in logs {
+        let bucket_index = (io_log.end.duration_since(origin).as_secs_f64() / bucket_duration.as_secs_f64()) as usize;
+        combined[min(bucket_index, num_buckets - 1)].merge(&io_log.latency);
+    }
+    combined
+}
+
+pub fn threadsafe_io_batch_complete_64<Q: IoQueue>(mut queue_pair: Q, ns_id: u32, block_size: u64, data: (&Q::Buffer, &Vec<Allocation >), write: bool, timer: &mut IoTimer) -> Result<Q, Box<QueuePairError<Q>>> {
     let batch_size = 64;
     
     let mut total = 0;
@@ -97,36 +389,40 @@ pub fn threadsafe_io_batch_complete_64(mut queue_pair: NvmeQueuePair, ns_id: u32
         if alloc.stop <= alloc.start {
             continue;
         }
-        let res = queue_pair.submit_io(ns_id, block_size, &data.0.slice(alloc.start..alloc.stop), alloc.lba, write);
+        let submit_time = Instant::now();
+        let res = queue_pair.submit_io(ns_id, block_size, data.0, alloc.start..alloc.stop, alloc.lba, write);
         if res == 0 {
+            timer.rejected(write);
             if total > 0 {
-                queue_pair.complete_io(total);
+                timer.complete(&mut queue_pair, total);
             }
             return Err(Box::new(QueuePairError{queue_pair, message: "Request was not queued".into()}));
         }
+        timer.submitted_request(res, (alloc.stop - alloc.start) as u64, alloc.lba, submit_time, write);
 
         total += res;
     
         if total > batch_size {
             //complete but don't let the submission queue run out of entries
-            queue_pair.complete_io(total/2);
+            timer.complete(&mut queue_pair, total/2);
             total -= total / 2;
         }
     }
 
     if total > 0 {
-        queue_pair.complete_io(total);
+        timer.complete(&mut queue_pair, total);
     }
     Ok(queue_pair)
 }
 
-pub fn create_random_data(size: usize) -> Dma<u8> {
+pub fn create_random_data<Q: IoQueue>(queue_pair: &Q, size: usize) -> Result<Q::Buffer, BenchmarkError> {
     let mut rng = SmallRng::seed_from_u64(1);
-    let mut data: Dma<u8> = Dma::allocate(size).unwrap();
+    let mut data = queue_pair.allocate_buffer(size)?;
+    let slice = data.as_mut_slice();
     for i in 0..size / 8 {
-        data[i * 8..(i + 1) * 8].copy_from_slice(&rng.next_u64().to_le_bytes());
+        slice[i * 8..(i + 1) * 8].copy_from_slice(&rng.next_u64().to_le_bytes());
     }
-    data
+    Ok(data)
 }
 
 pub fn construct_allocation_from_distribution<D, T>(total_size: usize, ram_size: usize, block_size: u64, distribution: D) -> Vec<Allocation>
@@ -146,6 +442,19 @@ where D:Distribution<T>, T: rand_distr::num_traits::NumCast  {
     return allocations;
 }
 
+/**
+ * Allocations for n blocks drawn from a zipf distribution over the range of n blocks at start_lba
+ */
+pub fn construct_zipf_allocations(n: u64, s: f64, start_lba: u64, ram_size: usize, block_size: u64) -> Result<Vec<Allocation>, BenchmarkError> {
+    let distr = Zipf::new(n as f64, s).map_err(|e| BenchmarkError::InvalidParameter(format!("zipf distribution with n = {}, s = {}: {}", n, s, e)))?;
+    let mut allocations = construct_allocation_from_distribution::<_, f64>(n as usize * block_size as usize, ram_size, block_size, distr);
+    // the samples are in 1..=n
+    for allocation in allocations.iter_mut() {
+        allocation.lba = start_lba + allocation.lba - 1;
+    }
+    Ok(allocations)
+}
+
 pub fn construct_random_allocations(size: usize, max_block_amount: u64, block_size: u64, random_from: bool, random_to: bool) -> Vec<Allocation> {    
     let mut size = size;
     let mut num_blocks = size as u64 / block_size;
@@ -184,15 +493,57 @@ pub fn get_random_safe_start(op_size: u64, max_blocks: u64, block_size: u64) ->
         return None;
     }
 
-    return Some(rand::rng().next_u64() % (max_blocks - op_size / block_size));
+    return Some(rand::rng().next_u64() % (max_blocks - op_size / block_size + 1));
 }
 
-pub fn print_2x2(results: &[u128]) {
-    println!("\n--- Results (MiB/s)---");
-    println!("{}| {} | {}", "              ", "randto=false", "randto=true ");
-    println!("--------------------------");
-    println!("{}| {:<12} | {:<12}", "randfrom=false", results[0], results[1]);
-    println!("{}| {:<12} | {:<12}", "randfrom=true ",  results[2], results[3]);
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::NvmeBackend;
+    use crate::sim::{SimConfig, SimDevice};
+
+    #[test]
+    fn zipf_allocations_stay_in_window() {
+        let (first, blocks, block_size) = (100, 64, 512);
+        for n in [1, 16, 64] {
+            for s in [0.0, 1.0, 2.0] {
+                for _ in 0..100 {
+                    let start_lba = first + get_random_safe_start(n * block_size, blocks, block_size).unwrap();
+                    let allocations = construct_zipf_allocations(n, s, start_lba, 4096, block_size).unwrap();
+                    assert_eq!(allocations.len(), n as usize);
+                    assert!(allocations.iter().all(|a| a.lba >= start_lba && a.lba < start_lba + n));
+                    assert!(allocations.iter().all(|a| a.lba >= first && a.lba < first + blocks));
+                }
+            }
+        }
+    }
 
-const QUEUE_LENGTH: usize = 1024;
\ No newline at end of file
+    #[test]
+    fn safe_start_fits_the_operation() {
+        assert_eq!(get_random_safe_start(64 * 512, 64, 512), Some(0));
+        assert_eq!(get_random_safe_start(65 * 512, 64, 512), None);
+        for _ in 0..100 {
+            assert!(get_random_safe_start(16 * 512, 64, 512).unwrap() <= 48);
+        }
+    }
+
+    #[test]
+    fn failed_batch_writes_are_recorded_as_writes() {
+        let mut nvme = SimDevice::new(SimConfig::parse("sim:capacity=64M,errors=1.0").unwrap());
+        let ns = nvme.namespaces()[0];
+        let mut queue_pair = nvme.create_io_queue_pair(128).unwrap();
+        let data = queue_pair.allocate_buffer(4096).unwrap();
+        let allocations = vec![Allocation { lba: 8, start: 0, stop: 4096 }];
+        let mut timer = IoTimer::new();
+
+        queue_pair = threadsafe_io_batch_complete_64(queue_pair, ns.id, ns.block_size, (&data, &allocations), true, &mut timer).unwrap();
+        let writes = timer.take_completed(true);
+        let rows = writes.errors.rows();
+
+        assert_eq!((writes.failed, writes.bytes), (1, 4096));
+        assert_eq!(timer.take_completed(false).commands, 0);
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].op, rows[0].lba, rows[0].count), ("write", Some(8), 1));
+        nvme.delete_io_queue_pair(queue_pair).unwrap();
+    }
+}
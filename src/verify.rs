@@ -0,0 +1,318 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::device::{IoBuffer, IoQueue};
+
+/*
+ * Every written block starts with a header, the rest is payload derived from (seed, lba, generation):
+ *
+ *   0  magic "NVMBENCH"    8  lba    16  generation    24  seed    32  timestamp [ns]    40  checksum
+ *
+ * The checksum (FNV-1a) covers the header up to the checksum and the payload, so a block read back
+ * tells which write it came from without any state besides the expected generation per LBA.
+ */
+const MAGIC: u64 = u64::from_le_bytes(*b"NVMBENCH");
+const HEADER_SIZE: usize = 48;
+const CHECKSUM_OFFSET: usize = 40;
+/// Smallest unit a device writes atomically, a block torn inside is only partially written
+const SECTOR_SIZE: usize = 512;
+/// Errors listed by LBA per report, the counters include all of them
+const MAX_ERRORS: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// reads of the workload check the blocks the thread wrote before
+    Inline,
+    /// every written block is read back and checked after the run
+    After,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockErrorKind {
+    /// an intact block written for another LBA
+    Misdirected,
+    /// an intact block of an older write or an earlier run
+    Stale,
+    /// partially written, some sectors or blocks of the write are missing
+    Torn,
+    /// no intact block at all
+    Corrupted,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BlockError {
+    pub lba: u64,
+    pub kind: BlockErrorKind,
+    pub expected_generation: u64,
+    pub found_generation: Option<u64>,
+    pub found_lba: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub blocks_written: u64,
+    pub blocks_checked: u64,
+    pub misdirected: u64,
+    pub stale: u64,
+    pub torn: u64,
+    pub corrupted: u64,
+    /// blocks the read-back could not read, they are not checked
+    pub unreadable: u64,
+    /// the first MAX_ERRORS errors
+    pub errors: Vec<BlockError>,
+}
+
+impl VerifyReport {
+    pub fn merge(&mut self, other: VerifyReport) {
+        self.blocks_written += other.blocks_written;
+        self.blocks_checked += other.blocks_checked;
+        self.misdirected += other.misdirected;
+        self.stale += other.stale;
+        self.torn += other.torn;
+        self.corrupted += other.corrupted;
+        self.unreadable += other.unreadable;
+        let room = MAX_ERRORS.saturating_sub(self.errors.len());
+        self.errors.extend(other.errors.into_iter().take(room));
+    }
+
+    pub fn failed_blocks(&self) -> u64 {
+        self.misdirected + self.stale + self.torn + self.corrupted
+    }
+
+    fn push(&mut self, error: BlockError) {
+        match error.kind {
+            BlockErrorKind::Misdirected => self.misdirected += 1,
+            BlockErrorKind::Stale => self.stale += 1,
+            BlockErrorKind::Torn => self.torn += 1,
+            BlockErrorKind::Corrupted => self.corrupted += 1,
+        }
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error);
+        }
+    }
+}
+
+struct Header {
+    lba: u64,
+    generation: u64,
+    seed: u64,
+}
+
+fn field(block: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum(block: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in block[..CHECKSUM_OFFSET].iter().chain(&block[HEADER_SIZE..]) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn fill_payload(payload: &mut [u8], seed: u64, lba: u64, generation: u64) {
+    let mut rng = SmallRng::seed_from_u64(seed ^ lba.rotate_left(24) ^ generation.rotate_left(48));
+    rng.fill_bytes(payload);
+}
+
+/**
+ * Generates the blocks of the writes of one thread and checks the blocks it reads back. Requests in
+ * flight each own a slice of the buffer and never overlap, so the queue has to be drained whenever
+ * must_drain says so, after which drained checks the reads of the batch.
+ */
+pub struct Verifier {
+    pub mode: VerifyMode,
+    seed: u64,
+    block_size: usize,
+    io_size: usize,
+    depth: usize,
+    /// generation of the last write per LBA
+    generations: HashMap<u64, u64>,
+    /// start LBAs of all written requests
+    written: BTreeSet<u64>,
+    /// requests submitted since the last drain: (write, lba)
+    batch: Vec<(bool, u64)>,
+    report: VerifyReport,
+}
+
+impl Verifier {
+    pub fn new(mode: VerifyMode, seed: u64, block_size: u64, io_size: u64, depth: usize) -> Verifier {
+        Verifier {
+            mode,
+            seed,
+            block_size: block_size as usize,
+            io_size: io_size as usize,
+            depth: depth.max(1),
+            generations: HashMap::new(),
+            written: BTreeSet::new(),
+            batch: Vec::new(),
+            report: VerifyReport::default(),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.io_size * self.depth
+    }
+
+    pub fn must_drain(&self, lba: u64) -> bool {
+        self.batch.len() >= self.depth || self.batch.iter().any(|&(_, other)| other == lba)
+    }
+
+    /**
+     * Registers a request, writes get their blocks generated into the buffer
+     * @returns the range of the buffer the request transfers
+     */
+    pub fn submit(&mut self, buffer: &mut [u8], lba: u64, write: bool) -> Range<usize> {
+        let range = self.batch.len() * self.io_size..(self.batch.len() + 1) * self.io_size;
+        if write {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+            for (i, block) in buffer[range.clone()].chunks_mut(self.block_size).enumerate() {
+                let block_lba = lba + i as u64;
+                let generation = self.generations.entry(block_lba).or_insert(0);
+                *generation += 1;
+                let generation = *generation;
+                self.fill_block(block, block_lba, generation, timestamp);
+            }
+            self.written.insert(lba);
+            self.report.blocks_written += (self.io_size / self.block_size) as u64;
+        }
+        self.batch.push((write, lba));
+        range
+    }
+
+    /// Call once every request of the batch completed
+    pub fn drained(&mut self, buffer: &[u8]) {
+        let batch = std::mem::take(&mut self.batch);
+        if self.mode == VerifyMode::Inline {
+            for (i, (_, lba)) in batch.into_iter().enumerate().filter(|(_, (write, _))| !write) {
+                self.check(&buffer[i * self.io_size..(i + 1) * self.io_size], lba);
+            }
+        }
+    }
+
+    /**
+     * Reads back every request this thread wrote, depth requests at a time. Requests that were not
+     * queued or completed among failed commands count as unreadable instead of being checked.
+     */
+    pub fn read_back<Q: IoQueue>(&mut self, queue_pair: &mut Q, buffer: &Q::Buffer, ns_id: u32) {
+        let written: Vec<u64> = self.written.iter().copied().collect();
+        for chunk in written.chunks(self.depth) {
+            let mut commands = 0;
+            let mut pending = Vec::new();
+            let mut readable = Vec::new();
+            for (i, &lba) in chunk.iter().enumerate() {
+                let range = i * self.io_size..(i + 1) * self.io_size;
+                let mut res = queue_pair.submit_io(ns_id, self.block_size as u64, buffer, range.clone(), lba, false);
+                if res == 0 {
+                    // queue full, large requests are split into many commands
+                    self.complete_reads(queue_pair, commands, &mut pending, &mut readable);
+                    commands = 0;
+                    res = queue_pair.submit_io(ns_id, self.block_size as u64, buffer, range, lba, false);
+                }
+                if res == 0 {
+                    self.report.unreadable += (self.io_size / self.block_size) as u64;
+                    continue;
+                }
+                commands += res;
+                pending.push((i, lba));
+            }
+            self.complete_reads(queue_pair, commands, &mut pending, &mut readable);
+            for (i, lba) in readable {
+                self.check(&buffer.as_slice()[i * self.io_size..(i + 1) * self.io_size], lba);
+            }
+        }
+    }
+
+    /// complete_io only counts the failed commands, so one of them makes every pending request unreadable
+    fn complete_reads<Q: IoQueue>(&mut self, queue_pair: &mut Q, commands: usize, pending: &mut Vec<(usize, u64)>, readable: &mut Vec<(usize, u64)>) {
+        if queue_pair.complete_io(commands) == 0 {
+            readable.append(pending);
+        } else {
+            self.report.unreadable += (pending.len() * self.io_size / self.block_size) as u64;
+            pending.clear();
+        }
+    }
+
+    pub fn finish(self) -> VerifyReport {
+        self.report
+    }
+
+    fn fill_block(&self, block: &mut [u8], lba: u64, generation: u64, timestamp: u64) {
+        block[0..8].copy_from_slice(&MAGIC.to_le_bytes());
+        block[8..16].copy_from_slice(&lba.to_le_bytes());
+        block[16..24].copy_from_slice(&generation.to_le_bytes());
+        block[24..32].copy_from_slice(&self.seed.to_le_bytes());
+        block[32..40].copy_from_slice(&timestamp.to_le_bytes());
+        fill_payload(&mut block[HEADER_SIZE..], self.seed, lba, generation);
+        let sum = checksum(block);
+        block[CHECKSUM_OFFSET..HEADER_SIZE].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Checks the blocks of one request that were written before, a stale block next to intact
+    /// blocks of the current write means the write was torn
+    fn check(&mut self, data: &[u8], lba: u64) {
+        let mut errors = Vec::new();
+        let mut current = 0;
+        for (i, block) in data.chunks(self.block_size).enumerate() {
+            let block_lba = lba + i as u64;
+            let Some(&expected) = self.generations.get(&block_lba) else {
+                continue;
+            };
+            self.report.blocks_checked += 1;
+            match self.classify(block, block_lba, expected) {
+                None => current += 1,
+                Some(error) => errors.push(error),
+            }
+        }
+        for mut error in errors {
+            if current > 0 && error.kind == BlockErrorKind::Stale {
+                error.kind = BlockErrorKind::Torn;
+            }
+            self.report.push(error);
+        }
+    }
+
+    fn classify(&self, block: &[u8], lba: u64, expected: u64) -> Option<BlockError> {
+        let error = |kind, header: Option<&Header>| Some(BlockError {
+            lba,
+            kind,
+            expected_generation: expected,
+            found_generation: header.map(|h| h.generation),
+            found_lba: header.map(|h| h.lba),
+        });
+
+        if block.iter().all(|&byte| byte == 0) {
+            // never written, e.g. a lost write to a deallocated block
+            return error(BlockErrorKind::Stale, None);
+        }
+        if block.len() < HEADER_SIZE || field(block, 0) != MAGIC {
+            return error(BlockErrorKind::Corrupted, None);
+        }
+        let header = Header { lba: field(block, 8), generation: field(block, 16), seed: field(block, 24) };
+        if field(block, CHECKSUM_OFFSET) != checksum(block) {
+            // intact up to a sector boundary: the tail of the block was never written
+            let mut payload = vec![0; block.len() - HEADER_SIZE];
+            fill_payload(&mut payload, header.seed, header.lba, header.generation);
+            let intact = block[HEADER_SIZE..].iter().zip(&payload).take_while(|(a, b)| a == b).count() + HEADER_SIZE;
+            let kind = if intact % SECTOR_SIZE == 0 && intact < block.len() { BlockErrorKind::Torn } else { BlockErrorKind::Corrupted };
+            return error(kind, Some(&header));
+        }
+
+        if header.lba != lba {
+            error(BlockErrorKind::Misdirected, Some(&header))
+        } else if header.seed != self.seed || header.generation < expected {
+            error(BlockErrorKind::Stale, Some(&header))
+        } else if header.generation > expected {
+            error(BlockErrorKind::Corrupted, Some(&header))
+        } else {
+            None
+        }
+    }
+}